@@ -0,0 +1,563 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::image::{invalid_data, Image, Segment};
+use crate::symbols::SymbolTable;
+
+// Two pass LC-3 assembler accepting the lc3as dialect. Besides the image it
+// keeps the labels and the source line of every word it emitted, which is
+// what source level debugging needs
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Assembly {
+    pub image: Image,
+    pub symbols: SymbolTable,
+    // 1 based source line of every address that holds an assembled word
+    pub lines: BTreeMap<u16, usize>,
+    // addresses of instructions, as opposed to words from data directives
+    pub code: BTreeSet<u16>,
+}
+
+impl Assembly {
+    pub fn line_at(&self, addr: u16) -> Option<usize> {
+        self.lines.get(&addr).copied()
+    }
+
+    // first address assembled from a line, a line without code (a comment
+    // or a lone label) maps to the next line that has some
+    pub fn addr_at_line(&self, line: usize) -> Option<(u16, usize)> {
+        self.lines
+            .iter()
+            .filter(|(_, l)| **l >= line)
+            .min_by_key(|(addr, l)| (**l, **addr))
+            .map(|(addr, l)| (*addr, *l))
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        assemble(&fs::read_to_string(path)?).map_err(invalid_data)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Str(String),
+}
+
+// a source line split into its label, operation and operands
+struct Line {
+    number: usize,
+    label: Option<String>,
+    op: Option<String>,
+    operands: Vec<Token>,
+}
+
+pub fn assemble(source: &str) -> Result<Assembly, String> {
+    let mut lines = Vec::new();
+    for (n, text) in source.lines().enumerate() {
+        let line = parse_line(n + 1, text).map_err(|e| format!("line {}: {}", n + 1, e))?;
+        lines.push(line);
+    }
+
+    // first pass: addresses of labels
+    let mut symbols = SymbolTable::new();
+    // kept wider than an address so running off the end of memory shows
+    let mut pc: Option<u32> = None;
+    for line in &lines {
+        let at = |e: String| format!("line {}: {}", line.number, e);
+        let op = line.op.as_deref().map(str::to_ascii_uppercase);
+        if op.as_deref() == Some(".ORIG") {
+            pc = Some(parse_word(&line.operands, 0).map_err(at)? as u32);
+            continue;
+        }
+        if let Some(label) = &line.label {
+            let addr = pc.ok_or_else(|| at("label outside of .ORIG/.END".to_string()))?;
+            if addr > 0xFFFF {
+                return Err(at(format!("label {} is past xFFFF", label)));
+            }
+            if symbols.lookup(label).is_some() {
+                return Err(at(format!("duplicate label {}", label)));
+            }
+            symbols.insert(label, addr as u16);
+        }
+        match op.as_deref() {
+            Some(".END") => pc = None,
+            Some(op) => {
+                let addr = pc.ok_or_else(|| at(format!("{} outside of .ORIG/.END", op)))?;
+                let end = addr + size_of(op, &line.operands).map_err(at)?;
+                if end > 0x10000 {
+                    return Err(at("segment runs past xFFFF".to_string()));
+                }
+                pc = Some(end);
+            }
+            None => (),
+        }
+    }
+
+    // second pass: encode
+    let mut assembly = Assembly {
+        symbols,
+        ..Assembly::default()
+    };
+    let mut segment: Option<Segment> = None;
+    for line in &lines {
+        let at = |e: String| format!("line {}: {}", line.number, e);
+        let Some(op) = line.op.as_deref().map(str::to_ascii_uppercase) else {
+            continue;
+        };
+        match op.as_str() {
+            ".ORIG" => {
+                let origin = parse_word(&line.operands, 0).map_err(at)?;
+                assembly.image.segments.extend(segment.take());
+                segment = Some(Segment {
+                    origin,
+                    words: Vec::new(),
+                });
+            }
+            ".END" => assembly.image.segments.extend(segment.take()),
+            _ => {
+                let segment = segment.as_mut().unwrap();
+                let pc = segment.origin.wrapping_add(segment.words.len() as u16);
+                let words = encode(&op, &line.operands, pc, &assembly.symbols).map_err(at)?;
+                if !op.starts_with('.') {
+                    assembly.code.insert(pc);
+                }
+                for (i, word) in words.into_iter().enumerate() {
+                    assembly
+                        .lines
+                        .insert(pc.wrapping_add(i as u16), line.number);
+                    segment.words.push(word);
+                }
+            }
+        }
+    }
+    assembly.image.segments.extend(segment);
+
+    if assembly.image.segments.is_empty() {
+        return Err("no .ORIG in source".to_string());
+    }
+    Ok(assembly)
+}
+
+const TRAPS: [(&str, u16); 6] = [
+    ("GETC", 0x20),
+    ("OUT", 0x21),
+    ("PUTS", 0x22),
+    ("IN", 0x23),
+    ("PUTSP", 0x24),
+    ("HALT", 0x25),
+];
+
+fn is_operation(word: &str) -> bool {
+    let word = word.to_ascii_uppercase();
+    matches!(
+        word.as_str(),
+        "ADD"
+            | "AND"
+            | "NOT"
+            | "JMP"
+            | "RET"
+            | "JSR"
+            | "JSRR"
+            | "LD"
+            | "LDI"
+            | "LDR"
+            | "LEA"
+            | "ST"
+            | "STI"
+            | "STR"
+            | "TRAP"
+            | "RTI"
+            | ".ORIG"
+            | ".END"
+            | ".FILL"
+            | ".BLKW"
+            | ".STRINGZ"
+    ) || branch_flags(&word).is_some()
+        || TRAPS.iter().any(|(name, _)| *name == word)
+}
+
+// BR, BRn, BRzp, ... as the nzp bits, BR alone branches always
+fn branch_flags(op: &str) -> Option<u16> {
+    let flags = op.strip_prefix("BR")?;
+    if flags.is_empty() {
+        return Some(0x7);
+    }
+    let mut nzp = 0;
+    let mut rest = flags;
+    for (c, bit) in [('N', 0x4), ('Z', 0x2), ('P', 0x1)] {
+        if let Some(r) = rest.strip_prefix(c) {
+            nzp |= bit;
+            rest = r;
+        }
+    }
+    rest.is_empty().then_some(nzp)
+}
+
+// splits on whitespace and commas, ; starts a comment outside of strings
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            ';' => break,
+            ',' => {
+                chars.next();
+            }
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '"' => {
+                chars.next();
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        None => return Err("unterminated string".to_string()),
+                        Some('"') => break,
+                        Some('\\') => s.push(match chars.next() {
+                            Some('n') => '\n',
+                            Some('t') => '\t',
+                            Some('r') => '\r',
+                            Some('0') => '\0',
+                            Some('e') => '\x1b',
+                            Some(c) => c,
+                            None => return Err("unterminated string".to_string()),
+                        }),
+                        Some(c) => s.push(c),
+                    }
+                }
+                tokens.push(Token::Str(s));
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || c == ',' || c == ';' || c == '"' {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Word(word));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_line(number: usize, text: &str) -> Result<Line, String> {
+    let mut tokens = tokenize(text)?.into_iter();
+    let mut line = Line {
+        number,
+        label: None,
+        op: None,
+        operands: Vec::new(),
+    };
+
+    let mut first = tokens.next();
+    if let Some(Token::Word(word)) = &first {
+        if !is_operation(word) {
+            let label = word.strip_suffix(':').unwrap_or(word);
+            if !label.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+                return Err(format!("bad label {:?}", word));
+            }
+            line.label = Some(label.to_string());
+            first = tokens.next();
+        }
+    }
+    match first {
+        Some(Token::Word(op)) if is_operation(&op) => line.op = Some(op),
+        Some(Token::Word(word)) => return Err(format!("unknown operation {:?}", word)),
+        Some(Token::Str(_)) => return Err("unexpected string".to_string()),
+        None => (),
+    }
+    line.operands = tokens.collect();
+    Ok(line)
+}
+
+// words a line occupies, needed before labels are known
+fn size_of(op: &str, operands: &[Token]) -> Result<u32, String> {
+    match op {
+        ".BLKW" => Ok(parse_word(operands, 0)? as u32),
+        ".STRINGZ" => match operands {
+            [Token::Str(s)] => Ok(s.chars().count() as u32 + 1),
+            _ => Err(".STRINGZ needs a string".to_string()),
+        },
+        _ => Ok(1),
+    }
+}
+
+// #10, #-3, 10, x3000, 0x3000, b0101
+fn parse_number(text: &str) -> Option<i32> {
+    if let Some(dec) = text.strip_prefix('#') {
+        return dec.parse().ok();
+    }
+    let (negative, text) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = text
+        .strip_prefix("0x")
+        .or_else(|| text.strip_prefix("0X"))
+        .or_else(|| text.strip_prefix(['x', 'X']))
+    {
+        i32::from_str_radix(hex, 16).ok()?
+    } else if let Some(bin) = text.strip_prefix(['b', 'B']) {
+        i32::from_str_radix(bin, 2).ok()?
+    } else {
+        text.parse().ok()?
+    };
+    Some(if negative { -value } else { value })
+}
+
+fn parse_operand_number(operands: &[Token], i: usize) -> Result<i32, String> {
+    match operands.get(i) {
+        Some(Token::Word(w)) => parse_number(w).ok_or_else(|| format!("bad number {:?}", w)),
+        _ => Err("missing number".to_string()),
+    }
+}
+
+// an address or count, which has to fit in 16 bits unsigned
+fn parse_word(operands: &[Token], i: usize) -> Result<u16, String> {
+    let value = parse_operand_number(operands, i)?;
+    u16::try_from(value).map_err(|_| format!("{} is outside x0000 to xFFFF", value))
+}
+
+fn parse_register(operands: &[Token], i: usize) -> Result<u16, String> {
+    match operands.get(i) {
+        Some(Token::Word(w)) => match w.to_ascii_uppercase().as_bytes() {
+            [b'R', r @ b'0'..=b'7'] => Ok((r - b'0') as u16),
+            _ => Err(format!("bad register {:?}", w)),
+        },
+        _ => Err("missing register".to_string()),
+    }
+}
+
+// a number, or a label as an address
+fn parse_value(operands: &[Token], i: usize, symbols: &SymbolTable) -> Result<i32, String> {
+    match operands.get(i) {
+        Some(Token::Word(w)) => parse_number(w)
+            .or_else(|| symbols.lookup(w).map(i32::from))
+            .ok_or_else(|| format!("undefined label {:?}", w)),
+        _ => Err("missing operand".to_string()),
+    }
+}
+
+// a label becomes an offset from the incremented pc, a number is taken as
+// the offset itself
+fn pc_offset(
+    operands: &[Token],
+    i: usize,
+    pc: u16,
+    bits: u32,
+    symbols: &SymbolTable,
+) -> Result<u16, String> {
+    let offset = match operands.get(i) {
+        Some(Token::Word(w)) => match parse_number(w) {
+            Some(offset) => offset,
+            None => {
+                let addr = symbols
+                    .lookup(w)
+                    .ok_or_else(|| format!("undefined label {:?}", w))?;
+                addr.wrapping_sub(pc.wrapping_add(1)) as i16 as i32
+            }
+        },
+        _ => return Err("missing label".to_string()),
+    };
+    fit_signed(offset, bits)
+}
+
+fn fit_signed(value: i32, bits: u32) -> Result<u16, String> {
+    let limit = 1 << (bits - 1);
+    if value < -limit || value >= limit {
+        return Err(format!("{} does not fit in {} bits", value, bits));
+    }
+    Ok((value as u16) & ((1 << bits) - 1))
+}
+
+fn expect_operands(operands: &[Token], count: usize) -> Result<(), String> {
+    if operands.len() != count {
+        return Err(format!(
+            "expected {} operands, found {}",
+            count,
+            operands.len()
+        ));
+    }
+    Ok(())
+}
+
+fn encode(
+    op: &str,
+    operands: &[Token],
+    pc: u16,
+    symbols: &SymbolTable,
+) -> Result<Vec<u16>, String> {
+    let reg = |i| parse_register(operands, i);
+    let word = match op {
+        "ADD" | "AND" => {
+            expect_operands(operands, 3)?;
+            let opcode = if op == "ADD" { 0x1000 } else { 0x5000 };
+            let last = match reg(2) {
+                Ok(sr2) => sr2,
+                Err(_) => 0x20 | fit_signed(parse_operand_number(operands, 2)?, 5)?,
+            };
+            opcode | reg(0)? << 9 | reg(1)? << 6 | last
+        }
+        "NOT" => {
+            expect_operands(operands, 2)?;
+            0x903F | reg(0)? << 9 | reg(1)? << 6
+        }
+        "JMP" => {
+            expect_operands(operands, 1)?;
+            0xC000 | reg(0)? << 6
+        }
+        "RET" => {
+            expect_operands(operands, 0)?;
+            0xC1C0
+        }
+        "JSR" => {
+            expect_operands(operands, 1)?;
+            0x4800 | pc_offset(operands, 0, pc, 11, symbols)?
+        }
+        "JSRR" => {
+            expect_operands(operands, 1)?;
+            0x4000 | reg(0)? << 6
+        }
+        "LD" | "LDI" | "LEA" | "ST" | "STI" => {
+            expect_operands(operands, 2)?;
+            let opcode = match op {
+                "LD" => 0x2000,
+                "LDI" => 0xA000,
+                "LEA" => 0xE000,
+                "ST" => 0x3000,
+                _ => 0xB000,
+            };
+            opcode | reg(0)? << 9 | pc_offset(operands, 1, pc, 9, symbols)?
+        }
+        "LDR" | "STR" => {
+            expect_operands(operands, 3)?;
+            let opcode = if op == "LDR" { 0x6000 } else { 0x7000 };
+            let offset = fit_signed(parse_operand_number(operands, 2)?, 6)?;
+            opcode | reg(0)? << 9 | reg(1)? << 6 | offset
+        }
+        "TRAP" => {
+            expect_operands(operands, 1)?;
+            let vector = parse_operand_number(operands, 0)?;
+            if !(0..=0xFF).contains(&vector) {
+                return Err(format!("bad trap vector {}", vector));
+            }
+            0xF000 | vector as u16
+        }
+        "RTI" => {
+            expect_operands(operands, 0)?;
+            0x8000
+        }
+        ".FILL" => {
+            expect_operands(operands, 1)?;
+            parse_value(operands, 0, symbols)? as u16
+        }
+        ".BLKW" => {
+            let count = parse_word(operands, 0)? as usize;
+            let fill = match operands.len() {
+                1 => 0,
+                2 => parse_value(operands, 1, symbols)? as u16,
+                _ => return Err("expected a count and an optional value".to_string()),
+            };
+            return Ok(vec![fill; count]);
+        }
+        ".STRINGZ" => {
+            let [Token::Str(s)] = operands else {
+                return Err(".STRINGZ needs a string".to_string());
+            };
+            return Ok(s.chars().map(|c| c as u16).chain([0]).collect());
+        }
+        _ => {
+            if let Some((_, vector)) = TRAPS.iter().find(|(name, _)| *name == op) {
+                expect_operands(operands, 0)?;
+                0xF000 | vector
+            } else if let Some(nzp) = branch_flags(op) {
+                expect_operands(operands, 1)?;
+                nzp << 9 | pc_offset(operands, 0, pc, 9, symbols)?
+            } else {
+                return Err(format!("unknown operation {:?}", op));
+            }
+        }
+    };
+    Ok(vec![word])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "; count down from 3
+        .ORIG x3000
+        LD R0, COUNT     ; load the counter
+LOOP    ADD R0, R0, #-1
+        BRp LOOP
+        LEA R0, MSG
+        PUTS
+        HALT
+COUNT   .FILL #3
+MSG     .STRINGZ \"ok\\n\"
+        .END
+";
+
+    #[test]
+    fn test_assemble() {
+        let assembly = assemble(SOURCE).unwrap();
+        assert_eq!(
+            assembly.image,
+            Image::new(
+                0x3000,
+                vec![0x2005, 0x103F, 0x03FE, 0xE003, 0xF022, 0xF025, 3, 0x6F, 0x6B, 0x0A, 0]
+            )
+        );
+        assert_eq!(assembly.symbols.lookup("LOOP"), Some(0x3001));
+        assert_eq!(assembly.symbols.lookup("MSG"), Some(0x3007));
+    }
+
+    #[test]
+    fn test_line_map() {
+        let assembly = assemble(SOURCE).unwrap();
+        assert_eq!(assembly.line_at(0x3001), Some(4));
+        assert_eq!(assembly.line_at(0x3009), Some(10));
+        // the comment and .ORIG lines map to the first instruction
+        assert_eq!(assembly.addr_at_line(1), Some((0x3000, 3)));
+        assert_eq!(assembly.addr_at_line(11), None);
+        assert!(assembly.code.contains(&0x3005) && !assembly.code.contains(&0x3006));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            assemble(".ORIG x3000\nADD R0, R0, #16\n.END").unwrap_err(),
+            "line 2: 16 does not fit in 5 bits"
+        );
+        assert_eq!(
+            assemble(".ORIG x3000\nBR NOWHERE\n.END").unwrap_err(),
+            "line 2: undefined label \"NOWHERE\""
+        );
+        assert!(assemble("ADD R0, R0, R0").is_err());
+        assert_eq!(
+            assemble(".ORIG x3000\n.BLKW #-1\n.END").unwrap_err(),
+            "line 2: -1 is outside x0000 to xFFFF"
+        );
+        assert_eq!(
+            assemble(".ORIG x3000\n.BLKW #65536\n.END").unwrap_err(),
+            "line 2: 65536 is outside x0000 to xFFFF"
+        );
+        assert_eq!(
+            assemble(".ORIG xFFF0\n.BLKW #16\nDONE HALT\n.END").unwrap_err(),
+            "line 3: label DONE is past xFFFF"
+        );
+        assert_eq!(
+            assemble(".ORIG xFFFF\n.STRINGZ \"hi\"\n.END").unwrap_err(),
+            "line 2: segment runs past xFFFF"
+        );
+        // filling memory up to the last word is fine
+        let assembly = assemble(".ORIG xFFF0\n.BLKW #15\nLAST .FILL #7\n.END").unwrap();
+        assert_eq!(assembly.symbols.lookup("LAST"), Some(0xFFFF));
+        assert_eq!(assembly.image.segments[0].words.len(), 16);
+    }
+}
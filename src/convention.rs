@@ -0,0 +1,309 @@
+use std::collections::HashSet;
+use std::mem::{self, Discriminant};
+use std::ops::RangeInclusive;
+
+use crate::hw::instruction::{OpCode, CALLEE_SAVED, RET, STACK_POINTER};
+use crate::hw::observer::Observer;
+use crate::hw::register::PC_REG;
+use crate::hw::vm::VM;
+use crate::image::Image;
+use crate::symbols::SymbolTable;
+
+// Checks subroutines against the LC-3 calling convention while a program
+// runs: every RET should come back to the JSR that made the call with R6
+// where it was and the callee-saved registers unchanged, and the stack must
+// not grow down into the program's own code or data
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ViolationKind {
+    // R6 on return differs from R6 on entry
+    StackImbalance { entry: u16, exit: u16 },
+    // R7 no longer holds the address after the JSR
+    ReturnAddressOverwritten { expected: u16, actual: u16 },
+    // R6 moved down into a segment of the loaded program
+    StackOverflow { sp: u16 },
+    Clobbered { register: u8, entry: u16, exit: u16 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Violation {
+    // the RET, or the instruction that moved R6
+    pub pc: u16,
+    // entry point of the subroutine it happened in, None outside any call
+    pub subroutine: Option<u16>,
+    pub kind: ViolationKind,
+}
+
+impl Violation {
+    pub fn describe(&self, symbols: &SymbolTable) -> String {
+        let what = match self.kind {
+            ViolationKind::StackImbalance { entry, exit } => {
+                format!("R6 is x{:04X} on return, x{:04X} on entry", exit, entry)
+            }
+            ViolationKind::ReturnAddressOverwritten { expected, actual } => format!(
+                "return address overwritten: R7 is {}, the caller is at {}",
+                symbols.format_addr(actual),
+                symbols.format_addr(expected)
+            ),
+            ViolationKind::StackOverflow { sp } => {
+                format!("stack grew into the program at {}", symbols.format_addr(sp))
+            }
+            ViolationKind::Clobbered {
+                register,
+                entry,
+                exit,
+            } => format!(
+                "R{} not preserved: x{:04X} on entry, x{:04X} on return",
+                register, entry, exit
+            ),
+        };
+        let within = match self.subroutine {
+            Some(addr) => symbols.format_addr(addr),
+            None => "the main program".to_string(),
+        };
+        format!("{} in {}: {}", symbols.format_addr(self.pc), within, what)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Call {
+    target: u16,
+    ret: u16,
+    // registers right after the JSR
+    registers: [u16; 8],
+}
+
+// An observer keeping a shadow copy of R0-R7 and the stack of calls in
+// progress. A violation is reported once per instruction, kind and register
+// so loops do not repeat it
+#[derive(Debug, Clone)]
+pub struct ConventionChecker {
+    pub preserved: Vec<u8>,
+    // code and data of the program, the stack must stay out of them
+    pub protected: Vec<RangeInclusive<u16>>,
+    pub violations: Vec<Violation>,
+    registers: [u16; 8],
+    calls: Vec<Call>,
+    // the instruction executing and how many times it has written PC, the
+    // first write only moves past it
+    current: (u16, u16),
+    pc_writes: u8,
+    seen: HashSet<(u16, Discriminant<ViolationKind>, u8)>,
+}
+
+impl ConventionChecker {
+    // starts from the registers vm has now, the segments of image are
+    // protected from the stack
+    pub fn new(vm: &VM, image: &Image) -> Self {
+        let mut registers = [0; 8];
+        for (r, value) in registers.iter_mut().enumerate() {
+            *value = vm.registers.get_val(r as u8);
+        }
+        ConventionChecker {
+            preserved: CALLEE_SAVED.to_vec(),
+            protected: image
+                .segments
+                .iter()
+                .filter(|s| !s.words.is_empty())
+                .map(|s| s.origin..=s.origin.wrapping_add(s.words.len() as u16 - 1))
+                .collect(),
+            violations: Vec::new(),
+            registers,
+            calls: Vec::new(),
+            current: (0, 0),
+            pc_writes: 0,
+            seen: HashSet::new(),
+        }
+    }
+
+    // subroutines entered and not yet returned from, outermost first
+    pub fn depth(&self) -> usize {
+        self.calls.len()
+    }
+
+    fn report(&mut self, pc: u16, kind: ViolationKind) {
+        let register = match kind {
+            ViolationKind::Clobbered { register, .. } => register,
+            _ => 0,
+        };
+        if self.seen.insert((pc, mem::discriminant(&kind), register)) {
+            self.violations.push(Violation {
+                pc,
+                subroutine: self.calls.last().map(|c| c.target),
+                kind,
+            });
+        }
+    }
+
+    fn check_return(&mut self, pc: u16) {
+        let Some(call) = self.calls.last().cloned() else {
+            return;
+        };
+        let regs = self.registers;
+        if regs[7] != call.ret {
+            let kind = ViolationKind::ReturnAddressOverwritten {
+                expected: call.ret.wrapping_sub(1),
+                actual: regs[7],
+            };
+            self.report(pc, kind);
+        }
+        let sp = STACK_POINTER as usize;
+        if regs[sp] != call.registers[sp] {
+            let kind = ViolationKind::StackImbalance {
+                entry: call.registers[sp],
+                exit: regs[sp],
+            };
+            self.report(pc, kind);
+        }
+        for r in self.preserved.clone() {
+            let (entry, exit) = (call.registers[r as usize], regs[r as usize]);
+            if entry != exit {
+                let kind = ViolationKind::Clobbered {
+                    register: r,
+                    entry,
+                    exit,
+                };
+                self.report(pc, kind);
+            }
+        }
+        self.calls.pop();
+    }
+}
+
+impl Observer for ConventionChecker {
+    fn on_fetch(&mut self, pc: u16, instruction: u16) {
+        self.current = (pc, instruction);
+        self.pc_writes = 0;
+        if instruction == RET {
+            self.check_return(pc);
+        }
+    }
+
+    fn on_register_write(&mut self, register: u8, old: u16, new: u16) {
+        let (pc, instruction) = self.current;
+        match register {
+            PC_REG => {
+                self.pc_writes += 1;
+                if self.pc_writes == 2 && instruction >> 12 == OpCode::OpJsr as u16 {
+                    self.calls.push(Call {
+                        target: new,
+                        ret: self.registers[7],
+                        registers: self.registers,
+                    });
+                }
+            }
+            STACK_POINTER => {
+                self.registers[STACK_POINTER as usize] = new;
+                if new < old && self.protected.iter().any(|r| r.contains(&new)) {
+                    self.report(pc, ViolationKind::StackOverflow { sp: new });
+                }
+            }
+            r if r < 8 => self.registers[r as usize] = new,
+            _ => (),
+        }
+    }
+}
+
+// a list of registers such as "R1,R2,R5"
+pub fn parse_registers(text: &str) -> Result<Vec<u8>, String> {
+    text.split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            name.strip_prefix(['R', 'r'])
+                .and_then(|n| n.parse().ok())
+                .filter(|n| *n < 8)
+                .ok_or_else(|| format!("unknown register {:?}", name))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use std::sync::{Arc, Mutex};
+
+    // GOOD pushes R7 and R1 and pops them again, BAD forgets to pop R1 and
+    // clobbers R2, LEAF returns past the HALT after its call
+    const SOURCE: &str = "        .ORIG x3000
+        LD R6, STACK
+        JSR GOOD
+        JSR BAD
+        JSR LEAF
+        HALT
+        HALT
+STACK   .FILL x4000
+GOOD    ADD R6, R6, #-1
+        STR R7, R6, #0
+        ADD R6, R6, #-1
+        STR R1, R6, #0
+        ADD R1, R1, #5
+        LDR R1, R6, #0
+        ADD R6, R6, #1
+        LDR R7, R6, #0
+        ADD R6, R6, #1
+        RET
+BAD     ADD R6, R6, #-1
+        STR R1, R6, #0
+        ADD R2, R2, #1
+        RET
+LEAF    ADD R7, R7, #1
+        RET
+        .END
+";
+
+    fn check(source: &str) -> (ConventionChecker, SymbolTable) {
+        let assembly = assemble(source).unwrap();
+        let mut vm = VM::new();
+        vm.load_image(&assembly.image);
+        vm.output = Some(Vec::new());
+        let checker = Arc::new(Mutex::new(ConventionChecker::new(&vm, &assembly.image)));
+        vm.add_observer(checker.clone());
+        vm.execute_program().unwrap();
+        let checker = checker.lock().unwrap().clone();
+        (checker, assembly.symbols)
+    }
+
+    #[test]
+    fn test_violations() {
+        let (checker, symbols) = check(SOURCE);
+        assert_eq!(checker.depth(), 0);
+        let report: Vec<String> = checker
+            .violations
+            .iter()
+            .map(|v| v.describe(&symbols))
+            .collect();
+        assert_eq!(
+            report,
+            vec![
+                "BAD+3 (x3014) in BAD (x3011): R6 is x3FFF on return, x4000 on entry",
+                "BAD+3 (x3014) in BAD (x3011): R2 not preserved: x0000 on entry, x0001 on return",
+                "LEAF+1 (x3016) in LEAF (x3015): return address overwritten: \
+                 R7 is x3005, the caller is at x3003",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        // the stack starts inside the program and is pushed onto the code
+        let source = "        .ORIG x3000
+        LEA R6, TOP
+        ADD R6, R6, #-1
+        HALT
+TOP     .FILL 0
+        .END";
+        let (checker, _) = check(source);
+        assert_eq!(
+            checker.violations,
+            vec![Violation {
+                pc: 0x3001,
+                subroutine: None,
+                kind: ViolationKind::StackOverflow { sp: 0x3002 },
+            }]
+        );
+        assert_eq!(parse_registers("R1, r5"), Ok(vec![1, 5]));
+        assert!(parse_registers("R8").is_err());
+    }
+}
@@ -0,0 +1,237 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::asm::Assembly;
+use crate::hw::branches::BranchCounter;
+use crate::hw::instruction::OpCode;
+use crate::hw::observer::Observer;
+
+// Which instructions ran and which way each branch went, gathered as an
+// observer of the VM and mapped back to source lines for lcov
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Coverage {
+    pub counts: BTreeMap<u16, u64>,
+    pub branches: BranchCounter,
+}
+
+impl Observer for Coverage {
+    fn on_fetch(&mut self, pc: u16, instruction: u16) {
+        *self.counts.entry(pc).or_default() += 1;
+        self.branches.on_fetch(pc, instruction);
+    }
+
+    fn on_register_write(&mut self, register: u8, old: u16, new: u16) {
+        self.branches.on_register_write(register, old, new);
+    }
+}
+
+// Coverage of one source file by line, what an lcov record holds
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LineCoverage {
+    pub source: String,
+    // execution count of every line holding an instruction
+    pub lines: BTreeMap<usize, u64>,
+    // (taken, not taken) of every conditional branch, None when the branch
+    // itself never ran
+    pub branches: BTreeMap<usize, Option<(u64, u64)>>,
+}
+
+impl LineCoverage {
+    // instructions that never ran count as 0, unconditional branches have
+    // no direction to cover
+    pub fn new(source: &str, assembly: &Assembly, coverage: &Coverage) -> Self {
+        let mut lines = LineCoverage {
+            source: source.to_string(),
+            ..LineCoverage::default()
+        };
+        let words: BTreeMap<u16, u16> = assembly
+            .image
+            .segments
+            .iter()
+            .flat_map(|s| {
+                let addrs = (0..).map(|i: u16| s.origin.wrapping_add(i));
+                addrs.zip(s.words.iter().copied())
+            })
+            .collect();
+        for &addr in &assembly.code {
+            let Some(line) = assembly.line_at(addr) else {
+                continue;
+            };
+            *lines.lines.entry(line).or_default() += coverage.counts.get(&addr).unwrap_or(&0);
+
+            let instruction = words[&addr];
+            let nzp = (instruction >> 9) & 0x7;
+            if instruction >> 12 == OpCode::OpBr as u16 && nzp != 0 && nzp != 0x7 {
+                lines
+                    .branches
+                    .insert(line, coverage.branches.counts.get(&addr).copied());
+            }
+        }
+        lines
+    }
+
+    // adds the counts of another run of the same source
+    pub fn merge(&mut self, other: &LineCoverage) {
+        for (line, count) in &other.lines {
+            *self.lines.entry(*line).or_default() += count;
+        }
+        for (line, branch) in &other.branches {
+            let merged = match (self.branches.get(line).copied().flatten(), branch) {
+                (Some(a), Some(b)) => Some((a.0 + b.0, a.1 + b.1)),
+                (a, b) => a.or(*b),
+            };
+            self.branches.insert(*line, merged);
+        }
+    }
+
+    pub fn to_lcov(&self) -> String {
+        let mut out = format!("TN:\nSF:{}\n", self.source);
+        let mut hit = 0;
+        for (line, branch) in &self.branches {
+            let (taken, not_taken) = match branch {
+                Some((taken, not_taken)) => (taken.to_string(), not_taken.to_string()),
+                None => ("-".to_string(), "-".to_string()),
+            };
+            let _ = writeln!(out, "BRDA:{},0,0,{}", line, taken);
+            let _ = writeln!(out, "BRDA:{},0,1,{}", line, not_taken);
+            hit += branch.map_or(0, |(t, n)| (t > 0) as usize + (n > 0) as usize);
+        }
+        let _ = write!(out, "BRF:{}\nBRH:{}\n", 2 * self.branches.len(), hit);
+        for (line, count) in &self.lines {
+            let _ = writeln!(out, "DA:{},{}", line, count);
+        }
+        let hit = self.lines.values().filter(|count| **count > 0).count();
+        let _ = write!(out, "LF:{}\nLH:{}\nend_of_record\n", self.lines.len(), hit);
+        out
+    }
+
+    // the records of an lcov .info file, other lines are ignored
+    pub fn parse_lcov(text: &str) -> Result<Vec<LineCoverage>, String> {
+        let mut records = Vec::new();
+        let mut record: Option<LineCoverage> = None;
+        for (n, line) in text.lines().enumerate() {
+            let at = || format!("line {}: bad lcov record {:?}", n + 1, line);
+            let line = line.trim();
+            if let Some(source) = line.strip_prefix("SF:") {
+                record = Some(LineCoverage {
+                    source: source.to_string(),
+                    ..LineCoverage::default()
+                });
+            } else if line == "end_of_record" {
+                records.extend(record.take());
+            } else if let Some(data) = line.strip_prefix("DA:") {
+                let record = record.as_mut().ok_or_else(at)?;
+                let mut fields = data.split(',');
+                let (Some(Ok(line)), Some(Ok(count))) = (
+                    fields.next().map(str::parse),
+                    fields.next().map(str::parse::<u64>),
+                ) else {
+                    return Err(at());
+                };
+                *record.lines.entry(line).or_default() += count;
+            } else if let Some(data) = line.strip_prefix("BRDA:") {
+                let record = record.as_mut().ok_or_else(at)?;
+                let fields: Vec<&str> = data.split(',').collect();
+                let [line, _, branch, taken] = fields[..] else {
+                    return Err(at());
+                };
+                let line = line.parse().map_err(|_| at())?;
+                let entry = record.branches.entry(line).or_default();
+                if taken == "-" {
+                    continue;
+                }
+                let taken: u64 = taken.parse().map_err(|_| at())?;
+                let counts = entry.get_or_insert((0, 0));
+                match branch {
+                    "0" => counts.0 += taken,
+                    "1" => counts.1 += taken,
+                    _ => return Err(at()),
+                }
+            }
+        }
+        Ok(records)
+    }
+
+    // the source with execution counts in the margin, ##### marks code that
+    // never ran
+    pub fn annotate(&self, source_text: &str) -> String {
+        let mut out = String::new();
+        for (n, text) in source_text.lines().enumerate() {
+            let line = n + 1;
+            let count = match self.lines.get(&line) {
+                Some(0) => "#####".to_string(),
+                Some(count) => count.to_string(),
+                None => "-".to_string(),
+            };
+            let _ = write!(out, "{:>9}:{:>5}:  {}", count, line, text);
+            match self.branches.get(&line) {
+                Some(Some((taken, not_taken))) => {
+                    let _ = write!(out, "    [taken {}, not taken {}]", taken, not_taken);
+                }
+                Some(None) => out.push_str("    [never reached]"),
+                None => (),
+            }
+            out.push('\n');
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::hw::vm::VM;
+    use std::sync::{Arc, Mutex};
+
+    const SOURCE: &str = "        .ORIG x3000
+        AND R0, R0, #0
+        ADD R0, R0, #1
+        BRz SKIP
+        ADD R0, R0, #1
+SKIP    HALT
+        .FILL #7
+        .END
+";
+
+    fn run(source: &str) -> LineCoverage {
+        let assembly = assemble(source).unwrap();
+        let mut vm = VM::new();
+        vm.load_image(&assembly.image);
+        let coverage = Arc::new(Mutex::new(Coverage::default()));
+        vm.add_observer(coverage.clone());
+        vm.execute_program().unwrap();
+        let coverage = coverage.lock().unwrap();
+        LineCoverage::new("prog.asm", &assembly, &coverage)
+    }
+
+    #[test]
+    fn test_lcov() {
+        let lines = run(SOURCE);
+        assert_eq!(
+            lines.to_lcov(),
+            "TN:\nSF:prog.asm\nBRDA:4,0,0,0\nBRDA:4,0,1,1\nBRF:2\nBRH:1\n\
+             DA:2,1\nDA:3,1\nDA:4,1\nDA:5,1\nDA:6,1\nLF:5\nLH:5\nend_of_record\n"
+        );
+        assert_eq!(LineCoverage::parse_lcov(&lines.to_lcov()), Ok(vec![lines]));
+    }
+
+    #[test]
+    fn test_merge_and_annotate() {
+        // the branch is taken this time and line 5 never runs
+        let mut lines =
+            run(&SOURCE.replace("ADD R0, R0, #1\n        BRz", "ADD R0, R0, #0\n        BRz"));
+        assert_eq!(lines.lines[&5], 0);
+        assert!(lines
+            .annotate(SOURCE)
+            .contains("    #####:    5:          ADD R0, R0, #1\n"));
+
+        lines.merge(&run(SOURCE));
+        assert_eq!(lines.lines[&5], 1);
+        assert_eq!(lines.branches[&4], Some((1, 1)));
+        let listing = lines.annotate(SOURCE);
+        assert!(listing.contains("        2:    4:          BRz SKIP    [taken 1, not taken 1]\n"));
+        assert!(listing.contains("        -:    7:          .FILL #7\n"));
+    }
+}
@@ -0,0 +1,696 @@
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+use serde_json::{json, Value};
+
+use crate::asm::Assembly;
+use crate::debugger::{cond_name, DEFAULT_HISTORY};
+use crate::expr::Expr;
+use crate::hw::breakpoint::Breakpoint;
+use crate::hw::instruction::RET;
+use crate::hw::register::PC_REG;
+use crate::hw::stop::StopReason;
+use crate::hw::vm::VM;
+use crate::image::Image;
+use crate::symbols::SymbolTable;
+
+pub mod protocol;
+
+// Debug Adapter Protocol server. Requests arrive on a channel so a running
+// program can be paused, responses and events go to the writer. Execution
+// goes through the VM's run/step/breakpoint API like the debugger's does
+
+const THREAD_ID: u64 = 1;
+// instructions run between checks for a pause request
+const SLICE: u64 = 10_000;
+// words the Memory scope shows from PC onwards
+const MEMORY_WINDOW: u16 = 16;
+
+const REGISTERS_REF: u64 = 1;
+const LABELS_REF: u64 = 2;
+const MEMORY_REF: u64 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Resume {
+    Continue,
+    StepIn,
+    Next,
+    StepOut,
+    StepBack,
+    ReverseContinue,
+}
+
+// reads messages on a thread of its own until the input ends
+pub fn spawn_reader(mut reader: impl BufRead + Send + 'static) -> Receiver<Value> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        while let Ok(Some(message)) = protocol::read_message(&mut reader) {
+            if sender.send(message).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+pub struct DapServer<W: Write> {
+    pub vm: VM,
+    requests: Receiver<Value>,
+    // requests that arrived while the program was running
+    pending: VecDeque<Value>,
+    out: W,
+    seq: u64,
+    // set when the program was assembled from source
+    assembly: Option<Assembly>,
+    source_path: Option<PathBuf>,
+    stop_on_entry: bool,
+    // breakpoints set from source lines, replaced by every setBreakpoints
+    source_breakpoints: Vec<u16>,
+}
+
+impl<W: Write> DapServer<W> {
+    pub fn new(requests: Receiver<Value>, out: W) -> Self {
+        DapServer {
+            vm: VM::new(),
+            requests,
+            pending: VecDeque::new(),
+            out,
+            seq: 0,
+            assembly: None,
+            source_path: None,
+            stop_on_entry: false,
+            source_breakpoints: Vec::new(),
+        }
+    }
+
+    // handles requests until disconnect or the end of input
+    pub fn serve(&mut self) -> io::Result<()> {
+        while let Some(request) = self
+            .pending
+            .pop_front()
+            .or_else(|| self.requests.recv().ok())
+        {
+            if !self.handle(&request)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, request: &Value) -> io::Result<bool> {
+        let args = &request["arguments"];
+        let result = match request["command"].as_str().unwrap_or("") {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsConditionalBreakpoints": true,
+                "supportsStepBack": true,
+                "supportsTerminateRequest": true,
+            })),
+            "launch" => {
+                let result = self.launch(args);
+                let launched = result.is_ok();
+                self.respond(request, result)?;
+                if launched {
+                    self.event("initialized", json!({}))?;
+                }
+                return Ok(true);
+            }
+            "setBreakpoints" => Ok(self.set_breakpoints(args)),
+            "setExceptionBreakpoints" => Ok(json!({ "breakpoints": [] })),
+            "configurationDone" => {
+                self.respond(request, Ok(json!({})))?;
+                if self.stop_on_entry {
+                    self.stopped("entry", None)?;
+                } else {
+                    self.resume(Resume::Continue)?;
+                }
+                return Ok(true);
+            }
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "LC-3" }] })),
+            "stackTrace" => Ok(self.stack_trace()),
+            "scopes" => Ok(json!({ "scopes": [
+                { "name": "Registers", "variablesReference": REGISTERS_REF, "expensive": false },
+                { "name": "Labels", "variablesReference": LABELS_REF, "expensive": false },
+                { "name": "Memory", "variablesReference": MEMORY_REF, "expensive": false },
+            ]})),
+            "variables" => Ok(self.variables(args["variablesReference"].as_u64().unwrap_or(0))),
+            "evaluate" => self.evaluate(args),
+            command @ ("continue" | "next" | "stepIn" | "stepOut" | "stepBack"
+            | "reverseContinue") => {
+                let resume = match command {
+                    "continue" => Resume::Continue,
+                    "next" => Resume::Next,
+                    "stepIn" => Resume::StepIn,
+                    "stepOut" => Resume::StepOut,
+                    "stepBack" => Resume::StepBack,
+                    _ => Resume::ReverseContinue,
+                };
+                let body = match resume {
+                    Resume::Continue => json!({ "allThreadsContinued": true }),
+                    _ => json!({}),
+                };
+                self.respond(request, Ok(body))?;
+                self.resume(resume)?;
+                return Ok(true);
+            }
+            // nothing is running, so there is nothing to pause
+            "pause" => Ok(json!({})),
+            "disconnect" | "terminate" => {
+                self.respond(request, Ok(json!({})))?;
+                return Ok(false);
+            }
+            command => Err(format!("unsupported request {:?}", command)),
+        };
+        self.respond(request, result)?;
+        Ok(true)
+    }
+
+    fn send(&mut self, mut message: Value) -> io::Result<()> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        protocol::write_message(&mut self.out, &message)
+    }
+
+    fn respond(&mut self, request: &Value, result: Result<Value, String>) -> io::Result<()> {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        self.send(response)
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    // launch { program, stopOnEntry?, input? }: an .asm program is
+    // assembled so breakpoints can be set on its source lines, any other
+    // file is loaded as an image with the .sym beside it
+    fn launch(&mut self, args: &Value) -> Result<Value, String> {
+        let program = args["program"]
+            .as_str()
+            .ok_or("launch needs a program")?
+            .to_string();
+        let path = Path::new(&program);
+        let is_source = path
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case("asm"));
+
+        let (image, symbols) = if is_source {
+            let assembly = Assembly::load(path)
+                .map_err(|e| format!("Unable to assemble {}: {}", program, e))?;
+            let loaded = (assembly.image.clone(), assembly.symbols.clone());
+            self.assembly = Some(assembly);
+            self.source_path = Some(path.to_path_buf());
+            loaded
+        } else {
+            let image =
+                Image::load(path).map_err(|e| format!("Unable to load {}: {}", program, e))?;
+            (image, SymbolTable::load_beside(path).unwrap_or_default())
+        };
+
+        let mut vm = VM::with_program(&image, symbols);
+        vm.history.set_limit(DEFAULT_HISTORY);
+        // STDIN and STDOUT carry the protocol, so the console is kept apart
+        vm.output = Some(Vec::new());
+        vm.input = Some(args["input"].as_str().unwrap_or("").bytes().collect());
+        self.vm = vm;
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+        Ok(json!({}))
+    }
+
+    // setBreakpoints { source: { path }, breakpoints: [{ line, condition? }] }
+    fn set_breakpoints(&mut self, args: &Value) -> Value {
+        for addr in self.source_breakpoints.drain(..) {
+            self.vm.breakpoints.remove(&addr);
+        }
+
+        let same_source = match (&self.source_path, args["source"]["path"].as_str()) {
+            (Some(ours), Some(theirs)) => same_file(ours, Path::new(theirs)),
+            _ => false,
+        };
+        let requested = args["breakpoints"].as_array().cloned().unwrap_or_default();
+        let mut breakpoints = Vec::new();
+        for (id, requested) in requested.iter().enumerate() {
+            let line = requested["line"].as_u64().unwrap_or(0) as usize;
+            let location = match &self.assembly {
+                Some(assembly) if same_source => assembly.addr_at_line(line),
+                _ => None,
+            };
+            let Some((addr, line)) = location else {
+                breakpoints.push(json!({
+                    "id": id + 1,
+                    "verified": false,
+                    "line": line,
+                    "message": "no code at this line",
+                }));
+                continue;
+            };
+
+            let condition = match requested["condition"].as_str() {
+                Some(text) if !text.trim().is_empty() => {
+                    match Expr::parse(text, &self.vm.symbols) {
+                        Ok(condition) => Some(condition),
+                        Err(e) => {
+                            breakpoints.push(json!({
+                                "id": id + 1,
+                                "verified": false,
+                                "line": line,
+                                "message": e,
+                            }));
+                            continue;
+                        }
+                    }
+                }
+                _ => None,
+            };
+            self.vm
+                .breakpoints
+                .insert(addr, Breakpoint::conditional(condition));
+            self.source_breakpoints.push(addr);
+            breakpoints.push(json!({
+                "id": id + 1,
+                "verified": true,
+                "line": line,
+                "instructionReference": format!("0x{:04X}", addr),
+            }));
+        }
+        json!({ "breakpoints": breakpoints })
+    }
+
+    // the LC-3 has no call stack to walk, the single frame is PC
+    fn stack_trace(&self) -> Value {
+        let pc = self.vm.registers.get_val(PC_REG);
+        let mut frame = json!({
+            "id": 0,
+            "name": format!("{}  {}", self.vm.symbols.format_addr(pc), self.vm.disassemble(pc)),
+            "line": 0,
+            "column": 0,
+            "instructionPointerReference": format!("0x{:04X}", pc),
+        });
+        let line = self.assembly.as_ref().and_then(|a| a.line_at(pc));
+        if let (Some(line), Some(path)) = (line, &self.source_path) {
+            frame["line"] = json!(line);
+            frame["column"] = json!(1);
+            frame["source"] = json!({
+                "name": path.file_name().map(|n| n.to_string_lossy()),
+                "path": path.display().to_string(),
+            });
+        }
+        json!({ "stackFrames": [frame], "totalFrames": 1 })
+    }
+
+    fn variables(&self, reference: u64) -> Value {
+        let variable = |name: String, value: String| json!({ "name": name, "value": value, "variablesReference": 0 });
+        let variables: Vec<Value> = match reference {
+            REGISTERS_REF => {
+                let mut registers: Vec<Value> = (0..8)
+                    .map(|r| variable(format!("R{}", r), word(self.vm.registers.get_val(r))))
+                    .collect();
+                let pc = self.vm.registers.get_val(PC_REG);
+                registers.push(variable("PC".to_string(), format!("x{:04X}", pc)));
+                let psr = self.vm.psr();
+                registers.push(variable(
+                    "PSR".to_string(),
+                    format!("x{:04X} ({})", psr, cond_name(psr & 0x7)),
+                ));
+                registers
+            }
+            LABELS_REF => self
+                .vm
+                .symbols
+                .iter()
+                .map(|(addr, name)| {
+                    let mut label = variable(name.to_string(), word(self.vm.memory[addr as usize]));
+                    label["memoryReference"] = json!(format!("0x{:04X}", addr));
+                    label
+                })
+                .collect(),
+            MEMORY_REF => {
+                let pc = self.vm.registers.get_val(PC_REG);
+                (0..MEMORY_WINDOW)
+                    .map(|i| {
+                        let addr = pc.wrapping_add(i);
+                        variable(
+                            format!("x{:04X}", addr),
+                            format!(
+                                "x{:04X}  {}",
+                                self.vm.memory[addr as usize],
+                                self.vm.disassemble(addr)
+                            ),
+                        )
+                    })
+                    .collect()
+            }
+            _ => Vec::new(),
+        };
+        json!({ "variables": variables })
+    }
+
+    // expressions use the debugger's condition language
+    fn evaluate(&self, args: &Value) -> Result<Value, String> {
+        let text = args["expression"].as_str().unwrap_or("");
+        let expr = Expr::parse(text, &self.vm.symbols)?;
+        let value = expr.eval(&self.vm);
+        Ok(json!({
+            "result": format!("{} (x{:04X})", value, value as u16),
+            "variablesReference": 0,
+        }))
+    }
+
+    fn resume(&mut self, resume: Resume) -> io::Result<()> {
+        if self.vm.halted && !matches!(resume, Resume::StepBack | Resume::ReverseContinue) {
+            return self.exited();
+        }
+
+        let pc = self.vm.registers.get_val(PC_REG);
+        let stop = match resume {
+            Resume::Continue => self.run_continuously(None)?,
+            Resume::StepIn => Some(self.vm.run(Some(1))),
+            // steps over JSR and JSRR by running until the call returns
+            Resume::Next if call_depth_change(self.vm.memory[pc as usize]) == 1 => {
+                self.run_continuously(Some(0))?
+            }
+            Resume::Next => Some(self.vm.run(Some(1))),
+            // runs until the subroutine being run returns
+            Resume::StepOut => self.run_continuously(Some(-1))?,
+            Resume::StepBack => Some(self.vm.run_back(Some(1))),
+            Resume::ReverseContinue => Some(self.vm.run_back(None)),
+        };
+        self.flush_output()?;
+
+        let Some(stop) = stop else {
+            return self.stopped("pause", None);
+        };
+        match stop {
+            StopReason::Halted => self.exited(),
+            StopReason::Fault(fault) => {
+                let description = fault.describe(&self.vm.symbols);
+                self.stopped("exception", Some(description))
+            }
+            // a single step back lands on breakpoints like any other line
+            StopReason::Breakpoint(addr)
+                if resume != Resume::StepBack && self.source_breakpoints.contains(&addr) =>
+            {
+                self.stopped("breakpoint", None)
+            }
+            StopReason::Breakpoint(_) => self.stopped("step", None),
+            StopReason::Watchpoint(hit) => {
+                let description = hit.describe(&self.vm.symbols);
+                self.stopped("data breakpoint", Some(description))
+            }
+            StopReason::HistoryStart | StopReason::Loop(_) | StopReason::EndOfInput(_) => {
+                let description = stop.describe(&self.vm.symbols);
+                self.stopped("step", Some(description))
+            }
+            StopReason::StepLimit => self.stopped("step", None),
+        }
+    }
+
+    // runs in slices until the program stops, or with a depth until the
+    // calls and returns made from here leave it that many calls deeper: 0
+    // once the call about to be made has returned, -1 once the subroutine
+    // being run has. Reaching the depth stops with StepLimit. None when a
+    // pause request came in
+    fn run_continuously(&mut self, depth: Option<i64>) -> io::Result<Option<StopReason>> {
+        // calls made less returns so far
+        let mut calls = 0;
+        let mut stop = self.run_slice(depth, &mut calls, true);
+        while stop == Err(StopReason::StepLimit) {
+            self.flush_output()?;
+            match self.requests.try_recv() {
+                Ok(request) if request["command"] == "pause" => {
+                    self.respond(&request, Ok(json!({})))?;
+                    return Ok(None);
+                }
+                Ok(request) => self.pending.push_back(request),
+                Err(TryRecvError::Empty) => (),
+                // the client went away, stop running for it
+                Err(TryRecvError::Disconnected) => return Ok(None),
+            }
+            stop = self.run_slice(depth, &mut calls, false);
+        }
+        Ok(Some(stop.err().unwrap_or(StopReason::StepLimit)))
+    }
+
+    // one slice of run_continuously, Ok once the depth is reached and Err
+    // with why the VM stopped otherwise. Counting calls takes a step at a time
+    fn run_slice(
+        &mut self,
+        depth: Option<i64>,
+        calls: &mut i64,
+        first: bool,
+    ) -> Result<(), StopReason> {
+        let Some(target) = depth else {
+            return Err(match first {
+                true => self.vm.run(Some(SLICE)),
+                false => self.vm.run_slice(Some(SLICE)),
+            });
+        };
+        for i in 0..SLICE {
+            let pc = self.vm.registers.get_val(PC_REG);
+            let change = call_depth_change(self.vm.memory[pc as usize]);
+            // resuming from a breakpoint does not stop on it again
+            let stop = match first && i == 0 {
+                true => self.vm.run(Some(1)),
+                false => self.vm.run_slice(Some(1)),
+            };
+            if stop != StopReason::StepLimit {
+                return Err(stop);
+            }
+            *calls += change;
+            if *calls == target {
+                return Ok(());
+            }
+        }
+        Err(StopReason::StepLimit)
+    }
+
+    fn stopped(&mut self, reason: &str, description: Option<String>) -> io::Result<()> {
+        let mut body = json!({
+            "reason": reason,
+            "threadId": THREAD_ID,
+            "allThreadsStopped": true,
+        });
+        if let Some(description) = description {
+            body["description"] = json!(description);
+            body["text"] = json!(description);
+        }
+        self.event("stopped", body)
+    }
+
+    fn exited(&mut self) -> io::Result<()> {
+        self.flush_output()?;
+        self.event("exited", json!({ "exitCode": 0 }))?;
+        self.event("terminated", json!({}))
+    }
+
+    // console text the program wrote since the last flush
+    fn flush_output(&mut self) -> io::Result<()> {
+        let Some(output) = self.vm.output.as_mut().filter(|o| !o.is_empty()) else {
+            return Ok(());
+        };
+        let text = String::from_utf8_lossy(&std::mem::take(output)).into_owned();
+        self.event("output", json!({ "category": "stdout", "output": text }))
+    }
+}
+
+// +1 for the instructions that call a subroutine, -1 for RET
+fn call_depth_change(instruction: u16) -> i64 {
+    match instruction {
+        RET => -1,
+        _ if instruction >> 12 == 0x4 => 1,
+        _ => 0,
+    }
+}
+
+// a value shown in hex and as a signed decimal
+fn word(value: u16) -> String {
+    format!("x{:04X} (#{})", value, value as i16)
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    a == b
+        || matches!(
+            (a.canonicalize(), b.canonicalize()),
+            (Ok(a), Ok(b)) if a == b
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const SOURCE: &str = "        .ORIG x3000
+        AND R0, R0, #0
+LOOP    ADD R0, R0, #1
+        ADD R1, R0, #-3
+        BRn LOOP
+        LEA R0, MSG
+        PUTS
+        HALT
+MSG     .STRINGZ \"done\"
+        .END
+";
+
+    // runs a scripted session and returns every message the server sent
+    fn session(requests: Vec<Value>) -> Vec<Value> {
+        let (sender, receiver) = mpsc::channel();
+        for (seq, request) in requests.into_iter().enumerate() {
+            let mut request = request;
+            request["seq"] = json!(seq + 1);
+            request["type"] = json!("request");
+            sender.send(request).unwrap();
+        }
+        drop(sender);
+
+        let mut out = Vec::new();
+        DapServer::new(receiver, &mut out).serve().unwrap();
+        let mut reader = io::Cursor::new(out);
+        let mut messages = Vec::new();
+        while let Some(message) = protocol::read_message(&mut reader).unwrap() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    fn events<'a>(messages: &'a [Value], name: &str) -> Vec<&'a Value> {
+        messages.iter().filter(|m| m["event"] == name).collect()
+    }
+
+    fn response<'a>(messages: &'a [Value], command: &str) -> &'a Value {
+        messages
+            .iter()
+            .find(|m| m["type"] == "response" && m["command"] == command)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_session() {
+        let path = std::env::temp_dir().join(format!("lc3-dap-{}.asm", std::process::id()));
+        fs::write(&path, SOURCE).unwrap();
+        let source = path.display().to_string();
+
+        let messages = session(vec![
+            json!({ "command": "initialize", "arguments": {} }),
+            json!({ "command": "launch", "arguments": { "program": source } }),
+            json!({ "command": "setBreakpoints", "arguments": {
+                "source": { "path": source },
+                "breakpoints": [{ "line": 4, "condition": "R0 == 2" }, { "line": 10 }],
+            }}),
+            json!({ "command": "configurationDone" }),
+            json!({ "command": "stackTrace", "arguments": { "threadId": 1 } }),
+            json!({ "command": "variables", "arguments": { "variablesReference": REGISTERS_REF } }),
+            json!({ "command": "next" }),
+            json!({ "command": "stepBack" }),
+            json!({ "command": "evaluate", "arguments": { "expression": "R0 + 40" } }),
+            json!({ "command": "continue" }),
+            json!({ "command": "disconnect" }),
+        ]);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(events(&messages, "initialized").len(), 1);
+        let breakpoints = &response(&messages, "setBreakpoints")["body"]["breakpoints"];
+        assert_eq!(breakpoints[0]["verified"], true);
+        // nothing is assembled from .END onwards
+        assert_eq!(breakpoints[1]["verified"], false);
+
+        let frame = &response(&messages, "stackTrace")["body"]["stackFrames"][0];
+        assert_eq!(frame["line"], 4);
+        let registers = &response(&messages, "variables")["body"]["variables"];
+        assert_eq!(registers[0]["value"], "x0002 (#2)");
+        assert_eq!(
+            response(&messages, "evaluate")["body"]["result"],
+            "42 (x002A)"
+        );
+
+        let stopped: Vec<&Value> = events(&messages, "stopped")
+            .into_iter()
+            .map(|e| &e["body"]["reason"])
+            .collect();
+        assert_eq!(stopped, vec!["breakpoint", "step", "step"]);
+        assert_eq!(events(&messages, "output")[0]["body"]["output"], "done");
+        assert_eq!(events(&messages, "exited").len(), 1);
+    }
+
+    #[test]
+    fn test_launch_error() {
+        let messages = session(vec![json!({
+            "command": "launch",
+            "arguments": { "program": "/nonexistent/prog.asm" },
+        })]);
+        assert_eq!(response(&messages, "launch")["success"], false);
+        assert!(events(&messages, "initialized").is_empty());
+    }
+
+    #[test]
+    fn test_step_over_recursion() {
+        // DOWN calls itself until R0 reaches 0, every frame returns to DONE
+        let source = "        .ORIG x3000
+        LD R6, STACK
+        AND R0, R0, #0
+        ADD R0, R0, #3
+        JSR DOWN
+        ADD R2, R0, #0
+        HALT
+DOWN    ADD R6, R6, #-1
+        STR R7, R6, #0
+        ADD R0, R0, #-1
+        BRz DONE
+        JSR DOWN
+DONE    LDR R7, R6, #0
+        ADD R6, R6, #1
+        RET
+STACK   .FILL x4000
+        .END
+";
+        let path = std::env::temp_dir().join(format!("lc3-dap-rec-{}.asm", std::process::id()));
+        fs::write(&path, source).unwrap();
+        let source = path.display().to_string();
+        let r6 = json!({ "command": "evaluate", "arguments": { "expression": "R6" } });
+        let line = json!({ "command": "stackTrace", "arguments": { "threadId": 1 } });
+
+        let messages = session(vec![
+            json!({ "command": "launch", "arguments": { "program": source } }),
+            json!({ "command": "setBreakpoints", "arguments": {
+                "source": { "path": source },
+                "breakpoints": [{ "line": 12, "condition": "R0 == 2" }],
+            }}),
+            json!({ "command": "configurationDone" }),
+            // over the JSR of the outermost frame, past the inner frames
+            // that return to the same address first
+            json!({ "command": "next" }),
+            line.clone(),
+            r6.clone(),
+            // out of DOWN, although R7 now holds an inner frame's return
+            json!({ "command": "stepOut" }),
+            line,
+            r6,
+            json!({ "command": "disconnect" }),
+        ]);
+        fs::remove_file(&path).unwrap();
+
+        let lines: Vec<&Value> = messages
+            .iter()
+            .filter(|m| m["command"] == "stackTrace")
+            .map(|m| &m["body"]["stackFrames"][0]["line"])
+            .collect();
+        assert_eq!(lines, vec![13, 6]);
+        let r6: Vec<&Value> = messages
+            .iter()
+            .filter(|m| m["command"] == "evaluate")
+            .map(|m| &m["body"]["result"])
+            .collect();
+        assert_eq!(r6, vec!["16383 (x3FFF)", "16384 (x4000)"]);
+    }
+}
@@ -0,0 +1,64 @@
+use std::io::{self, BufRead, Write};
+
+use serde_json::Value;
+
+use crate::image::invalid_data;
+
+// DAP framing: a Content-Length header, a blank line, then a JSON body
+
+// next message, None at end of input
+pub fn read_message(reader: &mut impl BufRead) -> io::Result<Option<Value>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            // blank lines before any header are tolerated
+            if length.is_some() {
+                break;
+            }
+            continue;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("Content-Length") {
+                let value = value.trim().parse::<usize>();
+                length = Some(value.map_err(|_| invalid_data("bad Content-Length"))?);
+            }
+        }
+    }
+
+    let mut body = vec![0u8; length.unwrap_or(0)];
+    reader.read_exact(&mut body)?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|e| invalid_data(e.to_string()))
+}
+
+pub fn write_message(writer: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_round_trip() {
+        let mut bytes = Vec::new();
+        write_message(&mut bytes, &json!({"seq": 1, "type": "request"})).unwrap();
+        write_message(&mut bytes, &json!({"seq": 2, "command": "ünïcode"})).unwrap();
+
+        let mut reader = io::Cursor::new(bytes);
+        let first = read_message(&mut reader).unwrap().unwrap();
+        assert_eq!(first["seq"], 1);
+        let second = read_message(&mut reader).unwrap().unwrap();
+        assert_eq!(second["command"], "ünïcode");
+        assert_eq!(read_message(&mut reader).unwrap(), None);
+    }
+}
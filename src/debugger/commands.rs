@@ -0,0 +1,285 @@
+use crate::expr::Expr;
+use crate::hw::register::{COND_REG, PC_REG};
+use crate::hw::watch::{CmpOp, WatchKind, Watchpoint};
+use crate::symbols::SymbolTable;
+
+// Something the user can name and assign to with `set`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    Register(u8), /* R0-R7, PC and COND use their register.rs indices */
+    Psr,
+    Memory(u16),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Step(u64),
+    Continue,
+    ReverseStep(u64),
+    ReverseContinue,
+    History(Option<usize>),
+    Break(u16, Option<Expr>),
+    Condition(u16, Option<Expr>),
+    Ignore(u16, u32),
+    Commands(u16, Vec<String>),
+    Delete(Option<u16>),
+    Breakpoints,
+    Watch(Watchpoint),
+    Unwatch(Option<usize>),
+    Watchpoints,
+    Registers,
+    Set(Target, u16),
+    Examine(u16, u16),
+    List(Option<u16>, u16),
+    Symbols,
+    Reset,
+    Reload,
+    Help,
+    Quit,
+}
+
+pub const HELP: &str = "\
+step [N]            (s)  execute N instructions, default 1
+continue            (c)  run until a breakpoint, fault or HALT
+reverse-step [N]    (rs) undo N instructions, default 1
+reverse-continue    (rc) run backwards to a breakpoint or watchpoint
+history [SIZE]           show or set how many instructions can be undone
+break ADDR [if EXPR] (b) set a breakpoint at an address or label, only
+                         stopping when EXPR is true
+condition ADDR [EXPR]    set or clear the condition of a breakpoint
+ignore ADDR COUNT        pass a breakpoint COUNT times before stopping
+commands ADDR [C; ...]   debugger commands to run when a breakpoint stops,
+                         continue resumes execution
+delete [ADDR]       (d)  clear one breakpoint, or all of them
+watch ADDR[:N] [OP V]    stop when N words at ADDR are written, optionally
+                         only with a value matching OP V (== != < <= > >=)
+rwatch ADDR[:N] [OP V]   stop when they are read
+awatch ADDR[:N] [OP V]   stop when they are read or written
+unwatch [NUM]            clear one watchpoint, or all of them
+info breakpoints         list breakpoints
+info watchpoints         list numbered watchpoints
+regs                (r)  print R0-R7, PC, PSR and condition codes
+set REG VALUE            assign R0-R7, PC, PSR or COND
+set mem[ADDR] VALUE      assign a memory word
+x ADDR [COUNT]           examine COUNT memory words, default 8
+list [ADDR] [COUNT] (l)  disassemble around ADDR, default PC
+symbols                  list the loaded labels
+reset                    restore the loaded image and registers
+reload                   read the image from disk again and reset
+quit                (q)  leave the debugger
+Numbers are hex (x41, 0x41 or 41) or decimal (#65), addresses may be
+labels with an optional offset (LOOP+3).
+Conditions use R0-R7, PC, PSR, COND, the flags N, Z and P, mem[ADDR],
+labels, signed(), == != < <= > >= && || ! + - & | ^ ~, where bare numbers
+are decimal, e.g. R0 == x41 && mem[x4000] > 3.";
+
+pub fn parse(line: &str, symbols: &SymbolTable) -> Result<Command, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let (name, args) = match words.split_first() {
+        Some((name, args)) => (name.to_ascii_lowercase(), args),
+        None => return Err("empty command".to_string()),
+    };
+    let addr = |text: &str| {
+        symbols
+            .resolve(text)
+            .ok_or_else(|| format!("unknown address or value {:?}", text))
+    };
+    // counts are decimal unless written with a prefix
+    let count = |text: &str| text.parse::<u16>().or_else(|_| addr(text));
+
+    let command = match (name.as_str(), args) {
+        ("step" | "s" | "si", []) => Command::Step(1),
+        ("step" | "s" | "si", [n]) => {
+            Command::Step(n.parse::<u64>().or_else(|_| addr(n).map(u64::from))?)
+        }
+        ("continue" | "c", []) => Command::Continue,
+        ("reverse-step" | "rs", []) => Command::ReverseStep(1),
+        ("reverse-step" | "rs", [n]) => {
+            Command::ReverseStep(n.parse().map_err(|_| format!("bad count {:?}", n))?)
+        }
+        ("reverse-continue" | "rc", []) => Command::ReverseContinue,
+        ("history", []) => Command::History(None),
+        ("history", [n]) => Command::History(Some(
+            n.parse().map_err(|_| format!("bad history size {:?}", n))?,
+        )),
+        ("break" | "b", [a]) => Command::Break(addr(a)?, None),
+        ("break" | "b", [a, "if", condition @ ..]) => {
+            Command::Break(addr(a)?, Some(parse_condition(condition, symbols)?))
+        }
+        ("condition", [a]) => Command::Condition(addr(a)?, None),
+        ("condition", [a, condition @ ..]) => {
+            Command::Condition(addr(a)?, Some(parse_condition(condition, symbols)?))
+        }
+        ("ignore", [a, n]) => Command::Ignore(
+            addr(a)?,
+            n.parse().map_err(|_| format!("bad count {:?}", n))?,
+        ),
+        ("commands", [a, rest @ ..]) => Command::Commands(
+            addr(a)?,
+            rest.join(" ")
+                .split(';')
+                .map(|c| c.trim().to_string())
+                .filter(|c| !c.is_empty())
+                .collect(),
+        ),
+        ("delete" | "d", []) => Command::Delete(None),
+        ("delete" | "d", [a]) => Command::Delete(Some(addr(a)?)),
+        ("info", ["breakpoints" | "break" | "b"]) => Command::Breakpoints,
+        ("watch" | "rwatch" | "awatch", [range, condition @ ..]) => {
+            let kind = match name.as_str() {
+                "rwatch" => WatchKind::Read,
+                "awatch" => WatchKind::Access,
+                _ => WatchKind::Write,
+            };
+            Command::Watch(parse_watch(kind, range, condition, symbols)?)
+        }
+        ("unwatch", []) => Command::Unwatch(None),
+        ("unwatch", [n]) => Command::Unwatch(Some(
+            n.parse()
+                .map_err(|_| format!("bad watchpoint number {:?}", n))?,
+        )),
+        ("info", ["watchpoints" | "watch" | "w"]) => Command::Watchpoints,
+        ("info", ["registers" | "regs" | "r"]) | ("regs" | "r", []) => Command::Registers,
+        ("set", [target, value]) => Command::Set(parse_target(target, symbols)?, addr(value)?),
+        ("x", [a]) => Command::Examine(addr(a)?, 8),
+        ("x", [a, n]) => Command::Examine(addr(a)?, count(n)?),
+        ("list" | "l", []) => Command::List(None, 10),
+        ("list" | "l", [a]) => Command::List(Some(addr(a)?), 10),
+        ("list" | "l", [a, n]) => Command::List(Some(addr(a)?), count(n)?),
+        ("symbols", []) => Command::Symbols,
+        ("reset", []) => Command::Reset,
+        ("reload", []) => Command::Reload,
+        ("help" | "h" | "?", _) => Command::Help,
+        ("quit" | "q" | "exit", []) => Command::Quit,
+        _ => return Err(format!("unknown command {:?}, try help", line.trim())),
+    };
+    Ok(command)
+}
+
+fn parse_condition(words: &[&str], symbols: &SymbolTable) -> Result<Expr, String> {
+    Expr::parse(&words.join(" "), symbols)
+}
+
+// ADDR or ADDR:COUNT followed by nothing or an OP VALUE pair
+fn parse_watch(
+    kind: WatchKind,
+    range: &str,
+    condition: &[&str],
+    symbols: &SymbolTable,
+) -> Result<Watchpoint, String> {
+    let (start, count) = match range.split_once(':') {
+        Some((start, count)) => (
+            start,
+            count
+                .parse::<u16>()
+                .ok()
+                .filter(|c| *c > 0)
+                .ok_or_else(|| format!("bad word count {:?}", count))?,
+        ),
+        None => (range, 1),
+    };
+    let start = symbols
+        .resolve(start)
+        .ok_or_else(|| format!("unknown address {:?}", start))?;
+
+    let condition = match condition {
+        [] => None,
+        [op, value] => Some((
+            CmpOp::from_symbol(op).ok_or_else(|| format!("unknown comparison {:?}", op))?,
+            symbols
+                .resolve(value)
+                .ok_or_else(|| format!("unknown value {:?}", value))?,
+        )),
+        _ => return Err("expected a comparison like == x41".to_string()),
+    };
+
+    Ok(Watchpoint {
+        start,
+        end: start.wrapping_add(count - 1),
+        kind,
+        condition,
+    })
+}
+
+// R0-R7, PC, PSR, COND (or CC) and mem[ADDR]
+pub fn parse_target(text: &str, symbols: &SymbolTable) -> Result<Target, String> {
+    let upper = text.to_ascii_uppercase();
+    if let Some(inner) = upper
+        .strip_prefix("MEM[")
+        .and_then(|rest| rest.strip_suffix(']'))
+    {
+        // resolve the original spelling so labels keep their case
+        let inner = &text[4..4 + inner.len()];
+        return symbols
+            .resolve(inner)
+            .map(Target::Memory)
+            .ok_or_else(|| format!("unknown address {:?}", inner));
+    }
+
+    match upper.as_str() {
+        "PC" => Ok(Target::Register(PC_REG)),
+        "PSR" => Ok(Target::Psr),
+        "COND" | "CC" => Ok(Target::Register(COND_REG)),
+        _ => upper
+            .strip_prefix('R')
+            .and_then(|n| n.parse::<u8>().ok())
+            .filter(|n| *n < 8)
+            .map(Target::Register)
+            .ok_or_else(|| format!("unknown register {:?}", text)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert("LOOP", 0x3004);
+
+        assert_eq!(parse("s", &symbols), Ok(Command::Step(1)));
+        assert_eq!(parse("step 20", &symbols), Ok(Command::Step(20)));
+        assert_eq!(
+            parse("b LOOP+1", &symbols),
+            Ok(Command::Break(0x3005, None))
+        );
+        assert_eq!(
+            parse("b LOOP if R0 == 3", &symbols),
+            Ok(Command::Break(
+                0x3004,
+                Some(Expr::parse("R0 == 3", &symbols).unwrap())
+            ))
+        );
+        assert_eq!(
+            parse("commands LOOP regs; continue", &symbols),
+            Ok(Command::Commands(
+                0x3004,
+                vec!["regs".to_string(), "continue".to_string()]
+            ))
+        );
+        assert_eq!(
+            parse("set r3 #-1", &symbols),
+            Ok(Command::Set(Target::Register(3), 0xFFFF))
+        );
+        assert_eq!(
+            parse("set mem[LOOP] x41", &symbols),
+            Ok(Command::Set(Target::Memory(0x3004), 0x41))
+        );
+        assert_eq!(
+            parse("x x4000 4", &symbols),
+            Ok(Command::Examine(0x4000, 4))
+        );
+        assert_eq!(
+            parse("watch LOOP:4 == #0", &symbols),
+            Ok(Command::Watch(Watchpoint {
+                start: 0x3004,
+                end: 0x3007,
+                kind: WatchKind::Write,
+                condition: Some((CmpOp::Eq, 0)),
+            }))
+        );
+        assert!(parse("set r8 1", &symbols).is_err());
+        assert!(parse("frobnicate", &symbols).is_err());
+    }
+}
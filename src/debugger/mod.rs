@@ -0,0 +1,481 @@
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+
+use crate::hw::breakpoint::Breakpoint;
+use crate::hw::register::{COND_REG, PC_REG};
+use crate::hw::stop::StopReason;
+use crate::hw::vm::VM;
+use crate::image::Image;
+use crate::symbols::SymbolTable;
+
+pub use crate::hw::register::cond_name;
+
+use commands::{Command, Target};
+
+pub mod commands;
+
+// instructions the debugger can step back over unless told otherwise
+pub const DEFAULT_HISTORY: usize = 100_000;
+
+// Interactive debugger driving a VM through its step/run/breakpoint API
+pub struct Debugger {
+    pub vm: VM,
+    image: Image,
+    image_path: PathBuf,
+    sym_path: Option<PathBuf>,
+    // debugger commands run when the breakpoint at an address stops
+    commands: BTreeMap<u16, Vec<String>>,
+    history_limit: usize,
+}
+
+impl Debugger {
+    pub fn new(image: Image, image_path: PathBuf, symbols: SymbolTable) -> Self {
+        let mut debugger = Debugger {
+            vm: VM::new(),
+            image,
+            image_path,
+            sym_path: None,
+            commands: BTreeMap::new(),
+            history_limit: DEFAULT_HISTORY,
+        };
+        debugger.vm.symbols = symbols;
+        debugger.reset();
+        debugger
+    }
+
+    // symbols given explicitly with --sym are re-read from there on reload
+    pub fn with_sym_path(mut self, sym_path: Option<PathBuf>) -> Self {
+        self.sym_path = sym_path;
+        self
+    }
+
+    // fresh machine with the image loaded, symbols and breakpoints are kept
+    pub fn reset(&mut self) {
+        let mut vm = VM::with_program(&self.image, std::mem::take(&mut self.vm.symbols));
+        vm.breakpoints = std::mem::take(&mut self.vm.breakpoints);
+        vm.watchpoints = std::mem::take(&mut self.vm.watchpoints);
+        vm.history.set_limit(self.history_limit);
+        // console redirection and queued input survive a reset
+        vm.output = self.vm.output.take().map(|mut output| {
+            output.clear();
+            output
+        });
+        vm.input = self.vm.input.take();
+        self.vm = vm;
+    }
+
+    fn reload(&mut self) -> io::Result<()> {
+        self.image = Image::load(&self.image_path)?;
+        self.vm.symbols = match &self.sym_path {
+            Some(path) => SymbolTable::load(path)?,
+            None => SymbolTable::load_beside(&self.image_path).unwrap_or_default(),
+        };
+        self.reset();
+        Ok(())
+    }
+
+    // reads commands from STDIN until quit or end of input
+    pub fn repl(&mut self) -> io::Result<()> {
+        let stdin = io::stdin();
+        let mut stdout = io::stdout();
+        let mut last_line = String::new();
+
+        writeln!(stdout, "Type help for a list of commands")?;
+        self.print_location(&mut stdout)?;
+        loop {
+            write!(stdout, "(lc3) ")?;
+            stdout.flush()?;
+
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            // an empty line repeats the previous command
+            if line.trim().is_empty() {
+                line = last_line.clone();
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match commands::parse(&line, &self.vm.symbols) {
+                Ok(command) => {
+                    if !self.execute(command, &mut stdout)? {
+                        return Ok(());
+                    }
+                }
+                Err(msg) => writeln!(stdout, "{}", msg)?,
+            }
+            last_line = line;
+        }
+    }
+
+    // performs one command, returns false when the debugger should exit
+    pub fn execute(&mut self, command: Command, out: &mut dyn Write) -> io::Result<bool> {
+        match command {
+            Command::Step(count) => self.resume(Some(count), out)?,
+            Command::Continue => self.resume(None, out)?,
+            Command::ReverseStep(count) => self.reverse(Some(count), out)?,
+            Command::ReverseContinue => self.reverse(None, out)?,
+            Command::History(Some(limit)) => {
+                self.history_limit = limit;
+                self.vm.history.set_limit(limit);
+            }
+            Command::History(None) => writeln!(
+                out,
+                "{} of up to {} instructions recorded",
+                self.vm.history.len(),
+                self.vm.history.limit()
+            )?,
+            Command::Break(addr, condition) => {
+                self.vm
+                    .breakpoints
+                    .insert(addr, Breakpoint::conditional(condition));
+                writeln!(out, "breakpoint at {}", self.vm.symbols.format_addr(addr))?;
+            }
+            Command::Condition(addr, condition) => match self.vm.breakpoints.get_mut(&addr) {
+                Some(breakpoint) => breakpoint.condition = condition,
+                None => self.no_breakpoint(addr, out)?,
+            },
+            Command::Ignore(addr, count) => match self.vm.breakpoints.get_mut(&addr) {
+                Some(breakpoint) => breakpoint.ignore = count,
+                None => self.no_breakpoint(addr, out)?,
+            },
+            Command::Commands(addr, lines) => {
+                if !self.vm.breakpoints.contains_key(&addr) {
+                    self.no_breakpoint(addr, out)?;
+                } else if lines.is_empty() {
+                    self.commands.remove(&addr);
+                } else {
+                    self.commands.insert(addr, lines);
+                }
+            }
+            Command::Delete(Some(addr)) => {
+                self.commands.remove(&addr);
+                if self.vm.breakpoints.remove(&addr).is_none() {
+                    writeln!(
+                        out,
+                        "no breakpoint at {}",
+                        self.vm.symbols.format_addr(addr)
+                    )?;
+                }
+            }
+            Command::Delete(None) => {
+                self.vm.breakpoints.clear();
+                self.commands.clear();
+            }
+            Command::Breakpoints => self.print_breakpoints(out)?,
+            Command::Watch(watchpoint) => {
+                self.vm.watchpoints.push(watchpoint);
+                writeln!(
+                    out,
+                    "watchpoint {}: {}",
+                    self.vm.watchpoints.len(),
+                    watchpoint.describe(&self.vm.symbols)
+                )?;
+            }
+            Command::Unwatch(Some(n)) => {
+                if n == 0 || n > self.vm.watchpoints.len() {
+                    writeln!(out, "no watchpoint {}", n)?;
+                } else {
+                    self.vm.watchpoints.remove(n - 1);
+                }
+            }
+            Command::Unwatch(None) => self.vm.watchpoints.clear(),
+            Command::Watchpoints => {
+                if self.vm.watchpoints.is_empty() {
+                    writeln!(out, "no watchpoints")?;
+                }
+                for (i, watchpoint) in self.vm.watchpoints.iter().enumerate() {
+                    writeln!(out, "{}: {}", i + 1, watchpoint.describe(&self.vm.symbols))?;
+                }
+            }
+            Command::Registers => self.print_registers(out)?,
+            Command::Set(target, value) => {
+                match target {
+                    Target::Register(reg) => self.vm.registers.update_register(reg, value),
+                    Target::Psr => self.vm.set_psr(value),
+                    Target::Memory(addr) => self.vm.write_memory(addr as usize, value),
+                }
+                // undoing past a hand made change would not restore it
+                self.vm.history.clear();
+            }
+            Command::Examine(addr, count) => self.print_memory(addr, count, out)?,
+            Command::List(addr, count) => {
+                // without an address show a few instructions before PC too
+                let start = addr.unwrap_or(self.vm.registers.get_val(PC_REG).wrapping_sub(3));
+                for i in 0..count {
+                    writeln!(out, "{}", self.format_line(start.wrapping_add(i)))?;
+                }
+            }
+            Command::Symbols => {
+                for (addr, name) in self.vm.symbols.iter() {
+                    writeln!(out, "x{:04X}  {}", addr, name)?;
+                }
+            }
+            Command::Reset => {
+                self.reset();
+                self.print_location(out)?;
+            }
+            Command::Reload => match self.reload() {
+                Ok(()) => self.print_location(out)?,
+                Err(e) => writeln!(out, "unable to reload {}: {}", self.image_path.display(), e)?,
+            },
+            Command::Help => writeln!(out, "{}", commands::HELP)?,
+            Command::Quit => return Ok(false),
+        }
+        Ok(true)
+    }
+
+    fn resume(&mut self, max_steps: Option<u64>, out: &mut dyn Write) -> io::Result<()> {
+        if self.vm.halted {
+            writeln!(out, "the program has halted, use reset to run it again")?;
+            return Ok(());
+        }
+
+        loop {
+            let stop = self.vm.run(max_steps);
+            // the output of the program does not end in a newline in general
+            writeln!(out)?;
+            if stop != StopReason::StepLimit {
+                writeln!(out, "{}", stop.describe(&self.vm.symbols))?;
+            }
+            self.print_location(out)?;
+
+            // run the commands attached to the breakpoint, a continue among
+            // them resumes here instead of recursing for every hit
+            let StopReason::Breakpoint(addr) = stop else {
+                return Ok(());
+            };
+            let Some(lines) = self.commands.get(&addr).cloned() else {
+                return Ok(());
+            };
+            let mut resume = false;
+            for line in lines {
+                match commands::parse(&line, &self.vm.symbols) {
+                    Ok(Command::Continue) => {
+                        resume = true;
+                        break;
+                    }
+                    Ok(command) => {
+                        if !self.execute(command, out)? {
+                            return Ok(());
+                        }
+                    }
+                    Err(msg) => writeln!(out, "{}", msg)?,
+                }
+            }
+            if !resume || self.vm.halted {
+                return Ok(());
+            }
+        }
+    }
+
+    fn reverse(&mut self, max_steps: Option<u64>, out: &mut dyn Write) -> io::Result<()> {
+        let stop = self.vm.run_back(max_steps);
+        if stop != StopReason::StepLimit {
+            writeln!(out, "{}", stop.describe(&self.vm.symbols))?;
+        }
+        self.print_location(out)
+    }
+
+    fn no_breakpoint(&self, addr: u16, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(
+            out,
+            "no breakpoint at {}",
+            self.vm.symbols.format_addr(addr)
+        )
+    }
+
+    fn print_breakpoints(&self, out: &mut dyn Write) -> io::Result<()> {
+        if self.vm.breakpoints.is_empty() {
+            writeln!(out, "no breakpoints")?;
+        }
+        for (addr, breakpoint) in &self.vm.breakpoints {
+            write!(
+                out,
+                "{}  hit {} times",
+                self.vm.symbols.format_addr(*addr),
+                breakpoint.hits
+            )?;
+            if let Some(condition) = &breakpoint.condition {
+                write!(out, "  if {}", condition)?;
+            }
+            if breakpoint.ignore > 0 {
+                write!(out, "  ignore next {}", breakpoint.ignore)?;
+            }
+            writeln!(out)?;
+            if let Some(lines) = self.commands.get(addr) {
+                writeln!(out, "    commands: {}", lines.join("; "))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn print_location(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(
+            out,
+            "{}",
+            self.format_line(self.vm.registers.get_val(PC_REG))
+        )
+    }
+
+    // "* => x3002  LOOP        ADD R0, R0, #-1", marking breakpoints and PC
+    pub fn format_line(&self, addr: u16) -> String {
+        format!(
+            "{} {} x{:04X}  {:04X}  {:<12} {}",
+            if self.vm.breakpoints.contains_key(&addr) {
+                "*"
+            } else {
+                " "
+            },
+            if self.vm.registers.get_val(PC_REG) == addr {
+                "=>"
+            } else {
+                "  "
+            },
+            addr,
+            self.vm.memory[addr as usize],
+            self.vm.symbols.name_at(addr).unwrap_or(""),
+            self.vm.disassemble(addr)
+        )
+    }
+
+    fn print_registers(&self, out: &mut dyn Write) -> io::Result<()> {
+        for row in 0..2 {
+            let regs: Vec<String> = (0..4)
+                .map(|i| row * 4 + i)
+                .map(|r| format!("R{} x{:04X}", r, self.vm.registers.get_val(r)))
+                .collect();
+            writeln!(out, "{}", regs.join("  "))?;
+        }
+        writeln!(
+            out,
+            "PC {}  PSR x{:04X}  CC {}",
+            self.vm
+                .symbols
+                .format_addr(self.vm.registers.get_val(PC_REG)),
+            self.vm.psr(),
+            cond_name(self.vm.registers.get_val(COND_REG))
+        )
+    }
+
+    fn print_memory(&self, addr: u16, count: u16, out: &mut dyn Write) -> io::Result<()> {
+        for row in (0..count).step_by(8) {
+            let start = addr.wrapping_add(row);
+            let words: Vec<String> = (row..count.min(row + 8))
+                .map(|i| format!("{:04X}", self.vm.memory[addr.wrapping_add(i) as usize]))
+                .collect();
+            writeln!(
+                out,
+                "x{:04X}  {:<12} {}",
+                start,
+                self.vm.symbols.name_at(start).unwrap_or(""),
+                words.join(" ")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hw::register::PC_START;
+
+    fn debugger() -> Debugger {
+        // AND R0 R0 0; LOOP ADD R0 R0 1; BRnzp LOOP
+        let image = Image::new(PC_START, vec![0x5020, 0x1021, 0x0FFE]);
+        let mut symbols = SymbolTable::new();
+        symbols.insert("LOOP", PC_START + 1);
+        Debugger::new(image, PathBuf::from("loop.obj"), symbols)
+    }
+
+    fn run(debugger: &mut Debugger, line: &str) -> String {
+        let command = commands::parse(line, &debugger.vm.symbols).unwrap();
+        let mut out = Vec::new();
+        debugger.execute(command, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_breakpoint_and_continue() {
+        let mut debugger = debugger();
+        run(&mut debugger, "break LOOP");
+        let out = run(&mut debugger, "continue");
+        assert!(out.contains("breakpoint at LOOP (x3001)"));
+        assert_eq!(debugger.vm.registers.get_val(0), 0);
+
+        // continuing from the breakpoint runs the loop once more
+        run(&mut debugger, "c");
+        assert_eq!(debugger.vm.registers.get_val(0), 1);
+        assert_eq!(debugger.vm.registers.get_val(PC_REG), PC_START + 1);
+    }
+
+    #[test]
+    fn test_set_and_reset() {
+        let mut debugger = debugger();
+        run(&mut debugger, "step 2");
+        run(&mut debugger, "set R0 #41");
+        run(&mut debugger, "set mem[x4000] x1234");
+        assert_eq!(debugger.vm.registers.get_val(0), 41);
+        assert!(run(&mut debugger, "x x4000 1").contains("1234"));
+        assert!(run(&mut debugger, "regs").contains("R0 x0029"));
+
+        run(&mut debugger, "reset");
+        assert_eq!(debugger.vm.registers.get_val(0), 0);
+        assert_eq!(debugger.vm.registers.get_val(PC_REG), PC_START);
+    }
+
+    #[test]
+    fn test_conditional_breakpoint() {
+        let mut debugger = debugger();
+        run(&mut debugger, "break LOOP if R0 == 5");
+        run(&mut debugger, "continue");
+        assert_eq!(debugger.vm.registers.get_val(0), 5);
+
+        // pass the breakpoint 3 times, then stop on the 4th
+        run(&mut debugger, "condition LOOP");
+        run(&mut debugger, "ignore LOOP 3");
+        run(&mut debugger, "continue");
+        assert_eq!(debugger.vm.registers.get_val(0), 9);
+        assert!(run(&mut debugger, "info breakpoints").contains("hit 5 times"));
+    }
+
+    #[test]
+    fn test_breakpoint_commands() {
+        let mut debugger = debugger();
+        run(&mut debugger, "break LOOP");
+        run(&mut debugger, "commands LOOP regs; continue");
+        run(&mut debugger, "break LOOP+1 if R0 == 3");
+        let out = run(&mut debugger, "continue");
+        // regs ran at every stop on LOOP until the second breakpoint held
+        assert_eq!(out.matches("PSR").count(), 3);
+        assert_eq!(debugger.vm.registers.get_val(0), 3);
+    }
+
+    #[test]
+    fn test_reverse_execution() {
+        let mut debugger = debugger();
+        run(&mut debugger, "step 7");
+        assert_eq!(debugger.vm.registers.get_val(0), 3);
+
+        run(&mut debugger, "reverse-step 2");
+        assert_eq!(debugger.vm.registers.get_val(0), 2);
+        assert_eq!(debugger.vm.registers.get_val(PC_REG), PC_START + 1);
+
+        run(&mut debugger, "break LOOP if R0 == 1");
+        let out = run(&mut debugger, "reverse-continue");
+        assert!(out.contains("breakpoint at LOOP"));
+        assert_eq!(debugger.vm.registers.get_val(0), 1);
+
+        assert!(run(&mut debugger, "rc").contains("start of the recorded history"));
+        assert_eq!(debugger.vm.steps, 0);
+
+        // forward again reaches the same state
+        run(&mut debugger, "delete");
+        run(&mut debugger, "step 7");
+        assert_eq!(debugger.vm.registers.get_val(0), 3);
+    }
+}
@@ -0,0 +1,343 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+use crate::hw::fault::Fault;
+use crate::hw::register::cond_name;
+use crate::hw::trace::{TraceRecord, Tracer};
+use crate::hw::vm::VM;
+use crate::image::invalid_data;
+use crate::symbols::SymbolTable;
+
+// First divergence between two executions, given as recorded JSON Lines
+// traces or as live VMs traced one instruction at a time
+
+// Trace records from a JSON Lines file, a bad line ends the trace with an
+// error kept in `error`
+pub struct TraceFile {
+    lines: io::Lines<BufReader<File>>,
+    line: usize,
+    pub error: Option<io::Error>,
+}
+
+impl TraceFile {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Ok(TraceFile {
+            lines: BufReader::new(File::open(path)?).lines(),
+            line: 0,
+            error: None,
+        })
+    }
+}
+
+impl Iterator for TraceFile {
+    type Item = TraceRecord;
+
+    fn next(&mut self) -> Option<TraceRecord> {
+        loop {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(e) => {
+                    self.error = Some(e);
+                    return None;
+                }
+            };
+            self.line += 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record = serde_json::from_str(&line)
+                .ok()
+                .and_then(|value| TraceRecord::from_json(&value));
+            if record.is_none() {
+                let msg = format!("line {}: not a JSON Lines trace record", self.line);
+                self.error = Some(invalid_data(msg));
+            }
+            return record;
+        }
+    }
+}
+
+// Records of a VM as it runs, until it halts, faults or reaches max_steps
+pub struct VmTrace {
+    pub vm: VM,
+    max_steps: u64,
+    pub fault: Option<Fault>,
+}
+
+impl VmTrace {
+    pub fn new(mut vm: VM, max_steps: u64) -> Self {
+        vm.tracer = Some(Tracer::collecting());
+        VmTrace {
+            vm,
+            max_steps,
+            fault: None,
+        }
+    }
+}
+
+impl Iterator for VmTrace {
+    type Item = TraceRecord;
+
+    fn next(&mut self) -> Option<TraceRecord> {
+        if self.vm.halted || self.fault.is_some() || self.vm.steps >= self.max_steps {
+            return None;
+        }
+        if let Err(fault) = self.vm.step() {
+            self.fault = Some(fault);
+            return None;
+        }
+        self.vm.tracer.as_mut()?.take_records().pop()
+    }
+}
+
+// Either kind of trace, so a recording can be checked against a live run
+pub enum Source {
+    File(TraceFile),
+    Vm(Box<VmTrace>),
+}
+
+impl Source {
+    // why the trace ended early, if it did
+    pub fn error(&self, symbols: &SymbolTable) -> Option<String> {
+        match self {
+            Source::File(file) => file.error.as_ref().map(|e| e.to_string()),
+            Source::Vm(run) => run.fault.map(|fault| fault.describe(symbols)),
+        }
+    }
+}
+
+impl Iterator for Source {
+    type Item = TraceRecord;
+
+    fn next(&mut self) -> Option<TraceRecord> {
+        match self {
+            Source::File(file) => file.next(),
+            Source::Vm(run) => run.next(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    // 1 based position in the traces where they first differ
+    pub index: usize,
+    // the differing records, None where that trace had already ended
+    pub expected: Option<TraceRecord>,
+    pub actual: Option<TraceRecord>,
+    pub differences: Vec<String>,
+    // identical records just before the divergence
+    pub before: Vec<TraceRecord>,
+    // records following the divergence on either side
+    pub expected_after: Vec<TraceRecord>,
+    pub actual_after: Vec<TraceRecord>,
+}
+
+// what differs between two records of the same step, empty when they agree
+pub fn differences(expected: &TraceRecord, actual: &TraceRecord) -> Vec<String> {
+    let mut found = Vec::new();
+    if expected.pc != actual.pc {
+        found.push(format!("PC x{:04X} != x{:04X}", expected.pc, actual.pc));
+    } else if expected.instruction != actual.instruction {
+        found.push(format!(
+            "instruction {:04X} != {:04X}",
+            expected.instruction, actual.instruction
+        ));
+    }
+
+    let expected_regs: BTreeMap<u8, u16> = expected.registers.iter().copied().collect();
+    let actual_regs: BTreeMap<u8, u16> = actual.registers.iter().copied().collect();
+    for r in 0..8 {
+        match (expected_regs.get(&r), actual_regs.get(&r)) {
+            (Some(a), Some(b)) if a != b => found.push(format!("R{} x{:04X} != x{:04X}", r, a, b)),
+            (Some(a), None) => found.push(format!("R{} x{:04X} != unchanged", r, a)),
+            (None, Some(b)) => found.push(format!("R{} unchanged != x{:04X}", r, b)),
+            _ => (),
+        }
+    }
+
+    if expected.writes != actual.writes {
+        let show = |writes: &[(u16, u16)]| match writes {
+            [] => "no writes".to_string(),
+            _ => writes
+                .iter()
+                .map(|(addr, value)| format!("[x{:04X}]<-x{:04X}", addr, value))
+                .collect::<Vec<String>>()
+                .join(" "),
+        };
+        found.push(format!(
+            "memory {} != {}",
+            show(&expected.writes),
+            show(&actual.writes)
+        ));
+    }
+    if expected.cond != actual.cond {
+        found.push(format!(
+            "CC {} != {}",
+            cond_name(expected.cond),
+            cond_name(actual.cond)
+        ));
+    }
+    found
+}
+
+// walks both traces in step, None when they are identical to the end
+pub fn first_divergence(
+    expected: &mut impl Iterator<Item = TraceRecord>,
+    actual: &mut impl Iterator<Item = TraceRecord>,
+    context: usize,
+) -> Option<Divergence> {
+    let mut before = VecDeque::with_capacity(context + 1);
+    let mut index = 0;
+    loop {
+        index += 1;
+        let (a, b) = (expected.next(), actual.next());
+        let found = match (&a, &b) {
+            (None, None) => return None,
+            (Some(a), Some(b)) => differences(a, b),
+            (Some(_), None) => vec!["the actual trace ended first".to_string()],
+            (None, Some(_)) => vec!["the expected trace ended first".to_string()],
+        };
+
+        if found.is_empty() {
+            before.push_back(a.unwrap());
+            if before.len() > context {
+                before.pop_front();
+            }
+            continue;
+        }
+        return Some(Divergence {
+            index,
+            expected: a,
+            actual: b,
+            differences: found,
+            before: before.into(),
+            expected_after: expected.by_ref().take(context).collect(),
+            actual_after: actual.by_ref().take(context).collect(),
+        });
+    }
+}
+
+impl Divergence {
+    pub fn describe(&self, expected_symbols: &SymbolTable, actual_symbols: &SymbolTable) -> String {
+        let line = |record: &TraceRecord, symbols: &SymbolTable| {
+            format!(
+                "{:<12}{}",
+                symbols.label_for(record.pc).unwrap_or_default(),
+                record.to_text()
+            )
+        };
+        let mut out = format!("first divergence at step {}", self.index);
+        match (&self.expected, &self.actual) {
+            (Some(a), Some(b)) if a.pc != b.pc => out.push_str(&format!(
+                ", {} != {}",
+                expected_symbols.format_addr(a.pc),
+                actual_symbols.format_addr(b.pc)
+            )),
+            (Some(record), _) => {
+                out.push_str(&format!(", {}", expected_symbols.format_addr(record.pc)))
+            }
+            (None, Some(record)) => {
+                out.push_str(&format!(", {}", actual_symbols.format_addr(record.pc)))
+            }
+            (None, None) => (),
+        }
+        out.push('\n');
+        for difference in &self.differences {
+            out.push_str(&format!("  {}\n", difference));
+        }
+
+        out.push_str("\nbefore (identical):\n");
+        for record in &self.before {
+            out.push_str(&format!("  {}\n", line(record, expected_symbols)));
+        }
+        for (name, record, after, symbols) in [
+            (
+                "expected",
+                &self.expected,
+                &self.expected_after,
+                expected_symbols,
+            ),
+            ("actual", &self.actual, &self.actual_after, actual_symbols),
+        ] {
+            out.push_str(&format!("\n{}:\n", name));
+            match record {
+                Some(record) => out.push_str(&format!("> {}\n", line(record, symbols))),
+                None => out.push_str("> (end of trace)\n"),
+            }
+            for record in after {
+                out.push_str(&format!("  {}\n", line(record, symbols)));
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hw::register::PC_START;
+    use crate::image::Image;
+
+    fn vm(words: Vec<u16>) -> VM {
+        let mut vm = VM::new();
+        vm.load_image(&Image::new(PC_START, words));
+        vm
+    }
+
+    #[test]
+    fn test_identical() {
+        // AND R0 R0 0; ADD R0 R0 1; HALT
+        let program = vec![0x5020, 0x1021, 0xF025];
+        let mut expected = VmTrace::new(vm(program.clone()), 100);
+        let mut actual = VmTrace::new(vm(program), 100);
+        assert_eq!(first_divergence(&mut expected, &mut actual, 2), None);
+        assert!(expected.vm.halted && actual.vm.halted);
+    }
+
+    #[test]
+    fn test_divergence() {
+        // AND R0 R0 0; ADD R0 R0 1; ADD R0 R0 1; ST R0 #1; HALT
+        let expected = vm(vec![0x5020, 0x1021, 0x1021, 0x3001, 0xF025]);
+        // the second ADD adds 2
+        let actual = vm(vec![0x5020, 0x1021, 0x1022, 0x3001, 0xF025]);
+        let divergence = first_divergence(
+            &mut VmTrace::new(expected, 100),
+            &mut VmTrace::new(actual, 100),
+            1,
+        )
+        .unwrap();
+
+        assert_eq!(divergence.index, 3);
+        assert_eq!(
+            divergence.differences,
+            vec!["instruction 1021 != 1022", "R0 x0002 != x0003"]
+        );
+        assert_eq!(divergence.before.len(), 1);
+        assert_eq!(divergence.before[0].step, 2);
+        assert_eq!(divergence.actual_after[0].writes, vec![(0x3005, 3)]);
+
+        let mut symbols = SymbolTable::new();
+        symbols.insert("START", PC_START);
+        let report = divergence.describe(&symbols, &symbols);
+        assert!(report.starts_with("first divergence at step 3, START+2 (x3002)\n"));
+        assert!(report.contains("> START+2            3  x3002  1021"));
+    }
+
+    #[test]
+    fn test_trace_ends_early() {
+        let records: Vec<TraceRecord> =
+            VmTrace::new(vm(vec![0x5020, 0x1021, 0xF025]), 100).collect();
+        assert_eq!(records.len(), 3);
+        let mut expected = records.clone().into_iter();
+        let mut actual = records[..1].iter().cloned();
+        let divergence = first_divergence(&mut expected, &mut actual, 3).unwrap();
+        assert_eq!(divergence.index, 2);
+        assert_eq!(divergence.differences, vec!["the actual trace ended first"]);
+        assert_eq!(divergence.expected_after.len(), 1);
+        assert!(divergence
+            .describe(&SymbolTable::new(), &SymbolTable::new())
+            .contains("> (end of trace)"));
+    }
+}
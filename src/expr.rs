@@ -0,0 +1,369 @@
+use std::fmt;
+
+use crate::hw::register::{ConditionFlag, COND_REG, PC_REG};
+use crate::hw::vm::VM;
+use crate::symbols::SymbolTable;
+
+// Small expression language for breakpoint conditions, e.g.
+//   R0 == x41 && mem[x4000] > 3
+//   signed(R1) < 0 || mem[NODE+1] == 0
+// Values are evaluated as i32: registers and memory words are unsigned
+// unless wrapped in signed(), bare numbers are decimal, x41 or 0x41 are hex
+// and labels evaluate to their address. N, Z and P are the condition flags.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expr {
+    source: String,
+    root: Node,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Node {
+    Num(i32),
+    Reg(u8),
+    Psr,
+    Flag(u16),
+    Mem(Box<Node>),
+    Signed(Box<Node>),
+    Unary(UnOp, Box<Node>),
+    Binary(BinOp, Box<Node>, Box<Node>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum UnOp {
+    Neg,
+    Not,
+    BitNot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BinOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Add,
+    Sub,
+}
+
+impl Expr {
+    pub fn parse(text: &str, symbols: &SymbolTable) -> Result<Self, String> {
+        let tokens = tokenize(text)?;
+        let mut parser = Parser {
+            tokens,
+            pos: 0,
+            symbols,
+        };
+        let root = parser.parse_or()?;
+        if let Some(token) = parser.peek() {
+            return Err(format!("unexpected {:?} in condition", token));
+        }
+        Ok(Expr {
+            source: text.trim().to_string(),
+            root,
+        })
+    }
+
+    pub fn eval(&self, vm: &VM) -> i32 {
+        eval(&self.root, vm)
+    }
+
+    pub fn is_true(&self, vm: &VM) -> bool {
+        self.eval(vm) != 0
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+fn eval(node: &Node, vm: &VM) -> i32 {
+    match node {
+        Node::Num(n) => *n,
+        Node::Reg(r) => vm.registers.get_val(*r) as i32,
+        Node::Psr => vm.psr() as i32,
+        Node::Flag(mask) => (vm.registers.get_val(COND_REG) & mask != 0) as i32,
+        Node::Mem(addr) => vm.memory[eval(addr, vm) as u16 as usize] as i32,
+        Node::Signed(inner) => eval(inner, vm) as u16 as i16 as i32,
+        Node::Unary(op, inner) => {
+            let v = eval(inner, vm);
+            match op {
+                UnOp::Neg => v.wrapping_neg(),
+                UnOp::Not => (v == 0) as i32,
+                UnOp::BitNot => !v & 0xFFFF,
+            }
+        }
+        Node::Binary(BinOp::Or, l, r) => (eval(l, vm) != 0 || eval(r, vm) != 0) as i32,
+        Node::Binary(BinOp::And, l, r) => (eval(l, vm) != 0 && eval(r, vm) != 0) as i32,
+        Node::Binary(op, l, r) => {
+            let (l, r) = (eval(l, vm), eval(r, vm));
+            match op {
+                BinOp::Eq => (l == r) as i32,
+                BinOp::Ne => (l != r) as i32,
+                BinOp::Lt => (l < r) as i32,
+                BinOp::Le => (l <= r) as i32,
+                BinOp::Gt => (l > r) as i32,
+                BinOp::Ge => (l >= r) as i32,
+                BinOp::BitOr => l | r,
+                BinOp::BitXor => l ^ r,
+                BinOp::BitAnd => l & r,
+                BinOp::Add => l.wrapping_add(r),
+                BinOp::Sub => l.wrapping_sub(r),
+                BinOp::Or | BinOp::And => unreachable!(),
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Num(i32),
+    Ident(String),
+    Op(&'static str),
+}
+
+// longest operators first so <= is not read as <
+const OPERATORS: [&str; 19] = [
+    "&&", "||", "==", "!=", "<=", ">=", "<", ">", "!", "~", "&", "|", "^", "+", "-", "(", ")", "[",
+    "]",
+];
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+
+    while !rest.is_empty() {
+        if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(**op)) {
+            tokens.push(Token::Op(op));
+            rest = rest[op.len()..].trim_start();
+            continue;
+        }
+
+        let end = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '#'))
+            .unwrap_or(rest.len());
+        if end == 0 {
+            return Err(format!(
+                "unexpected character {:?}",
+                rest.chars().next().unwrap()
+            ));
+        }
+        let word = &rest[..end];
+        tokens.push(match parse_number(word) {
+            Some(n) => Token::Num(n),
+            None if word.starts_with(|c: char| c.is_ascii_digit() || c == '#') => {
+                return Err(format!("bad number {:?}", word))
+            }
+            None => Token::Ident(word.to_string()),
+        });
+        rest = rest[end..].trim_start();
+    }
+    Ok(tokens)
+}
+
+// 65, #65, x41 and 0x41
+fn parse_number(word: &str) -> Option<i32> {
+    let lower = word.to_ascii_lowercase();
+    if let Some(hex) = lower.strip_prefix("0x").or_else(|| lower.strip_prefix('x')) {
+        return i32::from_str_radix(hex, 16)
+            .ok()
+            .filter(|_| !hex.is_empty());
+    }
+    lower.strip_prefix('#').unwrap_or(&lower).parse().ok()
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    symbols: &'a SymbolTable,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn eat(&mut self, op: &str) -> bool {
+        match self.peek() {
+            Some(Token::Op(t)) if *t == op => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), String> {
+        match self.eat(op) {
+            true => Ok(()),
+            false => Err(format!("expected {:?} in condition", op)),
+        }
+    }
+
+    // one precedence level of left associative binary operators
+    fn binary(
+        &mut self,
+        ops: &[(&str, BinOp)],
+        next: fn(&mut Self) -> Result<Node, String>,
+    ) -> Result<Node, String> {
+        let mut left = next(self)?;
+        'outer: loop {
+            for (text, op) in ops {
+                if self.eat(text) {
+                    let right = next(self)?;
+                    left = Node::Binary(*op, Box::new(left), Box::new(right));
+                    continue 'outer;
+                }
+            }
+            return Ok(left);
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Node, String> {
+        self.binary(&[("||", BinOp::Or)], Self::parse_and)
+    }
+
+    fn parse_and(&mut self) -> Result<Node, String> {
+        self.binary(&[("&&", BinOp::And)], Self::parse_cmp)
+    }
+
+    fn parse_cmp(&mut self) -> Result<Node, String> {
+        let ops = [
+            ("==", BinOp::Eq),
+            ("!=", BinOp::Ne),
+            ("<=", BinOp::Le),
+            (">=", BinOp::Ge),
+            ("<", BinOp::Lt),
+            (">", BinOp::Gt),
+        ];
+        self.binary(&ops, Self::parse_bitor)
+    }
+
+    fn parse_bitor(&mut self) -> Result<Node, String> {
+        self.binary(&[("|", BinOp::BitOr)], Self::parse_bitxor)
+    }
+
+    fn parse_bitxor(&mut self) -> Result<Node, String> {
+        self.binary(&[("^", BinOp::BitXor)], Self::parse_bitand)
+    }
+
+    fn parse_bitand(&mut self) -> Result<Node, String> {
+        self.binary(&[("&", BinOp::BitAnd)], Self::parse_sum)
+    }
+
+    fn parse_sum(&mut self) -> Result<Node, String> {
+        self.binary(&[("+", BinOp::Add), ("-", BinOp::Sub)], Self::parse_unary)
+    }
+
+    fn parse_unary(&mut self) -> Result<Node, String> {
+        for (text, op) in [("-", UnOp::Neg), ("!", UnOp::Not), ("~", UnOp::BitNot)] {
+            if self.eat(text) {
+                return Ok(Node::Unary(op, Box::new(self.parse_unary()?)));
+            }
+        }
+        self.parse_primary()
+    }
+
+    fn parse_primary(&mut self) -> Result<Node, String> {
+        let token = self.peek().cloned();
+        self.pos += 1;
+        match token {
+            Some(Token::Num(n)) => Ok(Node::Num(n)),
+            Some(Token::Op("(")) => {
+                let inner = self.parse_or()?;
+                self.expect(")")?;
+                Ok(inner)
+            }
+            Some(Token::Ident(name)) => self.parse_ident(&name),
+            Some(token) => Err(format!("unexpected {:?} in condition", token)),
+            None => Err("condition ends too early".to_string()),
+        }
+    }
+
+    fn parse_ident(&mut self, name: &str) -> Result<Node, String> {
+        let upper = name.to_ascii_uppercase();
+        match upper.as_str() {
+            "PC" => return Ok(Node::Reg(PC_REG)),
+            "PSR" => return Ok(Node::Psr),
+            "COND" | "CC" => return Ok(Node::Reg(COND_REG)),
+            "N" => return Ok(Node::Flag(ConditionFlag::NEG as u16)),
+            "Z" => return Ok(Node::Flag(ConditionFlag::ZERO as u16)),
+            "P" => return Ok(Node::Flag(ConditionFlag::POS as u16)),
+            "MEM" => {
+                self.expect("[")?;
+                let addr = self.parse_or()?;
+                self.expect("]")?;
+                return Ok(Node::Mem(Box::new(addr)));
+            }
+            "SIGNED" | "UNSIGNED" => {
+                self.expect("(")?;
+                let inner = self.parse_or()?;
+                self.expect(")")?;
+                return Ok(match upper.as_str() {
+                    "SIGNED" => Node::Signed(Box::new(inner)),
+                    _ => Node::Binary(BinOp::BitAnd, Box::new(inner), Box::new(Node::Num(0xFFFF))),
+                });
+            }
+            _ => (),
+        }
+
+        if let Some(r) = upper
+            .strip_prefix('R')
+            .and_then(|n| n.parse::<u8>().ok())
+            .filter(|r| *r < 8)
+        {
+            return Ok(Node::Reg(r));
+        }
+        self.symbols
+            .lookup(name)
+            .map(|addr| Node::Num(addr as i32))
+            .ok_or_else(|| format!("unknown name {:?} in condition", name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval_with(text: &str, vm: &VM) -> i32 {
+        Expr::parse(text, &vm.symbols).unwrap().eval(vm)
+    }
+
+    #[test]
+    fn test_eval() {
+        let mut vm = VM::new();
+        vm.symbols.insert("DATA", 0x4000);
+        vm.registers.update_register(0, 0x41);
+        vm.registers.update_register(1, 0xFFFF);
+        vm.registers.update_cond_register(1);
+        vm.memory[0x4000] = 5;
+        vm.memory[0x4001] = 7;
+
+        assert_eq!(eval_with("R0 == x41 && mem[x4000] > 3", &vm), 1);
+        assert_eq!(eval_with("R0 == 41", &vm), 0);
+        assert_eq!(eval_with("R1 < 0", &vm), 0);
+        assert_eq!(eval_with("signed(R1) < 0", &vm), 1);
+        assert_eq!(eval_with("signed(R1) == -1", &vm), 1);
+        assert_eq!(eval_with("N && !Z", &vm), 1);
+        assert_eq!(eval_with("mem[DATA+1] - mem[DATA]", &vm), 2);
+        assert_eq!(eval_with("(R0 & xF0) | 1", &vm), 0x41);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let symbols = SymbolTable::new();
+        assert!(Expr::parse("R0 ==", &symbols).is_err());
+        assert!(Expr::parse("mem[x4000", &symbols).is_err());
+        assert!(Expr::parse("NOSUCHLABEL > 1", &symbols).is_err());
+        assert!(Expr::parse("R0 = 1", &symbols).is_err());
+        assert!(Expr::parse("R0 == 1 2", &symbols).is_err());
+    }
+}
@@ -0,0 +1,430 @@
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+
+use crate::hw::breakpoint::Breakpoint;
+use crate::hw::fault::Fault;
+use crate::hw::register::PC_REG;
+use crate::hw::stop::StopReason;
+use crate::hw::vm::VM;
+use crate::hw::watch::{WatchKind, Watchpoint};
+
+use packet::{Incoming, PacketStream, INTERRUPT};
+
+pub mod packet;
+
+// GDB Remote Serial Protocol stub. Addresses in packets are LC-3 word
+// addresses and every word travels as two big-endian bytes, so PC,
+// breakpoints and memory all agree on what an address means.
+// Register numbers: 0-7 are R0-R7, 8 is PC and 9 is PSR.
+
+const NUM_GDB_REGS: u8 = 10;
+// instructions run between checks for an interrupt from the client
+const SLICE: u64 = 10_000;
+
+pub const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.lc3.core">
+    <reg name="r0" bitsize="16" type="int16" regnum="0"/>
+    <reg name="r1" bitsize="16" type="int16"/>
+    <reg name="r2" bitsize="16" type="int16"/>
+    <reg name="r3" bitsize="16" type="int16"/>
+    <reg name="r4" bitsize="16" type="int16"/>
+    <reg name="r5" bitsize="16" type="data_ptr"/>
+    <reg name="r6" bitsize="16" type="data_ptr"/>
+    <reg name="r7" bitsize="16" type="code_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="psr" bitsize="16" type="int16"/>
+  </feature>
+</target>
+"#;
+
+// A stream the stub can poll for interrupts while the program runs
+pub trait Connection: Read + Write {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+impl Connection for UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+pub struct GdbStub<C: Connection> {
+    pub vm: VM,
+    packets: PacketStream<C>,
+}
+
+impl<C: Connection> GdbStub<C> {
+    pub fn new(vm: VM, conn: C) -> Self {
+        GdbStub {
+            vm,
+            packets: PacketStream::new(conn),
+        }
+    }
+
+    // answers packets until the client detaches, kills or disconnects
+    pub fn serve(&mut self) -> io::Result<()> {
+        while let Some(incoming) = self.packets.receive()? {
+            let packet = match incoming {
+                Incoming::Packet(packet) => packet,
+                // nothing is running, so there is nothing to interrupt
+                Incoming::Interrupt => continue,
+            };
+
+            match packet.as_str() {
+                "k" => return Ok(()),
+                "D" => {
+                    self.packets.send("OK")?;
+                    return Ok(());
+                }
+                "QStartNoAckMode" => {
+                    self.packets.send("OK")?;
+                    self.packets.ack = false;
+                }
+                _ => {
+                    let reply = self.handle(&packet)?;
+                    self.packets.send(&reply)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, packet: &str) -> io::Result<String> {
+        let reply = match packet.as_bytes().first() {
+            Some(b'?') => "S05".to_string(),
+            Some(b'g') => (0..NUM_GDB_REGS)
+                .map(|r| format!("{:04x}", self.read_register(r)))
+                .collect(),
+            Some(b'G') => {
+                let values = parse_words(&packet[1..]);
+                match values {
+                    Some(values) if values.len() == NUM_GDB_REGS as usize => {
+                        for (r, value) in values.into_iter().enumerate() {
+                            self.write_register(r as u8, value);
+                        }
+                        "OK".to_string()
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+            Some(b'p') => match u8::from_str_radix(&packet[1..], 16) {
+                Ok(r) if r < NUM_GDB_REGS => format!("{:04x}", self.read_register(r)),
+                _ => "E01".to_string(),
+            },
+            Some(b'P') => match packet[1..].split_once('=').and_then(|(r, v)| {
+                Some((
+                    u8::from_str_radix(r, 16).ok()?,
+                    parse_words(v)?.first().copied()?,
+                ))
+            }) {
+                Some((r, value)) if r < NUM_GDB_REGS => {
+                    self.write_register(r, value);
+                    "OK".to_string()
+                }
+                _ => "E01".to_string(),
+            },
+            Some(b'm') => match parse_addr_len(&packet[1..]) {
+                Some((addr, len)) => self.read_memory(addr, len),
+                None => "E01".to_string(),
+            },
+            Some(b'M') => self.write_memory(&packet[1..]),
+            Some(b'Z') => self.set_point(&packet[1..], true),
+            Some(b'z') => self.set_point(&packet[1..], false),
+            Some(b'c') => self.resume(&packet[1..], false)?,
+            Some(b's') => self.resume(&packet[1..], true)?,
+            Some(b'H') => "OK".to_string(),
+            Some(b'v') => self.handle_v(packet)?,
+            Some(b'q') => self.handle_query(packet),
+            _ => String::new(),
+        };
+        Ok(reply)
+    }
+
+    fn handle_v(&mut self, packet: &str) -> io::Result<String> {
+        if packet == "vCont?" {
+            return Ok("vCont;c;C;s;S".to_string());
+        }
+        // there is one thread, so the first action is the one that applies
+        if let Some(actions) = packet.strip_prefix("vCont;") {
+            let action = actions.split(';').next().unwrap_or("");
+            return match action.as_bytes().first() {
+                Some(b'c') | Some(b'C') => self.resume("", false),
+                Some(b's') | Some(b'S') => self.resume("", true),
+                _ => Ok("E01".to_string()),
+            };
+        }
+        Ok(String::new())
+    }
+
+    fn handle_query(&self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return "PacketSize=1000;qXfer:features:read+;swbreak+;hwbreak+;\
+                    QStartNoAckMode+;vContSupported+"
+                .to_string();
+        }
+        if let Some(rest) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            return match parse_addr_len(rest) {
+                Some((offset, len)) => xfer_chunk(TARGET_XML, offset as usize, len),
+                None => "E01".to_string(),
+            };
+        }
+        match packet {
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    fn read_register(&self, r: u8) -> u16 {
+        match r {
+            9 => self.vm.psr(),
+            _ => self.vm.registers.get_val(r),
+        }
+    }
+
+    fn write_register(&mut self, r: u8, value: u16) {
+        match r {
+            9 => self.vm.set_psr(value),
+            _ => self.vm.registers.update_register(r, value),
+        }
+    }
+
+    // len counts bytes, two per word
+    fn read_memory(&self, addr: u16, len: usize) -> String {
+        let words = len.div_ceil(2);
+        let mut hex: String = (0..words)
+            .map(|i| {
+                format!(
+                    "{:04x}",
+                    self.vm.memory[addr.wrapping_add(i as u16) as usize]
+                )
+            })
+            .collect();
+        hex.truncate(2 * len);
+        hex
+    }
+
+    fn write_memory(&mut self, args: &str) -> String {
+        let Some((range, data)) = args.split_once(':') else {
+            return "E01".to_string();
+        };
+        match (parse_addr_len(range), parse_words(data)) {
+            (Some((addr, len)), Some(words)) if len == 2 * words.len() => {
+                for (i, word) in words.into_iter().enumerate() {
+                    self.vm
+                        .write_memory(addr.wrapping_add(i as u16) as usize, word);
+                }
+                "OK".to_string()
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    // Z/z TYPE,ADDR,KIND: 0 and 1 are breakpoints, 2, 3 and 4 are write,
+    // read and access watchpoints over KIND bytes
+    fn set_point(&mut self, args: &str, insert: bool) -> String {
+        let fields: Vec<&str> = args.split(',').collect();
+        let [kind, addr, len] = fields.as_slice() else {
+            return "E01".to_string();
+        };
+        let (Ok(addr), Ok(len)) = (
+            u16::from_str_radix(addr, 16),
+            usize::from_str_radix(len, 16),
+        ) else {
+            return "E01".to_string();
+        };
+
+        let watch_kind = match *kind {
+            "0" | "1" => {
+                if insert {
+                    self.vm.breakpoints.insert(addr, Breakpoint::default());
+                } else {
+                    self.vm.breakpoints.remove(&addr);
+                }
+                return "OK".to_string();
+            }
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return String::new(),
+        };
+
+        let watchpoint = Watchpoint {
+            start: addr,
+            end: addr.wrapping_add((len.div_ceil(2).max(1) - 1) as u16),
+            kind: watch_kind,
+            condition: None,
+        };
+        if insert {
+            self.vm.watchpoints.push(watchpoint);
+        } else {
+            self.vm.watchpoints.retain(|w| *w != watchpoint);
+        }
+        "OK".to_string()
+    }
+
+    // c [ADDR] and s [ADDR], runs in slices so a ^C from the client can
+    // interrupt a program that never stops by itself
+    fn resume(&mut self, addr: &str, single_step: bool) -> io::Result<String> {
+        if let Ok(addr) = u16::from_str_radix(addr, 16) {
+            self.vm.registers.update_register(PC_REG, addr);
+        }
+
+        let mut stop = self.vm.run(Some(if single_step { 1 } else { SLICE }));
+        while !single_step && stop == StopReason::StepLimit {
+            if self.interrupted()? {
+                return Ok("S02".to_string());
+            }
+            stop = self.vm.run_slice(Some(SLICE));
+        }
+        Ok(self.stop_reply(stop))
+    }
+
+    fn interrupted(&mut self) -> io::Result<bool> {
+        // the interrupt may come after the start of a packet already read
+        if self.packets.take_unread(INTERRUPT) {
+            return Ok(true);
+        }
+        let stream = &mut self.packets.stream;
+        stream.set_nonblocking(true)?;
+        let mut buf = [0u8; 1];
+        let result = stream.read(&mut buf);
+        stream.set_nonblocking(false)?;
+        match result {
+            Ok(1) if buf[0] == INTERRUPT => Ok(true),
+            // the start of a packet sent while the program runs
+            Ok(1) => {
+                self.packets.unread(buf[0]);
+                Ok(false)
+            }
+            Ok(_) => Ok(false),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn stop_reply(&self, stop: StopReason) -> String {
+        match stop {
+            StopReason::Halted => "W00".to_string(),
+            // SIGILL for opcodes and traps the machine cannot perform
+            StopReason::Fault(Fault::IllegalOpcode { .. })
+            | StopReason::Fault(Fault::PrivilegeViolation { .. })
+            | StopReason::Fault(Fault::UnknownTrap { .. }) => "S04".to_string(),
+            // SIGSEGV for memory the program may not use or never loaded
+            StopReason::Fault(Fault::AccessViolation { .. })
+            | StopReason::Fault(Fault::RunawayPc { .. }) => "S0B".to_string(),
+            StopReason::Breakpoint(_) => "T05swbreak:;".to_string(),
+            StopReason::Watchpoint(hit) => {
+                let kind = match self.vm.watchpoints.get(hit.index).map(|w| w.kind) {
+                    Some(WatchKind::Read) => "rwatch",
+                    Some(WatchKind::Access) => "awatch",
+                    _ => "watch",
+                };
+                format!("T05{}:{:x};", kind, hit.addr)
+            }
+            StopReason::StepLimit
+            | StopReason::HistoryStart
+            | StopReason::Loop(_)
+            | StopReason::EndOfInput(_) => "S05".to_string(),
+        }
+    }
+}
+
+// ADDR,LEN in hex
+fn parse_addr_len(text: &str) -> Option<(u16, usize)> {
+    let (addr, len) = text.split_once(',')?;
+    Some((
+        u16::from_str_radix(addr, 16).ok()?,
+        usize::from_str_radix(len, 16).ok()?,
+    ))
+}
+
+// big-endian words, four hex digits each
+fn parse_words(hex: &str) -> Option<Vec<u16>> {
+    if !hex.len().is_multiple_of(4) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(4)
+        .map(|i| u16::from_str_radix(hex.get(i..i + 4)?, 16).ok())
+        .collect()
+}
+
+// qXfer replies: m while more data follows, l for the last chunk
+fn xfer_chunk(data: &str, offset: usize, len: usize) -> String {
+    let data = data.as_bytes();
+    let start = offset.min(data.len());
+    let end = (start + len).min(data.len());
+    let marker = if end == data.len() { 'l' } else { 'm' };
+    format!("{}{}", marker, String::from_utf8_lossy(&data[start..end]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hw::register::PC_START;
+    use crate::image::Image;
+    use std::net::TcpListener;
+    use std::thread;
+
+    // scripted client: sends each packet, acknowledges and collects replies
+    fn exchange(client: &mut TcpStream, packet: &str) -> String {
+        client.write_all(&packet::frame(packet)).unwrap();
+        let mut client = PacketStream::new(client.try_clone().unwrap());
+        let mut ack = [0u8; 1];
+        client.stream.read_exact(&mut ack).unwrap();
+        assert_eq!(ack[0], b'+');
+        match client.receive().unwrap() {
+            Some(Incoming::Packet(reply)) => reply,
+            other => panic!("expected a reply, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (conn, _) = listener.accept().unwrap();
+            let mut vm = VM::new();
+            // LOOP ADD R0 R0 1; STR R0 R1 #0; BRnzp LOOP
+            vm.load_image(&Image::new(PC_START, vec![0x1021, 0x7040, 0x0FFD]));
+            vm.registers.update_register(1, 0x4000);
+            GdbStub::new(vm, conn).serve().unwrap();
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        assert!(exchange(&mut client, "qSupported:swbreak+").contains("qXfer:features:read+"));
+        assert!(
+            exchange(&mut client, "qXfer:features:read:target.xml:0,1000").starts_with("l<?xml")
+        );
+        assert_eq!(exchange(&mut client, "p8"), "3000");
+        assert_eq!(exchange(&mut client, "m3000,4"), "10217040");
+
+        assert_eq!(exchange(&mut client, "Z0,3002,2"), "OK");
+        assert_eq!(exchange(&mut client, "c"), "T05swbreak:;");
+        assert_eq!(exchange(&mut client, "p8"), "3002");
+        assert_eq!(exchange(&mut client, "z0,3002,2"), "OK");
+
+        assert_eq!(exchange(&mut client, "Z2,4000,2"), "OK");
+        assert_eq!(exchange(&mut client, "c"), "T05watch:4000;");
+        assert_eq!(exchange(&mut client, "m4000,2"), "0002");
+        assert_eq!(exchange(&mut client, "s"), "S05");
+        assert_eq!(exchange(&mut client, "P0=0041"), "OK");
+        assert_eq!(&exchange(&mut client, "g")[..4], "0041");
+
+        client.write_all(&packet::frame("k")).unwrap();
+        server.join().unwrap();
+    }
+}
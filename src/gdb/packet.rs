@@ -0,0 +1,218 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+
+// RSP framing: $<data>#<two hex digit checksum>, acknowledged with + or -.
+// A lone 0x03 byte outside a packet is an interrupt request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Incoming {
+    Packet(String),
+    Interrupt,
+}
+
+pub const INTERRUPT: u8 = 0x03;
+
+pub fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |acc, b| acc.wrapping_add(*b))
+}
+
+// escapes the bytes RSP reserves inside packet data
+pub fn frame(data: &str) -> Vec<u8> {
+    let mut body = Vec::with_capacity(data.len());
+    for b in data.bytes() {
+        match b {
+            b'#' | b'$' | b'}' | b'*' => body.extend_from_slice(&[b'}', b ^ 0x20]),
+            _ => body.push(b),
+        }
+    }
+
+    let mut out = Vec::with_capacity(body.len() + 4);
+    out.push(b'$');
+    out.extend_from_slice(&body);
+    out.extend_from_slice(format!("#{:02x}", checksum(&body)).as_bytes());
+    out
+}
+
+fn unescape(body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(body.len());
+    let mut iter = body.iter();
+    while let Some(b) = iter.next() {
+        match b {
+            b'}' => {
+                if let Some(next) = iter.next() {
+                    out.push(next ^ 0x20);
+                }
+            }
+            _ => out.push(*b),
+        }
+    }
+    out
+}
+
+// Packet level connection, acknowledgements are sent and expected until the
+// client switches them off with QStartNoAckMode
+pub struct PacketStream<S: Read + Write> {
+    pub stream: S,
+    pub ack: bool,
+    // bytes read while looking for something else, read again first
+    unread: VecDeque<u8>,
+}
+
+impl<S: Read + Write> PacketStream<S> {
+    pub fn new(stream: S) -> Self {
+        PacketStream {
+            stream,
+            ack: true,
+            unread: VecDeque::new(),
+        }
+    }
+
+    // puts back a byte read from the stream that belongs to what comes next
+    pub fn unread(&mut self, byte: u8) {
+        self.unread.push_back(byte);
+    }
+
+    // removes the first put back byte equal to byte, if there is one
+    pub fn take_unread(&mut self, byte: u8) -> bool {
+        match self.unread.iter().position(|b| *b == byte) {
+            Some(i) => self.unread.remove(i).is_some(),
+            None => false,
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        if let Some(byte) = self.unread.pop_front() {
+            return Ok(Some(byte));
+        }
+        let mut buf = [0u8; 1];
+        match self.stream.read(&mut buf)? {
+            0 => Ok(None),
+            _ => Ok(Some(buf[0])),
+        }
+    }
+
+    // next packet or interrupt, None once the client has disconnected
+    pub fn receive(&mut self) -> io::Result<Option<Incoming>> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(INTERRUPT) => return Ok(Some(Incoming::Interrupt)),
+                Some(b'$') => (),
+                // stray acknowledgements and line noise between packets
+                Some(_) => continue,
+            }
+
+            let mut body = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(b) => body.push(b),
+                }
+            }
+            let mut sum = [0u8; 2];
+            self.stream.read_exact(&mut sum)?;
+            let expected = std::str::from_utf8(&sum)
+                .ok()
+                .and_then(|s| u8::from_str_radix(s, 16).ok());
+
+            if expected != Some(checksum(&body)) {
+                if self.ack {
+                    self.stream.write_all(b"-")?;
+                }
+                continue;
+            }
+            if self.ack {
+                self.stream.write_all(b"+")?;
+            }
+            let data = String::from_utf8_lossy(&unescape(&body)).into_owned();
+            return Ok(Some(Incoming::Packet(data)));
+        }
+    }
+
+    pub fn send(&mut self, data: &str) -> io::Result<()> {
+        let framed = frame(data);
+        loop {
+            self.stream.write_all(&framed)?;
+            self.stream.flush()?;
+            if !self.ack {
+                return Ok(());
+            }
+            // resend on -, give up quietly if the client went away. Anything
+            // but an acknowledgement is the start of what the client sends next
+            match self.read_byte()? {
+                Some(b'-') => continue,
+                Some(b'+') | None => return Ok(()),
+                Some(byte) => {
+                    self.unread(byte);
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame() {
+        assert_eq!(frame("OK"), b"$OK#9a".to_vec());
+        assert_eq!(frame("a#b"), b"$a}\x03b#43".to_vec());
+        assert_eq!(unescape(b"a}\x03b"), b"a#b".to_vec());
+    }
+
+    // a client whose replies are all sent up front
+    struct Client {
+        input: io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Client {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Client {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_unacknowledged_packet() {
+        // the client skips the + and sends its next packet straight away
+        let mut packets = PacketStream::new(Client {
+            input: io::Cursor::new(b"$g#67".to_vec()),
+            output: Vec::new(),
+        });
+        packets.send("OK").unwrap();
+        assert_eq!(
+            packets.receive().unwrap(),
+            Some(Incoming::Packet("g".to_string()))
+        );
+        assert_eq!(packets.stream.output, b"$OK#9a+".to_vec());
+    }
+
+    #[test]
+    fn test_interrupt_after_unread_bytes() {
+        let mut packets = PacketStream::new(Client {
+            input: io::Cursor::new(b"#67".to_vec()),
+            output: Vec::new(),
+        });
+        packets.unread(b'$');
+        packets.unread(b'g');
+        packets.unread(INTERRUPT);
+        assert!(packets.take_unread(INTERRUPT));
+        assert!(!packets.take_unread(INTERRUPT));
+        assert_eq!(
+            packets.receive().unwrap(),
+            Some(Incoming::Packet("g".to_string()))
+        );
+    }
+}
@@ -0,0 +1,502 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use serde_json::{json, Value};
+
+use crate::asm::Assembly;
+use crate::hw::loops::LoopDetector;
+use crate::hw::register::PC_REG;
+use crate::hw::stop::StopReason;
+use crate::hw::vm::VM;
+use crate::image::{invalid_data, Image};
+use crate::symbols::SymbolTable;
+
+// Batch grading: every submission image runs every test case of a spec in
+// its own VM, spread over worker threads
+//
+// Spec file, JSON:
+// { "limit": 1000000,
+//   "tests": [ { "name": "adds two digits",
+//                "input": "12",
+//                "registers": { "R1": 5 },
+//                "memory": { "NUM": "x0007", "x4000": 3 },
+//                "limit": 10000,
+//                "expect": { "output": "3\n",
+//                            "registers": { "R0": 3 },
+//                            "memory": { "RESULT": 3 } } } ] }
+// Addresses are labels of the submission or numbers, values are JSON
+// numbers or strings like "x41" and "#-1". Everything but the name is
+// optional
+
+// instructions a test may execute when the spec does not say
+pub const DEFAULT_LIMIT: u64 = 1_000_000;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TestCase {
+    pub name: String,
+    pub input: Vec<u8>,
+    pub registers: Vec<(u8, u16)>,
+    // addresses stay text until a submission's labels can resolve them
+    pub memory: Vec<(String, u16)>,
+    pub limit: u64,
+    pub output: Option<String>,
+    pub expect_registers: Vec<(u8, u16)>,
+    pub expect_memory: Vec<(String, u16)>,
+}
+
+pub fn parse_spec(text: &str) -> Result<Vec<TestCase>, String> {
+    let spec: Value = serde_json::from_str(text).map_err(|e| e.to_string())?;
+    let limit = match &spec["limit"] {
+        Value::Null => DEFAULT_LIMIT,
+        limit => limit.as_u64().ok_or("limit must be a number")?,
+    };
+    let tests = spec["tests"].as_array().ok_or("expected a tests array")?;
+    tests
+        .iter()
+        .enumerate()
+        .map(|(i, test)| parse_test(test, limit).map_err(|e| format!("test {}: {}", i + 1, e)))
+        .collect()
+}
+
+pub(crate) fn parse_test(test: &Value, default_limit: u64) -> Result<TestCase, String> {
+    let expect = &test["expect"];
+    Ok(TestCase {
+        name: test["name"]
+            .as_str()
+            .ok_or("every test needs a name")?
+            .to_string(),
+        input: test["input"]
+            .as_str()
+            .unwrap_or_default()
+            .as_bytes()
+            .to_vec(),
+        registers: parse_registers(&test["registers"])?,
+        memory: parse_memory(&test["memory"])?,
+        limit: match &test["limit"] {
+            Value::Null => default_limit,
+            limit => limit.as_u64().ok_or("limit must be a number")?,
+        },
+        output: expect["output"].as_str().map(str::to_string),
+        expect_registers: parse_registers(&expect["registers"])?,
+        expect_memory: parse_memory(&expect["memory"])?,
+    })
+}
+
+fn parse_value(value: &Value) -> Result<u16, String> {
+    let number = match value {
+        Value::Number(n) => n.as_i64(),
+        Value::String(s) => {
+            let s = s.trim();
+            if let Some(hex) = s.strip_prefix(['x', 'X']) {
+                i64::from_str_radix(hex, 16).ok()
+            } else {
+                s.strip_prefix('#').unwrap_or(s).parse().ok()
+            }
+        }
+        _ => None,
+    };
+    number
+        .filter(|n| (-0x8000..=0xFFFF).contains(n))
+        .map(|n| n as u16)
+        .ok_or_else(|| format!("bad value {}", value))
+}
+
+pub(crate) fn parse_registers(registers: &Value) -> Result<Vec<(u8, u16)>, String> {
+    let Some(registers) = registers.as_object() else {
+        return Ok(Vec::new());
+    };
+    registers
+        .iter()
+        .map(|(name, value)| {
+            let r = match name.to_ascii_uppercase().as_str() {
+                "PC" => PC_REG,
+                r => r
+                    .strip_prefix('R')
+                    .and_then(|n| n.parse().ok())
+                    .filter(|n| *n < 8)
+                    .ok_or_else(|| format!("unknown register {:?}", name))?,
+            };
+            Ok((r, parse_value(value)?))
+        })
+        .collect()
+}
+
+fn parse_memory(memory: &Value) -> Result<Vec<(String, u16)>, String> {
+    let Some(memory) = memory.as_object() else {
+        return Ok(Vec::new());
+    };
+    memory
+        .iter()
+        .map(|(addr, value)| Ok((addr.clone(), parse_value(value)?)))
+        .collect()
+}
+
+// One program to grade, named after its file
+pub struct Submission {
+    pub name: String,
+    pub path: PathBuf,
+    // why the program could not be loaded, every test fails with it
+    pub program: Result<(Image, SymbolTable), String>,
+}
+
+const IMAGE_EXTENSIONS: [&str; 6] = ["obj", "hex", "bin", "ihex", "ihx", "asm"];
+
+impl Submission {
+    pub fn load(path: &Path) -> Self {
+        let name = path
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let program = if path.extension().is_some_and(|e| e == "asm") {
+            Assembly::load(path).map(|a| (a.image, a.symbols))
+        } else {
+            Image::load(path)
+                .map(|image| (image, SymbolTable::load_beside(path).unwrap_or_default()))
+        };
+        Submission {
+            name,
+            path: path.to_path_buf(),
+            program: program.map_err(|e| e.to_string()),
+        }
+    }
+
+    // every image in a directory, in name order
+    pub fn load_dir(dir: &Path) -> io::Result<Vec<Self>> {
+        let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<io::Result<_>>()?;
+        paths.retain(|p| {
+            p.is_file()
+                && p.extension()
+                    .and_then(|e| e.to_str())
+                    .is_some_and(|e| IMAGE_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()))
+        });
+        paths.sort();
+        if paths.is_empty() {
+            return Err(invalid_data(format!("no images in {}", dir.display())));
+        }
+        Ok(paths.iter().map(|p| Submission::load(p)).collect())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestResult {
+    pub test: String,
+    // empty when the test passed
+    pub reasons: Vec<String>,
+    pub steps: u64,
+}
+
+impl TestResult {
+    pub fn passed(&self) -> bool {
+        self.reasons.is_empty()
+    }
+}
+
+pub fn run_test(image: &Image, symbols: &SymbolTable, test: &TestCase) -> TestResult {
+    let mut result = TestResult {
+        test: test.name.clone(),
+        reasons: Vec::new(),
+        steps: 0,
+    };
+    let mut vm = match prepare(image, symbols, test) {
+        Ok(vm) => vm,
+        Err(reasons) => {
+            result.reasons = reasons;
+            return result;
+        }
+    };
+
+    let stop = vm.run(Some(test.limit));
+    result.steps = vm.steps;
+    match stop {
+        StopReason::Halted => result.reasons = check(&vm, test),
+        StopReason::StepLimit => result.reasons.push(format!(
+            "timeout: no HALT within {} instructions",
+            test.limit
+        )),
+        stop => result.reasons.push(stop.describe(symbols)),
+    }
+    result
+}
+
+// a fresh VM with the image loaded, the test's input queued and its
+// registers and memory set up
+pub(crate) fn prepare(
+    image: &Image,
+    symbols: &SymbolTable,
+    test: &TestCase,
+) -> Result<VM, Vec<String>> {
+    let mut vm = VM::with_program(image, symbols.clone());
+    vm.input = Some(test.input.clone().into());
+    vm.output = Some(Vec::new());
+    // a program stuck in a loop fails there and then, not at the limit
+    vm.loop_detector = Some(LoopDetector::default());
+    for (r, value) in &test.registers {
+        vm.registers.update_register(*r, *value);
+    }
+    let mut reasons = Vec::new();
+    for (addr, value) in &test.memory {
+        match resolve(&vm.symbols, addr) {
+            Ok(addr) => vm.write_memory(addr as usize, *value),
+            Err(e) => reasons.push(e),
+        }
+    }
+    match reasons.is_empty() {
+        true => Ok(vm),
+        false => Err(reasons),
+    }
+}
+
+fn resolve(symbols: &SymbolTable, addr: &str) -> Result<u16, String> {
+    symbols
+        .resolve(addr)
+        .ok_or_else(|| format!("unknown address {:?}", addr))
+}
+
+pub(crate) fn register_name(r: u8) -> String {
+    match r {
+        PC_REG => "PC".to_string(),
+        r => format!("R{}", r),
+    }
+}
+
+// how the finished VM differs from what the test expects
+pub(crate) fn check(vm: &VM, test: &TestCase) -> Vec<String> {
+    let mut reasons = Vec::new();
+    let output = String::from_utf8_lossy(vm.output.as_deref().unwrap_or_default()).to_string();
+    if let Some(expected) = test.output.as_ref().filter(|e| **e != output) {
+        reasons.push(format!(
+            "wrong output: expected {:?}, got {:?}",
+            expected, output
+        ));
+    }
+    for (r, expected) in &test.expect_registers {
+        let actual = vm.registers.get_val(*r);
+        if actual != *expected {
+            reasons.push(format!(
+                "wrong {}: expected x{:04X}, got x{:04X}",
+                register_name(*r),
+                expected,
+                actual
+            ));
+        }
+    }
+    for (addr, expected) in &test.expect_memory {
+        let actual = match resolve(&vm.symbols, addr) {
+            Ok(at) => vm.memory[at as usize],
+            Err(e) => {
+                reasons.push(e);
+                continue;
+            }
+        };
+        if actual != *expected {
+            reasons.push(format!(
+                "wrong memory at {}: expected x{:04X}, got x{:04X}",
+                addr, expected, actual
+            ));
+        }
+    }
+    reasons
+}
+
+pub struct Report {
+    pub submission: String,
+    pub path: PathBuf,
+    pub results: Vec<TestResult>,
+}
+
+impl Report {
+    pub fn passed(&self) -> usize {
+        self.results.iter().filter(|r| r.passed()).count()
+    }
+}
+
+// runs every test of every submission on up to jobs threads, the reports
+// come back in submission order with results in test order
+pub fn grade(submissions: &[Submission], tests: &[TestCase], jobs: usize) -> Vec<Report> {
+    let total = submissions.len() * tests.len();
+    let next = AtomicUsize::new(0);
+    let worker = || {
+        let mut done = Vec::new();
+        loop {
+            let job = next.fetch_add(1, Ordering::Relaxed);
+            if job >= total {
+                return done;
+            }
+            let (submission, test) = (&submissions[job / tests.len()], &tests[job % tests.len()]);
+            let result = match &submission.program {
+                Ok((image, symbols)) => run_test(image, symbols, test),
+                Err(e) => TestResult {
+                    test: test.name.clone(),
+                    reasons: vec![format!("unable to load: {}", e)],
+                    steps: 0,
+                },
+            };
+            done.push((job, result));
+        }
+    };
+    let mut results: Vec<(usize, TestResult)> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..jobs.clamp(1, total.max(1)))
+            .map(|_| scope.spawn(worker))
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|w| w.join().expect("grading thread panicked"))
+            .collect()
+    });
+    results.sort_by_key(|(job, _)| *job);
+
+    let mut results = results.into_iter().map(|(_, result)| result);
+    submissions
+        .iter()
+        .map(|s| Report {
+            submission: s.name.clone(),
+            path: s.path.clone(),
+            results: results.by_ref().take(tests.len()).collect(),
+        })
+        .collect()
+}
+
+pub fn to_json(reports: &[Report]) -> Value {
+    let reports: Vec<Value> = reports
+        .iter()
+        .map(|report| {
+            let tests: Vec<Value> = report
+                .results
+                .iter()
+                .map(|r| {
+                    json!({
+                        "name": r.test,
+                        "passed": r.passed(),
+                        "steps": r.steps,
+                        "reasons": r.reasons,
+                    })
+                })
+                .collect();
+            json!({
+                "submission": report.submission,
+                "path": report.path.display().to_string(),
+                "passed": report.passed(),
+                "total": report.results.len(),
+                "tests": tests,
+            })
+        })
+        .collect();
+    json!({ "submissions": reports })
+}
+
+// one row per submission and test
+pub fn to_csv(reports: &[Report]) -> String {
+    let quote = |field: &str| match field.contains([',', '"', '\n']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_string(),
+    };
+    let mut out = String::from("submission,test,result,steps,reasons\n");
+    for report in reports {
+        for r in &report.results {
+            out.push_str(&format!(
+                "{},{},{},{},{}\n",
+                quote(&report.submission),
+                quote(&r.test),
+                if r.passed() { "pass" } else { "fail" },
+                r.steps,
+                quote(&r.reasons.join("; "))
+            ));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    // reads a digit and prints the next one
+    const GOOD: &str = ".ORIG x3000
+        GETC
+        ADD R0, R0, #1
+        OUT
+        ST R0, LAST
+        HALT
+LAST    .FILL 0
+        .END";
+
+    const SPEC: &str = r##"{
+        "limit": 100,
+        "tests": [
+            { "name": "next digit", "input": "4",
+              "expect": { "output": "5", "registers": { "R0": "x35" },
+                          "memory": { "LAST": 53 } } },
+            { "name": "preset", "input": "a", "registers": { "R1": "#-1" },
+              "expect": { "output": "b", "registers": { "R1": 65535 } } }
+        ]
+    }"##;
+
+    fn submission(name: &str, source: &str) -> Submission {
+        let assembly = assemble(source).map_err(|e| e.to_string());
+        Submission {
+            name: name.to_string(),
+            path: PathBuf::from(format!("{}.asm", name)),
+            program: assembly.map(|a| (a.image, a.symbols)),
+        }
+    }
+
+    #[test]
+    fn test_parse_spec() {
+        let tests = parse_spec(SPEC).unwrap();
+        assert_eq!(tests.len(), 2);
+        assert_eq!(tests[0].expect_registers, vec![(0, 0x35)]);
+        assert_eq!(tests[0].expect_memory, vec![("LAST".to_string(), 53)]);
+        assert_eq!(tests[1].registers, vec![(1, 0xFFFF)]);
+        assert_eq!(tests[1].limit, 100);
+        assert_eq!(
+            parse_spec(r#"{ "tests": [ { "registers": { "R9": 1 } } ] }"#),
+            Err("test 1: every test needs a name".to_string())
+        );
+    }
+
+    #[test]
+    fn test_grade() {
+        let submissions = vec![
+            submission("good", GOOD),
+            // prints the digit itself
+            submission("wrong", &GOOD.replace("ADD R0, R0, #1", "ADD R0, R0, #0")),
+            submission("loops", ".ORIG x3000\nLOOP BR LOOP\n.END"),
+            submission("broken", "ADD"),
+        ];
+        let tests = parse_spec(SPEC).unwrap();
+        let reports = grade(&submissions, &tests, 3);
+
+        assert_eq!(
+            reports.iter().map(Report::passed).collect::<Vec<_>>(),
+            vec![2, 0, 0, 0]
+        );
+        assert_eq!(
+            reports[1].results[0].reasons,
+            vec![
+                "wrong output: expected \"5\", got \"4\"",
+                "wrong R0: expected x0035, got x0034",
+                "wrong memory at LAST: expected x0035, got x0034",
+            ]
+        );
+        assert_eq!(
+            reports[2].results[1].reasons,
+            vec!["infinite loop: branch to itself at LOOP (x3000)"]
+        );
+        assert!(reports[3].results[0].reasons[0].starts_with("unable to load: "));
+
+        let json = to_json(&reports);
+        assert_eq!(json["submissions"][0]["passed"], 2);
+        assert_eq!(json["submissions"][2]["tests"][0]["steps"], 2);
+        let csv = to_csv(&reports);
+        assert!(csv.contains("\ngood,next digit,pass,5,\n"));
+        assert!(csv.contains(
+            "\nwrong,preset,fail,5,\"wrong output: expected \"\"b\"\", got \"\"a\"\"\"\n"
+        ));
+    }
+}
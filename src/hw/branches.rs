@@ -0,0 +1,59 @@
+use std::collections::BTreeMap;
+
+use super::instruction::OpCode;
+use super::observer::Observer;
+use super::register::PC_REG;
+
+// Which way each BR went, for observers that report on branches to pass
+// their fetches and register writes on to. A BR counts as not taken when
+// it is fetched and moves over to taken if it writes PC anywhere but past
+// itself
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BranchCounter {
+    // (taken, not taken) per BR address
+    pub counts: BTreeMap<u16, (u64, u64)>,
+    // the BR being executed, until its PC write shows it was taken
+    current: Option<u16>,
+}
+
+impl Observer for BranchCounter {
+    fn on_fetch(&mut self, pc: u16, instruction: u16) {
+        self.current = None;
+        if instruction >> 12 == OpCode::OpBr as u16 {
+            self.counts.entry(pc).or_default().1 += 1;
+            self.current = Some(pc);
+        }
+    }
+
+    fn on_register_write(&mut self, register: u8, old: u16, new: u16) {
+        let Some(pc) = self.current else {
+            return;
+        };
+        // the write moving PC past the branch is not the jump
+        if register == PC_REG && !(old == pc && new == pc.wrapping_add(1)) {
+            let branch = self.counts.get_mut(&pc).unwrap();
+            branch.0 += 1;
+            branch.1 -= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_branch_counter() {
+        let mut counter = BranchCounter::default();
+        // BRz +2 at x3000 taken, then not taken
+        counter.on_fetch(0x3000, 0x0402);
+        counter.on_register_write(PC_REG, 0x3000, 0x3001);
+        counter.on_register_write(PC_REG, 0x3001, 0x3003);
+        counter.on_fetch(0x3000, 0x0402);
+        counter.on_register_write(PC_REG, 0x3000, 0x3001);
+        // ADD R0 R0 1 is no branch
+        counter.on_fetch(0x3001, 0x1021);
+        counter.on_register_write(PC_REG, 0x3001, 0x3002);
+        assert_eq!(counter.counts, BTreeMap::from([(0x3000, (1, 1))]));
+    }
+}
@@ -0,0 +1,19 @@
+use crate::expr::Expr;
+
+// A breakpoint only stops once its condition holds and its ignore count
+// has run out, hits counts every time both of those were checked
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub condition: Option<Expr>,
+    pub ignore: u32,
+    pub hits: u32,
+}
+
+impl Breakpoint {
+    pub fn conditional(condition: Option<Expr>) -> Self {
+        Breakpoint {
+            condition,
+            ..Breakpoint::default()
+        }
+    }
+}
@@ -0,0 +1,341 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::image::invalid_data;
+use crate::symbols::SymbolTable;
+
+use super::fault::Fault;
+use super::instruction::OpCode;
+use super::observer::Observer;
+use super::register::PC_REG;
+use super::vm::VM;
+
+// Core file layout, big-endian:
+//   "LC3C", version u16
+//   fault: kind u8 (1 illegal opcode, 2 privilege, 3 unknown trap, 4 access
+//   violation, 5 runaway PC), pc u16, instruction, trap vector or address u16
+//   recent instructions: count u32, then (pc u16, instruction u16) oldest first
+//   symbols: count u32, then name length u8, name, address u16
+//   the machine state as written by VM::save_state
+pub const MAGIC: &[u8; 4] = b"LC3C";
+pub const VERSION: u16 = 1;
+
+// instructions kept for a core file unless told otherwise
+pub const DEFAULT_RECENT: usize = 32;
+
+// Remembers the last few instructions fetched, for the core file
+#[derive(Debug, Clone, Default)]
+pub struct RecentInstructions {
+    limit: usize,
+    pub entries: VecDeque<(u16, u16)>,
+}
+
+impl RecentInstructions {
+    pub fn new(limit: usize) -> Self {
+        RecentInstructions {
+            limit,
+            entries: VecDeque::with_capacity(limit),
+        }
+    }
+}
+
+impl Observer for RecentInstructions {
+    fn on_fetch(&mut self, pc: u16, instruction: u16) {
+        if self.entries.len() == self.limit {
+            self.entries.pop_front();
+        }
+        if self.limit > 0 {
+            self.entries.push_back((pc, instruction));
+        }
+    }
+}
+
+// A faulted machine as read back from a core file
+pub struct CoreDump {
+    pub vm: VM,
+    pub fault: Fault,
+    pub recent: Vec<(u16, u16)>,
+}
+
+pub fn write_core(
+    vm: &VM,
+    fault: &Fault,
+    recent: &[(u16, u16)],
+    out: &mut impl Write,
+) -> io::Result<()> {
+    out.write_all(MAGIC)?;
+    out.write_u16::<BigEndian>(VERSION)?;
+    let (kind, detail) = match *fault {
+        Fault::IllegalOpcode { instruction, .. } => (1, instruction),
+        Fault::PrivilegeViolation { .. } => (2, 0),
+        Fault::UnknownTrap { vector, .. } => (3, vector as u16),
+        Fault::AccessViolation { addr, .. } => (4, addr),
+        Fault::RunawayPc { .. } => (5, 0),
+    };
+    out.write_u8(kind)?;
+    out.write_u16::<BigEndian>(fault.pc())?;
+    out.write_u16::<BigEndian>(detail)?;
+
+    out.write_u32::<BigEndian>(recent.len() as u32)?;
+    for (pc, instruction) in recent {
+        out.write_u16::<BigEndian>(*pc)?;
+        out.write_u16::<BigEndian>(*instruction)?;
+    }
+
+    let symbols: Vec<(u16, &str)> = vm.symbols.iter().filter(|(_, n)| n.len() < 256).collect();
+    out.write_u32::<BigEndian>(symbols.len() as u32)?;
+    for (addr, name) in symbols {
+        out.write_u8(name.len() as u8)?;
+        out.write_all(name.as_bytes())?;
+        out.write_u16::<BigEndian>(addr)?;
+    }
+    vm.save_state(out)
+}
+
+pub fn read_core(input: &mut impl Read) -> io::Result<CoreDump> {
+    let truncated = |_| invalid_data("core file is truncated");
+    let mut magic = [0; 4];
+    input.read_exact(&mut magic).map_err(truncated)?;
+    if &magic != MAGIC {
+        return Err(invalid_data("not a core file"));
+    }
+    let version = input.read_u16::<BigEndian>().map_err(truncated)?;
+    if version != VERSION {
+        return Err(invalid_data(format!(
+            "core file version {} is not supported",
+            version
+        )));
+    }
+
+    let kind = input.read_u8().map_err(truncated)?;
+    let pc = input.read_u16::<BigEndian>().map_err(truncated)?;
+    let detail = input.read_u16::<BigEndian>().map_err(truncated)?;
+    let fault = match kind {
+        1 => Fault::IllegalOpcode {
+            pc,
+            instruction: detail,
+        },
+        2 => Fault::PrivilegeViolation { pc },
+        3 => Fault::UnknownTrap {
+            pc,
+            vector: detail as u8,
+        },
+        4 => Fault::AccessViolation { pc, addr: detail },
+        5 => Fault::RunawayPc { pc },
+        _ => return Err(invalid_data(format!("unknown fault kind {}", kind))),
+    };
+
+    let count = input.read_u32::<BigEndian>().map_err(truncated)?;
+    let mut recent = Vec::new();
+    for _ in 0..count {
+        let pc = input.read_u16::<BigEndian>().map_err(truncated)?;
+        let instruction = input.read_u16::<BigEndian>().map_err(truncated)?;
+        recent.push((pc, instruction));
+    }
+
+    let mut vm = VM::new();
+    let count = input.read_u32::<BigEndian>().map_err(truncated)?;
+    for _ in 0..count {
+        let mut name = vec![0; input.read_u8().map_err(truncated)? as usize];
+        input.read_exact(&mut name).map_err(truncated)?;
+        let addr = input.read_u16::<BigEndian>().map_err(truncated)?;
+        let name = String::from_utf8(name).map_err(|_| invalid_data("bad symbol name"))?;
+        vm.symbols.insert(&name, addr);
+    }
+    vm.load_state(input)?;
+    Ok(CoreDump { vm, fault, recent })
+}
+
+// One entry of a reconstructed call stack
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    // where execution is, or the JSR that will be returned to
+    pub pc: u16,
+    // R5 of the frame, None for frames only known from R7
+    pub frame_pointer: Option<u16>,
+}
+
+// a chain of more frames than this is taken to be corrupted
+const MAX_FRAMES: usize = 64;
+
+impl CoreDump {
+    // Walks the frames of the LC-3 stack convention: R5 points at the first
+    // local with the caller's R5 just above it and the return address above
+    // that. A leaf subroutine that keeps its return address in R7 shows up
+    // as an extra frame when R7 follows a JSR that no frame accounts for
+    pub fn backtrace(&self) -> Vec<Frame> {
+        let memory = &self.vm.memory;
+        let is_call = |ret: u16| {
+            let call = memory[ret.wrapping_sub(1) as usize];
+            ret != 0 && OpCode::from_u16(&call) == Some(OpCode::OpJsr)
+        };
+        let mut frames = vec![Frame {
+            pc: self.fault.pc(),
+            frame_pointer: None,
+        }];
+
+        let mut linked = Vec::new();
+        let mut fp = self.vm.registers.get_val(5);
+        while fp != 0 && linked.len() < MAX_FRAMES {
+            let caller_fp = memory[fp.wrapping_add(1) as usize];
+            let ret = memory[fp.wrapping_add(2) as usize];
+            if !is_call(ret) {
+                break;
+            }
+            linked.push(Frame {
+                pc: ret.wrapping_sub(1),
+                frame_pointer: Some(fp),
+            });
+            // the stack grows down, so callers' frames are at higher addresses
+            if caller_fp <= fp {
+                break;
+            }
+            fp = caller_fp;
+        }
+
+        let r7 = self.vm.registers.get_val(7);
+        let leaf = r7.wrapping_sub(1);
+        if is_call(r7) && !linked.iter().any(|f| f.pc == leaf) {
+            frames.push(Frame {
+                pc: leaf,
+                frame_pointer: None,
+            });
+        }
+        frames.extend(linked);
+        frames
+    }
+
+    // fault, registers, backtrace and the instructions leading up to the
+    // fault, with labels from symbols
+    pub fn describe(&self, symbols: &SymbolTable) -> String {
+        let vm = &self.vm;
+        let mut out = format!("{}\n\n", self.fault.describe(symbols));
+        for r in 0..8 {
+            out.push_str(&format!("R{} x{:04X}", r, vm.registers.get_val(r)));
+            out.push_str(if r % 4 == 3 { "\n" } else { "  " });
+        }
+        out.push_str(&format!(
+            "PC x{:04X}  PSR x{:04X}  steps {}\n",
+            vm.registers.get_val(PC_REG),
+            vm.psr(),
+            vm.steps
+        ));
+
+        out.push_str("\nbacktrace:\n");
+        for (i, frame) in self.backtrace().iter().enumerate() {
+            out.push_str(&format!(
+                "#{:<2} {:<20} {}",
+                i,
+                symbols.format_addr(frame.pc),
+                super::disasm::disassemble(vm.memory[frame.pc as usize], frame.pc, symbols)
+            ));
+            if let Some(fp) = frame.frame_pointer {
+                out.push_str(&format!("  (R5 x{:04X})", fp));
+            }
+            out.push('\n');
+        }
+
+        if !self.recent.is_empty() {
+            out.push_str("\nlast instructions:\n");
+            for (pc, instruction) in &self.recent {
+                out.push_str(&format!(
+                    "  {:<20} {:04X}  {}\n",
+                    symbols.format_addr(*pc),
+                    instruction,
+                    super::disasm::disassemble(*instruction, *pc, symbols)
+                ));
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use std::sync::{Arc, Mutex};
+
+    // MAIN calls F with a stack frame, F calls the leaf G through R7 only
+    // and G runs into a reserved opcode
+    const SOURCE: &str = "        .ORIG x3000
+MAIN    LD R6, STACK
+        AND R5, R5, #0
+        JSR F
+        HALT
+STACK   .FILL x4000
+F       ADD R6, R6, #-1
+        ADD R6, R6, #-1
+        STR R7, R6, #0
+        ADD R6, R6, #-1
+        STR R5, R6, #0
+        ADD R5, R6, #-1
+        JSR G
+        HALT
+G       .FILL xD000
+        .END
+";
+
+    #[test]
+    fn test_core_round_trip() {
+        let assembly = assemble(SOURCE).unwrap();
+        let mut vm = VM::new();
+        vm.load_image(&assembly.image);
+        vm.symbols = assembly.symbols;
+        let recent = Arc::new(Mutex::new(RecentInstructions::new(3)));
+        vm.add_observer(recent.clone());
+        let fault = vm.execute_program().unwrap_err();
+
+        let recent: Vec<(u16, u16)> = recent.lock().unwrap().entries.iter().copied().collect();
+        let mut bytes = Vec::new();
+        write_core(&vm, &fault, &recent, &mut bytes).unwrap();
+        let core = read_core(&mut bytes.as_slice()).unwrap();
+        assert_eq!(core.fault, fault);
+        assert_eq!(
+            core.recent,
+            vec![(0x300A, 0x1BBF), (0x300B, 0x4801), (0x300D, 0xD000)]
+        );
+        assert_eq!(core.vm.symbols.lookup("G"), Some(0x300D));
+        assert_eq!(core.vm.registers.snapshot(), vm.registers.snapshot());
+
+        assert_eq!(
+            core.backtrace(),
+            vec![
+                Frame {
+                    pc: 0x300D,
+                    frame_pointer: None
+                },
+                Frame {
+                    pc: 0x300B,
+                    frame_pointer: None
+                },
+                Frame {
+                    pc: 0x3002,
+                    frame_pointer: Some(0x3FFC)
+                },
+            ]
+        );
+        let report = core.describe(&core.vm.symbols);
+        assert!(report.starts_with("illegal opcode xD000: fault at G (x300D)\n"));
+        assert!(report.contains("#2  MAIN+2 (x3002)       JSR F  (R5 x3FFC)\n"));
+    }
+
+    #[test]
+    fn test_memory_faults_round_trip() {
+        let vm = VM::new();
+        for fault in [
+            Fault::AccessViolation {
+                pc: 0x3004,
+                addr: 0xFE04,
+            },
+            Fault::RunawayPc { pc: 0x3100 },
+        ] {
+            let mut bytes = Vec::new();
+            write_core(&vm, &fault, &[], &mut bytes).unwrap();
+            let core = read_core(&mut bytes.as_slice()).unwrap();
+            assert_eq!(core.fault, fault);
+        }
+    }
+}
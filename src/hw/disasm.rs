@@ -0,0 +1,91 @@
+use crate::symbols::SymbolTable;
+
+use super::instruction::{sign_extend, OpCode};
+
+// Turns an instruction word back into assembly, addr is where the word lives
+// so PC relative operands can be shown as absolute targets (or labels)
+pub fn disassemble(instruction: u16, addr: u16, symbols: &SymbolTable) -> String {
+    let dr = (instruction >> 9) & 0x7;
+    let sr1 = (instruction >> 6) & 0x7;
+    let next_pc = addr.wrapping_add(1);
+    let target = |bits: u8| {
+        let offset = sign_extend(instruction & ((1 << bits) - 1), bits);
+        target_name(next_pc.wrapping_add(offset), symbols)
+    };
+    let imm5 = || sign_extend(instruction & 0x1f, 5) as i16;
+    let offset6 = || sign_extend(instruction & 0x3f, 6) as i16;
+
+    match OpCode::from_u16(&instruction) {
+        Some(OpCode::OpBr) => {
+            let n = if instruction & 0x800 != 0 { "n" } else { "" };
+            let z = if instruction & 0x400 != 0 { "z" } else { "" };
+            let p = if instruction & 0x200 != 0 { "p" } else { "" };
+            if (instruction >> 9) & 0x7 == 0 {
+                // no condition bits, the branch is never taken
+                format!(".FILL x{:04X}", instruction)
+            } else {
+                format!("BR{}{}{} {}", n, z, p, target(9))
+            }
+        }
+        Some(OpCode::OpAdd) | Some(OpCode::OpAnd) => {
+            let name = if instruction >> 12 == 1 { "ADD" } else { "AND" };
+            if (instruction >> 5) & 0x1 == 1 {
+                format!("{} R{}, R{}, #{}", name, dr, sr1, imm5())
+            } else {
+                format!("{} R{}, R{}, R{}", name, dr, sr1, instruction & 0x7)
+            }
+        }
+        Some(OpCode::OpLd) => format!("LD R{}, {}", dr, target(9)),
+        Some(OpCode::OpSt) => format!("ST R{}, {}", dr, target(9)),
+        Some(OpCode::OpLdi) => format!("LDI R{}, {}", dr, target(9)),
+        Some(OpCode::OpSti) => format!("STI R{}, {}", dr, target(9)),
+        Some(OpCode::OpLea) => format!("LEA R{}, {}", dr, target(9)),
+        Some(OpCode::OpLdr) => format!("LDR R{}, R{}, #{}", dr, sr1, offset6()),
+        Some(OpCode::OpStr) => format!("STR R{}, R{}, #{}", dr, sr1, offset6()),
+        Some(OpCode::OpNot) => format!("NOT R{}, R{}", dr, sr1),
+        Some(OpCode::OpJmp) if sr1 == 7 => "RET".to_string(),
+        Some(OpCode::OpJmp) => format!("JMP R{}", sr1),
+        Some(OpCode::OpJsr) if (instruction >> 11) & 1 == 1 => format!("JSR {}", target(11)),
+        Some(OpCode::OpJsr) => format!("JSRR R{}", sr1),
+        Some(OpCode::OpRti) => "RTI".to_string(),
+        Some(OpCode::OpTrap) => match instruction & 0xFF {
+            0x20 => "GETC".to_string(),
+            0x21 => "OUT".to_string(),
+            0x22 => "PUTS".to_string(),
+            0x23 => "IN".to_string(),
+            0x24 => "PUTSP".to_string(),
+            0x25 => "HALT".to_string(),
+            vector => format!("TRAP x{:02X}", vector),
+        },
+        Some(OpCode::OpRes) | None => format!(".FILL x{:04X}", instruction),
+    }
+}
+
+// an exact label if there is one, otherwise the raw address
+fn target_name(addr: u16, symbols: &SymbolTable) -> String {
+    match symbols.name_at(addr) {
+        Some(name) => name.to_string(),
+        None => format!("x{:04X}", addr),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disassemble() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert("LOOP", 0x3000);
+
+        assert_eq!(disassemble(0x1021, 0x3000, &symbols), "ADD R0, R0, #1");
+        assert_eq!(disassemble(0x103F, 0x3000, &symbols), "ADD R0, R0, #-1");
+        assert_eq!(disassemble(0x5042, 0x3000, &symbols), "AND R0, R1, R2");
+        // BRp back to the start of the loop
+        assert_eq!(disassemble(0x03FD, 0x3002, &symbols), "BRp LOOP");
+        assert_eq!(disassemble(0x2202, 0x3000, &symbols), "LD R1, x3003");
+        assert_eq!(disassemble(0xC1C0, 0x3000, &symbols), "RET");
+        assert_eq!(disassemble(0xF025, 0x3000, &symbols), "HALT");
+        assert_eq!(disassemble(0xD000, 0x3000, &symbols), ".FILL xD000");
+    }
+}
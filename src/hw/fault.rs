@@ -0,0 +1,57 @@
+use std::fmt;
+
+use crate::symbols::SymbolTable;
+
+// Conditions that stop the machine instead of executing an instruction,
+// pc is always the address of the offending instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    IllegalOpcode { pc: u16, instruction: u16 },
+    PrivilegeViolation { pc: u16 },
+    UnknownTrap { pc: u16, vector: u8 },
+    // a data access outside the memory user programs may use
+    AccessViolation { pc: u16, addr: u16 },
+    // PC reached memory nothing was ever loaded into or written to
+    RunawayPc { pc: u16 },
+}
+
+impl Fault {
+    pub fn pc(&self) -> u16 {
+        match *self {
+            Fault::IllegalOpcode { pc, .. } => pc,
+            Fault::PrivilegeViolation { pc } => pc,
+            Fault::UnknownTrap { pc, .. } => pc,
+            Fault::AccessViolation { pc, .. } => pc,
+            Fault::RunawayPc { pc } => pc,
+        }
+    }
+
+    fn reason(&self) -> String {
+        match *self {
+            Fault::IllegalOpcode { instruction, .. } => {
+                format!("illegal opcode x{:04X}", instruction)
+            }
+            Fault::PrivilegeViolation { .. } => "privilege mode violation (RTI)".to_string(),
+            Fault::UnknownTrap { vector, .. } => format!("unknown trap x{:02X}", vector),
+            Fault::AccessViolation { addr, .. } => {
+                format!("access control violation at x{:04X}", addr)
+            }
+            Fault::RunawayPc { .. } => "runaway PC, nothing was loaded here".to_string(),
+        }
+    }
+
+    // "illegal opcode xD000: fault at LOOP+3 (x3012)"
+    pub fn describe(&self, symbols: &SymbolTable) -> String {
+        format!(
+            "{}: fault at {}",
+            self.reason(),
+            symbols.format_addr(self.pc())
+        )
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: fault at x{:04X}", self.reason(), self.pc())
+    }
+}
@@ -0,0 +1,143 @@
+use std::collections::VecDeque;
+
+use super::register::NUM_REGISTERS;
+
+// Everything one instruction changed, enough to put the machine back the
+// way it was before the instruction ran
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UndoEntry {
+    pub pc: u16,
+    pub registers: [u16; NUM_REGISTERS as usize],
+    pub halted: bool,
+    // in the order the writes happened
+    pub writes: Vec<MemWrite>,
+    pub reads: Vec<u16>,
+    // characters taken from the input source
+    pub input: Vec<u16>,
+    // the loop detector's counts of data writes and input characters
+    pub memory_writes: u64,
+    pub inputs_taken: u64,
+    // the key KBDR held, and the state at the last KBSR read that found no
+    // key
+    pub kbdr: u16,
+    pub idle: Option<([u16; NUM_REGISTERS as usize], u64, u64)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemWrite {
+    pub addr: u16,
+    pub old: u16,
+    pub new: u16,
+}
+
+// Bounded undo log of executed instructions. A limit of 0 turns recording
+// off, which is the default so plain runs pay nothing for it
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    entries: VecDeque<UndoEntry>,
+    limit: usize,
+    recording: bool,
+    // input given back by undone instructions, consumed again before any
+    // new input so re-executing them sees the same characters
+    replay: VecDeque<u16>,
+}
+
+impl History {
+    pub fn with_limit(limit: usize) -> Self {
+        History {
+            limit,
+            ..History::default()
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    // shrinking the limit drops the oldest entries
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+        while self.entries.len() > limit {
+            self.entries.pop_front();
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.replay.clear();
+    }
+
+    // starts the entry of an instruction about to execute
+    pub(crate) fn begin(&mut self, entry: UndoEntry) {
+        if self.limit == 0 {
+            return;
+        }
+        if self.entries.len() == self.limit {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+        self.recording = true;
+    }
+
+    // the instruction completed, later changes belong to nobody
+    pub(crate) fn end(&mut self) {
+        self.recording = false;
+    }
+
+    // the instruction faulted and changed nothing worth keeping
+    pub(crate) fn abort(&mut self) {
+        if self.recording {
+            self.entries.pop_back();
+        }
+        self.recording = false;
+    }
+
+    fn current(&mut self) -> Option<&mut UndoEntry> {
+        match self.recording {
+            true => self.entries.back_mut(),
+            false => None,
+        }
+    }
+
+    pub(crate) fn record_write(&mut self, addr: u16, old: u16, new: u16) {
+        if let Some(entry) = self.current() {
+            entry.writes.push(MemWrite { addr, old, new });
+        }
+    }
+
+    pub(crate) fn record_read(&mut self, addr: u16) {
+        if let Some(entry) = self.current() {
+            entry.reads.push(addr);
+        }
+    }
+
+    pub(crate) fn record_input(&mut self, c: u16) {
+        if let Some(entry) = self.current() {
+            entry.input.push(c);
+        }
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<UndoEntry> {
+        let entry = self.entries.pop_back()?;
+        for c in entry.input.iter().rev() {
+            self.replay.push_front(*c);
+        }
+        Some(entry)
+    }
+
+    pub(crate) fn has_replay(&self) -> bool {
+        !self.replay.is_empty()
+    }
+
+    pub(crate) fn take_replay(&mut self) -> Option<u16> {
+        self.replay.pop_front()
+    }
+}
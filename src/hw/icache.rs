@@ -0,0 +1,200 @@
+use std::collections::BTreeMap;
+
+use crate::symbols::SymbolTable;
+
+use super::instruction::{sign_extend, OpCode};
+
+// Instructions decoded once per address as VM::step runs them, with the
+// fields already extracted and the offsets sign-extended. Each entry keeps
+// the word it was decoded from and is only used while memory still holds
+// that word, so memory changed behind the VM's back never reads stale
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decoded {
+    AddReg { dr: u8, sr1: u8, sr2: u8 },
+    AddImm { dr: u8, sr1: u8, imm: u16 },
+    AndReg { dr: u8, sr1: u8, sr2: u8 },
+    AndImm { dr: u8, sr1: u8, imm: u16 },
+    Br { nzp: u16, offset: u16 },
+    Jmp { base: u8 },
+    Jsr { offset: u16 },
+    Jsrr { base: u8 },
+    Ld { dr: u8, offset: u16 },
+    Ldi { dr: u8, offset: u16 },
+    Ldr { dr: u8, base: u8, offset: u16 },
+    Lea { dr: u8, offset: u16 },
+    Not { dr: u8, sr: u8 },
+    St { sr: u8, offset: u16 },
+    Sti { sr: u8, offset: u16 },
+    Str { sr: u8, base: u8, offset: u16 },
+    // TRAP, RTI and the reserved opcode, which have nothing to extract
+    Other,
+}
+
+pub fn decode(word: u16) -> Decoded {
+    let r = |shift: u16| ((word >> shift) & 0x7) as u8;
+    let imm5 = sign_extend(word & 0x1F, 5);
+    let offset6 = sign_extend(word & 0x3F, 6);
+    let offset9 = sign_extend(word & 0x1FF, 9);
+    let immediate = (word >> 5) & 1 == 1;
+    match OpCode::from_u16(&word) {
+        Some(OpCode::OpAdd) if immediate => Decoded::AddImm {
+            dr: r(9),
+            sr1: r(6),
+            imm: imm5,
+        },
+        Some(OpCode::OpAdd) => Decoded::AddReg {
+            dr: r(9),
+            sr1: r(6),
+            sr2: r(0),
+        },
+        Some(OpCode::OpAnd) if immediate => Decoded::AndImm {
+            dr: r(9),
+            sr1: r(6),
+            imm: imm5,
+        },
+        Some(OpCode::OpAnd) => Decoded::AndReg {
+            dr: r(9),
+            sr1: r(6),
+            sr2: r(0),
+        },
+        Some(OpCode::OpBr) => Decoded::Br {
+            nzp: (word >> 9) & 0x7,
+            offset: offset9,
+        },
+        Some(OpCode::OpJmp) => Decoded::Jmp { base: r(6) },
+        Some(OpCode::OpJsr) if (word >> 11) & 1 == 1 => Decoded::Jsr {
+            offset: sign_extend(word & 0x7FF, 11),
+        },
+        Some(OpCode::OpJsr) => Decoded::Jsrr { base: r(6) },
+        Some(OpCode::OpLd) => Decoded::Ld {
+            dr: r(9),
+            offset: offset9,
+        },
+        Some(OpCode::OpLdi) => Decoded::Ldi {
+            dr: r(9),
+            offset: offset9,
+        },
+        Some(OpCode::OpLdr) => Decoded::Ldr {
+            dr: r(9),
+            base: r(6),
+            offset: offset6,
+        },
+        Some(OpCode::OpLea) => Decoded::Lea {
+            dr: r(9),
+            offset: offset9,
+        },
+        Some(OpCode::OpNot) => Decoded::Not { dr: r(9), sr: r(6) },
+        Some(OpCode::OpSt) => Decoded::St {
+            sr: r(9),
+            offset: offset9,
+        },
+        Some(OpCode::OpSti) => Decoded::Sti {
+            sr: r(9),
+            offset: offset9,
+        },
+        Some(OpCode::OpStr) => Decoded::Str {
+            sr: r(9),
+            base: r(6),
+            offset: offset6,
+        },
+        Some(OpCode::OpTrap | OpCode::OpRti | OpCode::OpRes) | None => Decoded::Other,
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct InstructionCache {
+    // (word, decoded) per address, allocated on first use
+    entries: Vec<Option<(u16, Decoded)>>,
+    // stores into a word that had been executed, as
+    // (instruction, address written) -> times
+    pub writes: BTreeMap<(u16, u16), u64>,
+}
+
+impl InstructionCache {
+    // the decoded form of word, the instruction at addr
+    pub fn get(&mut self, addr: u16, word: u16) -> Decoded {
+        if self.entries.is_empty() {
+            self.entries = vec![None; 1 << 16];
+        }
+        match self.entries[addr as usize] {
+            Some((cached, decoded)) if cached == word => decoded,
+            _ => {
+                let decoded = decode(word);
+                self.entries[addr as usize] = Some((word, decoded));
+                decoded
+            }
+        }
+    }
+
+    // whether the word at addr has been decoded, so has run as code
+    pub fn contains(&self, addr: u16) -> bool {
+        self.entries
+            .get(addr as usize)
+            .is_some_and(|entry| entry.is_some())
+    }
+
+    pub fn invalidate(&mut self, addr: u16) {
+        if let Some(entry) = self.entries.get_mut(addr as usize) {
+            *entry = None;
+        }
+    }
+
+    // the instruction at pc stored into code at addr
+    pub fn record_write(&mut self, pc: u16, addr: u16) {
+        *self.writes.entry((pc, addr)).or_default() += 1;
+    }
+
+    // one line per instruction and address of self-modifying writes
+    pub fn describe_writes(&self, symbols: &SymbolTable) -> Vec<String> {
+        self.writes
+            .iter()
+            .map(|((pc, addr), times)| {
+                format!(
+                    "{} modified the instruction at {} ({} times)",
+                    symbols.format_addr(*pc),
+                    symbols.format_addr(*addr),
+                    times
+                )
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        // ADD R0 R0 -1; BRnp -5; JSRR R7; HALT
+        assert_eq!(
+            decode(0x103F),
+            Decoded::AddImm {
+                dr: 0,
+                sr1: 0,
+                imm: 0xFFFF
+            }
+        );
+        assert_eq!(
+            decode(0x0BFB),
+            Decoded::Br {
+                nzp: 5,
+                offset: 0xFFFB
+            }
+        );
+        assert_eq!(decode(0x41C0), Decoded::Jsrr { base: 7 });
+        assert_eq!(decode(0xF025), Decoded::Other);
+    }
+
+    #[test]
+    fn test_cache() {
+        let mut cache = InstructionCache::default();
+        assert!(!cache.contains(0x3000));
+        cache.get(0x3000, 0x103F);
+        assert!(cache.contains(0x3000));
+        // a different word at the same address is decoded afresh
+        assert_eq!(cache.get(0x3000, 0x41C0), Decoded::Jsrr { base: 7 });
+        cache.invalidate(0x3000);
+        assert!(!cache.contains(0x3000));
+    }
+}
@@ -0,0 +1,221 @@
+use std::collections::VecDeque;
+use std::fmt::Write;
+
+use crate::symbols::SymbolTable;
+
+// Keyboard input given up front so runs are reproducible. Each key becomes
+// available at an instruction count; GETC and IN, which wait for a key,
+// take the next one whether or not it is due yet, as if the machine had
+// waited for it. Script files hold one "STEP TEXT" line per timed run of
+// keys, where TEXT may use \n, \r, \t, \\ and \xHH:
+//   0 hello\n
+//   5000 a
+
+// memory-mapped keyboard: bit 15 of the status register is set while a key
+// is waiting, reading the data register takes it
+pub const KBSR: u16 = 0xFE00;
+pub const KBDR: u16 = 0xFE02;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtEnd {
+    // GETC reads this value, 0 like the end of STDIN unless told otherwise
+    Eof(u16),
+    // carry on from the real keyboard
+    Block,
+    // VM::run stops before an instruction that would read past the end,
+    // step and execute_program read it as 0
+    Stop,
+}
+
+impl Default for AtEnd {
+    fn default() -> Self {
+        AtEnd::Eof(0)
+    }
+}
+
+impl AtEnd {
+    // eof, eof:VALUE, block or stop
+    pub fn parse(text: &str) -> Result<Self, String> {
+        match text.split_once(':') {
+            None if text == "eof" => Ok(AtEnd::Eof(0)),
+            None if text == "block" => Ok(AtEnd::Block),
+            None if text == "stop" => Ok(AtEnd::Stop),
+            Some(("eof", value)) => SymbolTable::new()
+                .resolve(value)
+                .map(AtEnd::Eof)
+                .ok_or_else(|| format!("bad end of input value {:?}", value)),
+            _ => Err(format!("unknown end of input {:?}", text)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InputScript {
+    // (instruction count the key is due at, key) in the order they are read
+    keys: VecDeque<(u64, u8)>,
+    pub at_end: AtEnd,
+    // (instruction count, key) of every key taken
+    pub consumed: Vec<(u64, u8)>,
+}
+
+impl InputScript {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut script = InputScript::default();
+        for (n, line) in text.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let at = |e: &str| format!("line {}: {}", n + 1, e);
+            let (step, keys) = line.split_once(' ').unwrap_or((line, ""));
+            let step: u64 = step.parse().map_err(|_| at("expected a step count"))?;
+            if script.keys.back().is_some_and(|(last, _)| *last > step) {
+                return Err(at("steps must not go backwards"));
+            }
+            for key in unescape(keys).map_err(|e| at(&e))? {
+                script.keys.push_back((step, key));
+            }
+        }
+        Ok(script)
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    // keys due by instruction count now and not yet taken
+    pub fn available(&self, now: u64) -> bool {
+        self.keys.front().is_some_and(|(due, _)| *due <= now)
+    }
+
+    // the next key for the instruction at count now, None past the end
+    pub fn take(&mut self, now: u64) -> Option<u8> {
+        let (_, key) = self.keys.pop_front()?;
+        self.consumed.push((now, key));
+        Some(key)
+    }
+
+    // keys still to come, as (due, key)
+    pub fn pending(&self) -> impl Iterator<Item = (u64, u8)> + '_ {
+        self.keys.iter().copied()
+    }
+
+    pub fn push(&mut self, due: u64, key: u8) {
+        self.keys.push_back((due, key));
+    }
+
+    // the consumed keys in the script format, so a run can be replayed
+    pub fn consumed_script(&self) -> String {
+        let mut out = String::new();
+        let mut line: Option<u64> = None;
+        for &(step, key) in &self.consumed {
+            if line != Some(step) {
+                if line.is_some() {
+                    out.push('\n');
+                }
+                let _ = write!(out, "{} ", step);
+                line = Some(step);
+            }
+            out.push_str(&escape(key));
+        }
+        if line.is_some() {
+            out.push('\n');
+        }
+        out
+    }
+}
+
+// keys available from the start
+impl From<Vec<u8>> for InputScript {
+    fn from(keys: Vec<u8>) -> Self {
+        keys.into_iter().collect()
+    }
+}
+
+impl FromIterator<u8> for InputScript {
+    fn from_iter<I: IntoIterator<Item = u8>>(keys: I) -> Self {
+        InputScript {
+            keys: keys.into_iter().map(|key| (0, key)).collect(),
+            ..InputScript::default()
+        }
+    }
+}
+
+impl Extend<u8> for InputScript {
+    fn extend<I: IntoIterator<Item = u8>>(&mut self, keys: I) {
+        let due = self.keys.back().map_or(0, |(due, _)| *due);
+        self.keys.extend(keys.into_iter().map(|key| (due, key)));
+    }
+}
+
+fn unescape(text: &str) -> Result<Vec<u8>, String> {
+    let mut keys = Vec::new();
+    let mut bytes = text.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            keys.push(b);
+            continue;
+        }
+        keys.push(match bytes.next() {
+            Some(b'n') => b'\n',
+            Some(b'r') => b'\r',
+            Some(b't') => b'\t',
+            Some(b'\\') => b'\\',
+            Some(b'x') => {
+                let hex: Vec<u8> = bytes.by_ref().take(2).collect();
+                std::str::from_utf8(&hex)
+                    .ok()
+                    .and_then(|h| u8::from_str_radix(h, 16).ok())
+                    .filter(|_| hex.len() == 2)
+                    .ok_or("\\x needs two hex digits")?
+            }
+            _ => return Err("unknown escape".to_string()),
+        });
+    }
+    Ok(keys)
+}
+
+fn escape(key: u8) -> String {
+    match key {
+        b'\n' => "\\n".to_string(),
+        b'\r' => "\\r".to_string(),
+        b'\t' => "\\t".to_string(),
+        b'\\' => "\\\\".to_string(),
+        0x20..=0x7E => (key as char).to_string(),
+        _ => format!("\\x{:02X}", key),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_script() {
+        let mut script = InputScript::parse("# greeting\n0 hi\\n\n\n5000 a\\x00\n").unwrap();
+        assert_eq!(script.len(), 5);
+        assert!(script.available(0));
+        assert_eq!(script.take(10), Some(b'h'));
+        assert_eq!(script.take(11), Some(b'i'));
+        assert_eq!(script.take(12), Some(b'\n'));
+        assert!(!script.available(4999));
+        assert!(script.available(5000));
+        assert_eq!(script.take(6000), Some(b'a'));
+        assert_eq!(script.take(6000), Some(0));
+        assert_eq!(script.take(6001), None);
+        assert_eq!(
+            script.consumed_script(),
+            "10 h\n11 i\n12 \\n\n6000 a\\x00\n"
+        );
+
+        assert!(InputScript::parse("x hi").is_err());
+        assert!(InputScript::parse("5 a\n4 b").is_err());
+        assert!(InputScript::parse("0 \\q").is_err());
+        assert_eq!(AtEnd::parse("eof:xFFFF"), Ok(AtEnd::Eof(0xFFFF)));
+        assert_eq!(AtEnd::parse("stop"), Ok(AtEnd::Stop));
+        assert!(AtEnd::parse("wait").is_err());
+    }
+}
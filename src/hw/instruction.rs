@@ -1,3 +1,4 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum OpCode {
     OpBr = 0, /* branch */
     OpAdd,    /* add  */
@@ -40,16 +41,46 @@ impl OpCode {
             _ => None,
         }
     }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::OpBr => "BR",
+            Self::OpAdd => "ADD",
+            Self::OpLd => "LD",
+            Self::OpSt => "ST",
+            Self::OpJsr => "JSR",
+            Self::OpAnd => "AND",
+            Self::OpLdr => "LDR",
+            Self::OpStr => "STR",
+            Self::OpRti => "RTI",
+            Self::OpNot => "NOT",
+            Self::OpLdi => "LDI",
+            Self::OpSti => "STI",
+            Self::OpJmp => "JMP",
+            Self::OpRes => "RES",
+            Self::OpLea => "LEA",
+            Self::OpTrap => "TRAP",
+        }
+    }
 }
 
+// RET is JMP R7
+pub const RET: u16 = 0xC1C0;
+
+// The course's calling convention: R0 carries results and R7 the return
+// address, a callee leaves R1-R5 as it found them and R6, the stack
+// pointer, where it was on entry. R6 is kept apart from the saved registers
+// because the convention checker follows it as the stack, reporting an
+// unbalanced stack rather than a clobbered register, while a subroutine
+// test only compares registers and so checks R6 along with the others
+pub const STACK_POINTER: u8 = 6;
+pub const CALLEE_SAVED: [u8; 5] = [1, 2, 3, 4, 5];
+
 pub fn sign_extend(num: u16, bit_count: u8) -> u16 {
     let mut ret: u16 = num;
     // if num is negative, need to pad with zeroes
-    println!("NUM SHIFTED: {:?}", num >> (bit_count - 1));
     if ((num >> (bit_count - 1)) & 1) != 0 {
-        println!("OLD NUM BIN: {:#b}", ret);
         ret |= 0xffff << bit_count;
-        println!("NEW NUM BIN: {:#b}", ret);
     }
     // if num is positive, it will already be padded with zeroes
     ret
@@ -0,0 +1,224 @@
+use crate::symbols::SymbolTable;
+
+use super::icache::{decode, Decoded};
+use super::input::KBSR;
+use super::register::{NUM_REGISTERS, PC_REG};
+
+// the longest loop, in instructions, that is sure to be caught
+pub const DEFAULT_WINDOW: u64 = 1 << 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoopKind {
+    // a branch or jump whose target is itself
+    BranchToSelf,
+    // a loop polling KBSR with no input left to arrive
+    BusyWait,
+    // the whole machine state came round again
+    Repeated,
+}
+
+// A loop the program can never leave, start and end are the lowest and
+// highest addresses it executes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Loop {
+    pub start: u16,
+    pub end: u16,
+    pub kind: LoopKind,
+}
+
+impl Loop {
+    pub fn describe(&self, symbols: &SymbolTable) -> String {
+        let range = match self.start == self.end {
+            true => symbols.format_addr(self.start),
+            false => format!(
+                "{}..{}",
+                symbols.format_addr(self.start),
+                symbols.format_addr(self.end)
+            ),
+        };
+        match self.kind {
+            LoopKind::BranchToSelf => format!("infinite loop: branch to itself at {}", range),
+            LoopKind::BusyWait => {
+                format!("infinite loop: waiting on KBSR with no input in {}", range)
+            }
+            LoopKind::Repeated => format!("infinite loop: the machine state repeats in {}", range),
+        }
+    }
+}
+
+// What has to come round again for a loop: every register, and the counts
+// of memory writes and input characters, which only grow
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoopState {
+    pub registers: [u16; NUM_REGISTERS as usize],
+    pub writes: u64,
+    pub inputs: u64,
+}
+
+// Brent's cycle detection over the machine states after each instruction.
+// Memory only changes through writes, so a state repeating with no write
+// and no input in between repeats forever. The saved state is renewed at
+// doubling intervals capped at the window, so a loop entered late in a long
+// run is still caught soon after
+#[derive(Debug, Clone)]
+pub struct LoopDetector {
+    pub window: u64,
+    saved: Option<LoopState>,
+    power: u64,
+    since: u64,
+    // addresses executed since the saved state
+    low: u16,
+    high: u16,
+}
+
+impl Default for LoopDetector {
+    fn default() -> Self {
+        LoopDetector::new(DEFAULT_WINDOW)
+    }
+}
+
+impl LoopDetector {
+    pub fn new(window: u64) -> Self {
+        LoopDetector {
+            window,
+            saved: None,
+            power: 1,
+            since: 0,
+            low: 0,
+            high: 0,
+        }
+    }
+
+    // the range of addresses of the loop once state has been seen before
+    pub fn check(&mut self, state: LoopState) -> Option<(u16, u16)> {
+        match self.saved {
+            Some(saved) if saved == state => return Some((self.low, self.high)),
+            // memory or input moved on, nothing from before can come round
+            Some(saved) if (saved.writes, saved.inputs) != (state.writes, state.inputs) => {
+                self.power = 1;
+                self.save(state);
+            }
+            Some(_) if self.since >= self.power => {
+                self.power = (self.power * 2).min(self.window);
+                self.save(state);
+            }
+            Some(_) => {
+                let pc = state.registers[PC_REG as usize];
+                self.since += 1;
+                self.low = self.low.min(pc);
+                self.high = self.high.max(pc);
+            }
+            None => self.save(state),
+        }
+        None
+    }
+
+    fn save(&mut self, state: LoopState) {
+        let pc = state.registers[PC_REG as usize];
+        self.saved = Some(state);
+        self.since = 0;
+        self.low = pc;
+        self.high = pc;
+    }
+}
+
+// names the loop from the instructions in it, memory and registers being
+// those of the repeating state
+pub fn classify(start: u16, end: u16, memory: &[u16], registers: &[u16]) -> Loop {
+    let mut kind = LoopKind::Repeated;
+    if start == end {
+        kind = LoopKind::BranchToSelf;
+    }
+    let mut addr = start;
+    loop {
+        if read_address(addr, memory, registers) == Some(KBSR) {
+            kind = LoopKind::BusyWait;
+        }
+        if addr == end {
+            break;
+        }
+        addr = addr.wrapping_add(1);
+    }
+    Loop { start, end, kind }
+}
+
+// the address the load at addr reads its value from, None for instructions
+// other than LD, LDI and LDR
+pub fn read_address(addr: u16, memory: &[u16], registers: &[u16]) -> Option<u16> {
+    let next = addr.wrapping_add(1);
+    match decode(memory[addr as usize]) {
+        Decoded::Ld { offset, .. } => Some(next.wrapping_add(offset)),
+        Decoded::Ldi { offset, .. } => Some(memory[next.wrapping_add(offset) as usize]),
+        Decoded::Ldr { base, offset, .. } => Some(registers[base as usize].wrapping_add(offset)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::hw::stop::StopReason;
+    use crate::hw::vm::VM;
+
+    fn run(source: &str, input: &[u8]) -> StopReason {
+        let assembly = assemble(source).unwrap();
+        let mut vm = VM::new();
+        vm.load_image(&assembly.image);
+        vm.input = Some(input.to_vec().into());
+        vm.loop_detector = Some(LoopDetector::default());
+        vm.run(Some(1_000_000))
+    }
+
+    fn found(start: u16, end: u16, kind: LoopKind) -> StopReason {
+        StopReason::Loop(Loop { start, end, kind })
+    }
+
+    #[test]
+    fn test_loops() {
+        let spin = ".ORIG x3000\nAND R0, R0, #0\nSELF BRnzp SELF\n.END";
+        assert_eq!(
+            run(spin, b""),
+            found(0x3001, 0x3001, LoopKind::BranchToSelf)
+        );
+
+        let poll = ".ORIG x3000
+POLL    LDI R1, KBSRP
+        BRzp POLL
+        HALT
+KBSRP   .FILL xFE00
+        .END";
+        assert_eq!(run(poll, b""), found(0x3000, 0x3001, LoopKind::BusyWait));
+
+        // waits for a non-zero character, of which there are none at the end
+        let getc = ".ORIG x3000
+LOOP    GETC
+        ADD R0, R0, #0
+        BRz LOOP
+        HALT
+        .END";
+        assert_eq!(run(getc, b""), found(0x3000, 0x3002, LoopKind::Repeated));
+        assert_eq!(run(getc, b"\0\0a"), StopReason::Halted);
+    }
+
+    #[test]
+    fn test_loops_that_end() {
+        // long loops with no write are told apart by their registers, a
+        // loop with the same registers each time round by its writes
+        let count = ".ORIG x3000
+        LD R0, N
+LOOP    ADD R0, R0, #-1
+        BRp LOOP
+        AND R1, R1, #0
+AGAIN   ST R1, N
+        LD R2, N
+        ADD R2, R2, #0
+        BRz AGAIN
+        HALT
+N       .FILL #30000
+        .END";
+        assert_eq!(run(count, b""), StopReason::StepLimit);
+        let count = count.replace("        BRz AGAIN\n", "");
+        assert_eq!(run(&count, b""), StopReason::Halted);
+    }
+}
@@ -1,3 +1,17 @@
+pub mod branches;
+pub mod breakpoint;
+pub mod coredump;
+pub mod disasm;
+pub mod fault;
+pub mod history;
+pub mod icache;
+pub mod input;
 pub mod instruction;
+pub mod loops;
+pub mod observer;
 pub mod register;
+pub mod snapshot;
+pub mod stop;
+pub mod trace;
 pub mod vm;
+pub mod watch;
@@ -0,0 +1,74 @@
+use std::sync::{Arc, Mutex};
+
+use super::fault::Fault;
+use super::instruction::OpCode;
+
+// Callbacks for instrumentation, every method does nothing by default so an
+// observer only implements the events it cares about. The VM only does the
+// extra work of reporting events while at least one observer is attached
+pub trait Observer {
+    // the word at pc is about to execute
+    fn on_fetch(&mut self, _pc: u16, _instruction: u16) {}
+
+    // None for words that decode to no opcode
+    fn on_decode(&mut self, _pc: u16, _instruction: u16, _opcode: Option<OpCode>) {}
+
+    // any register an instruction writes, PC and COND included
+    fn on_register_write(&mut self, _register: u8, _old: u16, _new: u16) {}
+
+    // data accesses, instruction fetches are reported by on_fetch instead
+    fn on_memory_read(&mut self, _addr: u16, _value: u16) {}
+    fn on_memory_write(&mut self, _addr: u16, _old: u16, _new: u16) {}
+
+    // pc is the address of the TRAP instruction, entry is only reported for
+    // vectors with a service routine, after R7 is written, and exit for
+    // service routines that completed
+    fn on_trap_entry(&mut self, _pc: u16, _vector: u8) {}
+    fn on_trap_exit(&mut self, _pc: u16, _vector: u8) {}
+
+    // the instruction at fault.pc() raised an exception and did not execute,
+    // it wrote nothing but PC, which is put back, and entered no trap
+    fn on_exception(&mut self, _fault: &Fault) {}
+
+    // pc is the address of the HALT, steps counts it
+    fn on_halt(&mut self, _pc: u16, _steps: u64) {}
+}
+
+// lets the caller keep a handle on an observer it has given to a VM
+impl<T: Observer> Observer for Arc<Mutex<T>> {
+    fn on_fetch(&mut self, pc: u16, instruction: u16) {
+        self.lock().unwrap().on_fetch(pc, instruction)
+    }
+
+    fn on_decode(&mut self, pc: u16, instruction: u16, opcode: Option<OpCode>) {
+        self.lock().unwrap().on_decode(pc, instruction, opcode)
+    }
+
+    fn on_register_write(&mut self, register: u8, old: u16, new: u16) {
+        self.lock().unwrap().on_register_write(register, old, new)
+    }
+
+    fn on_memory_read(&mut self, addr: u16, value: u16) {
+        self.lock().unwrap().on_memory_read(addr, value)
+    }
+
+    fn on_memory_write(&mut self, addr: u16, old: u16, new: u16) {
+        self.lock().unwrap().on_memory_write(addr, old, new)
+    }
+
+    fn on_trap_entry(&mut self, pc: u16, vector: u8) {
+        self.lock().unwrap().on_trap_entry(pc, vector)
+    }
+
+    fn on_trap_exit(&mut self, pc: u16, vector: u8) {
+        self.lock().unwrap().on_trap_exit(pc, vector)
+    }
+
+    fn on_exception(&mut self, fault: &Fault) {
+        self.lock().unwrap().on_exception(fault)
+    }
+
+    fn on_halt(&mut self, pc: u16, steps: u64) {
+        self.lock().unwrap().on_halt(pc, steps)
+    }
+}
@@ -1,8 +1,6 @@
-use std::collections::HashMap;
-
 // was considering using an enum but it is too cumbersome to go between
 // enums and other types
-const NUM_REGISTERS: u8 = 10;
+pub const NUM_REGISTERS: u8 = 10;
 /* LC3 Registers
  * R0 = 0
  * R1
@@ -22,22 +20,24 @@ pub const COND_REG: u8 = 9;
 
 pub enum ConditionFlag {
     POS = 1,
-    NEG = 2,
-    ZERO = 4,
+    ZERO = 2,
+    NEG = 4,
 }
 
 pub struct Registers {
-    pub regs: HashMap<u8, u16>,
+    pub regs: [u16; NUM_REGISTERS as usize],
+}
+
+impl Default for Registers {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Registers {
     pub fn new() -> Self {
-        let mut regs = HashMap::new();
-        for r in 0..NUM_REGISTERS {
-            regs.insert(r, 0);
-        }
-
-        regs.insert(PC_REG, PC_START);
+        let mut regs = [0; NUM_REGISTERS as usize];
+        regs[PC_REG as usize] = PC_START;
         Registers { regs }
     }
 
@@ -47,8 +47,7 @@ impl Registers {
             panic!("INVALID REGISTER: {:?}", register)
         }
 
-        eprintln!("inserting {:?} into reg: {:?}", value, register);
-        self.regs.insert(register, value);
+        self.regs[register as usize] = value;
     }
 
     pub fn get_val(&self, register: u8) -> u16 {
@@ -56,17 +55,35 @@ impl Registers {
             panic!("INVALID REGISTER: {:?}", register)
         }
 
-        *self.regs.get(&register).unwrap()
+        self.regs[register as usize]
     }
 
     pub fn update_cond_register(&mut self, register: u8) {
         let val = self.get_val(register);
-        eprintln!("val gotten from reg {:?}: {:?}", register, val);
-        eprintln!("NUM SHIFTED: {:?}", val >> 15);
-        match val {
-            0 => self.regs.insert(COND_REG, ConditionFlag::ZERO as u16),
-            x if (x >> 15) != 0 => self.regs.insert(COND_REG, ConditionFlag::NEG as u16),
-            _ => self.regs.insert(COND_REG, ConditionFlag::POS as u16),
+        self.regs[COND_REG as usize] = match val {
+            0 => ConditionFlag::ZERO as u16,
+            x if (x >> 15) != 0 => ConditionFlag::NEG as u16,
+            _ => ConditionFlag::POS as u16,
         };
     }
+
+    // every register value, indexed like regs
+    pub fn snapshot(&self) -> [u16; NUM_REGISTERS as usize] {
+        self.regs
+    }
+
+    pub fn restore(&mut self, values: &[u16; NUM_REGISTERS as usize]) {
+        self.regs = *values;
+    }
+}
+
+// N, Z and P letters for the condition flags that are set
+pub fn cond_name(cond: u16) -> String {
+    let mut name = String::new();
+    for (bit, letter) in [(4, 'N'), (2, 'Z'), (1, 'P')] {
+        if cond & bit != 0 {
+            name.push(letter);
+        }
+    }
+    name
 }
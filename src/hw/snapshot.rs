@@ -0,0 +1,207 @@
+use std::io::{self, Read, Write};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::image::invalid_data;
+
+use super::input::{AtEnd, InputScript};
+use super::register::{COND_REG, NUM_REGISTERS, PC_REG};
+use super::vm::VM;
+
+// Snapshot layout, all words big-endian like .obj:
+//   "LC3S", version u16, flags u16 (1 = halted, 2 = pending input follows)
+//   steps u64, R0-R7 and PC, PSR
+//   KBDR, the last key read from the keyboard data register u16. Versions 1
+//   and 2 had no device registers and it reads back as 0
+//   pending input: what happens at its end u8 (0 eof, 1 block, 2 stop) and
+//   the eof value u16, count u32, then (due u64, key u8) per key. Version 1
+//   had the length u32 and the keys, all due at once
+//   all 65536 memory words
+// There are no supervisor stacks to save, traps run natively and programs
+// stay in user mode
+pub const MAGIC: &[u8; 4] = b"LC3S";
+pub const VERSION: u16 = 3;
+
+const HALTED: u16 = 1;
+const HAS_INPUT: u16 = 2;
+
+impl VM {
+    // everything needed to carry on executing later, breakpoints, symbols
+    // and the undo history are not part of the machine state
+    pub fn save_state(&self, out: &mut impl Write) -> io::Result<()> {
+        out.write_all(MAGIC)?;
+        out.write_u16::<BigEndian>(VERSION)?;
+        let mut flags = 0;
+        if self.halted {
+            flags |= HALTED;
+        }
+        if self.input.is_some() {
+            flags |= HAS_INPUT;
+        }
+        out.write_u16::<BigEndian>(flags)?;
+        out.write_u64::<BigEndian>(self.steps)?;
+        for r in 0..=PC_REG {
+            out.write_u16::<BigEndian>(self.registers.get_val(r))?;
+        }
+        out.write_u16::<BigEndian>(self.psr())?;
+        out.write_u16::<BigEndian>(self.kbdr)?;
+
+        if let Some(input) = &self.input {
+            let (at_end, value) = match input.at_end {
+                AtEnd::Eof(value) => (0, value),
+                AtEnd::Block => (1, 0),
+                AtEnd::Stop => (2, 0),
+            };
+            out.write_u8(at_end)?;
+            out.write_u16::<BigEndian>(value)?;
+            out.write_u32::<BigEndian>(input.len() as u32)?;
+            for (due, key) in input.pending() {
+                out.write_u64::<BigEndian>(due)?;
+                out.write_u8(key)?;
+            }
+        }
+        for word in self.memory.iter() {
+            out.write_u16::<BigEndian>(*word)?;
+        }
+        Ok(())
+    }
+
+    // replaces the machine state with a saved one, the undo history is
+    // cleared since it belongs to the replaced execution
+    pub fn load_state(&mut self, input: &mut impl Read) -> io::Result<()> {
+        let truncated = |_| invalid_data("snapshot is truncated");
+        let mut magic = [0; 4];
+        input.read_exact(&mut magic).map_err(truncated)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("not a snapshot"));
+        }
+        let version = input.read_u16::<BigEndian>().map_err(truncated)?;
+        if !(1..=VERSION).contains(&version) {
+            return Err(invalid_data(format!(
+                "snapshot version {} is not supported",
+                version
+            )));
+        }
+        let flags = input.read_u16::<BigEndian>().map_err(truncated)?;
+        let steps = input.read_u64::<BigEndian>().map_err(truncated)?;
+        let mut registers = [0; NUM_REGISTERS as usize];
+        for value in registers.iter_mut().take(PC_REG as usize + 1) {
+            *value = input.read_u16::<BigEndian>().map_err(truncated)?;
+        }
+        registers[COND_REG as usize] = input.read_u16::<BigEndian>().map_err(truncated)? & 0x7;
+        let kbdr = match version {
+            1 | 2 => 0,
+            _ => input.read_u16::<BigEndian>().map_err(truncated)?,
+        };
+
+        let pending = match (flags & HAS_INPUT, version) {
+            (0, _) => None,
+            (_, 1) => {
+                let len = input.read_u32::<BigEndian>().map_err(truncated)?;
+                let mut bytes = vec![0; len as usize];
+                input.read_exact(&mut bytes).map_err(truncated)?;
+                Some(bytes.into())
+            }
+            _ => {
+                let mut script = InputScript::default();
+                let at_end = input.read_u8().map_err(truncated)?;
+                let value = input.read_u16::<BigEndian>().map_err(truncated)?;
+                script.at_end = match at_end {
+                    0 => AtEnd::Eof(value),
+                    1 => AtEnd::Block,
+                    2 => AtEnd::Stop,
+                    _ => return Err(invalid_data("bad end of input in snapshot")),
+                };
+                for _ in 0..input.read_u32::<BigEndian>().map_err(truncated)? {
+                    let due = input.read_u64::<BigEndian>().map_err(truncated)?;
+                    script.push(due, input.read_u8().map_err(truncated)?);
+                }
+                Some(script)
+            }
+        };
+        let mut memory = vec![0; self.memory.len()];
+        input
+            .read_u16_into::<BigEndian>(&mut memory)
+            .map_err(truncated)?;
+
+        self.memory.copy_from_slice(&memory);
+        self.set_all_loaded();
+        self.registers.restore(&registers);
+        self.halted = flags & HALTED != 0;
+        self.steps = steps;
+        self.kbdr = kbdr;
+        self.input = pending;
+        self.history.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hw::register::PC_START;
+    use crate::image::Image;
+
+    #[test]
+    fn test_round_trip() {
+        // GETC; ADD R0 R0 1; OUT; HALT
+        let mut vm = VM::new();
+        vm.load_image(&Image::new(PC_START, vec![0xF020, 0x1021, 0xF021, 0xF025]));
+        vm.input = Some(b"ab".to_vec().into());
+        vm.output = Some(Vec::new());
+        vm.step().unwrap();
+
+        let mut bytes = Vec::new();
+        vm.save_state(&mut bytes).unwrap();
+        let mut resumed = VM::new();
+        resumed.output = Some(Vec::new());
+        resumed.load_state(&mut bytes.as_slice()).unwrap();
+        assert_eq!(resumed.registers.snapshot(), vm.registers.snapshot());
+        assert_eq!(resumed.steps, 1);
+        assert_eq!(resumed.input, Some(b"b".to_vec().into()));
+
+        resumed.execute_program().unwrap();
+        assert_eq!(resumed.output, Some(b"b".to_vec()));
+        assert_eq!(resumed.steps, 4);
+    }
+
+    #[test]
+    fn test_keyboard_data_register() {
+        // LDI R0 KBDR; LDI R1 KBDR; HALT; .FILL xFE02, the second read gets
+        // the same key as no other has come
+        let mut vm = VM::new();
+        vm.load_image(&Image::new(PC_START, vec![0xA002, 0xA201, 0xF025, 0xFE02]));
+        vm.input = Some(b"a".to_vec().into());
+        vm.step().unwrap();
+
+        let mut bytes = Vec::new();
+        vm.save_state(&mut bytes).unwrap();
+        let mut resumed = VM::new();
+        resumed.load_state(&mut bytes.as_slice()).unwrap();
+        resumed.execute_program().unwrap();
+        assert_eq!(resumed.registers.get_val(1), u16::from(b'a'));
+
+        // version 2 had no device registers, KBDR reads back as 0
+        bytes.splice(36..38, []);
+        bytes[5] = 2;
+        resumed.load_state(&mut bytes.as_slice()).unwrap();
+        assert_eq!(resumed.kbdr, 0);
+        assert_eq!(resumed.registers.get_val(0), u16::from(b'a'));
+    }
+
+    #[test]
+    fn test_bad_snapshots() {
+        let mut bytes = Vec::new();
+        VM::new().save_state(&mut bytes).unwrap();
+        let error = |bytes: &[u8]| {
+            VM::new()
+                .load_state(&mut &bytes[..])
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(error(&bytes[..100]), "snapshot is truncated");
+        assert_eq!(error(b"LC3Z"), "not a snapshot");
+        bytes[5] = 9;
+        assert_eq!(error(&bytes), "snapshot version 9 is not supported");
+    }
+}
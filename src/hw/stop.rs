@@ -0,0 +1,46 @@
+use std::fmt;
+
+use crate::symbols::SymbolTable;
+
+use super::fault::Fault;
+use super::loops::Loop;
+use super::watch::WatchHit;
+
+// Why VM::run handed control back to its caller
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Halted,
+    Fault(Fault),
+    Breakpoint(u16),
+    Watchpoint(WatchHit),
+    StepLimit,
+    HistoryStart,
+    // only detected while the VM has a loop detector
+    Loop(Loop),
+    // the instruction at this address would read past the end of an input
+    // script that stops there
+    EndOfInput(u16),
+}
+
+impl StopReason {
+    pub fn describe(&self, symbols: &SymbolTable) -> String {
+        match self {
+            StopReason::Halted => "program halted".to_string(),
+            StopReason::Fault(fault) => fault.describe(symbols),
+            StopReason::Breakpoint(addr) => format!("breakpoint at {}", symbols.format_addr(*addr)),
+            StopReason::Watchpoint(hit) => hit.describe(symbols),
+            StopReason::StepLimit => "step limit reached".to_string(),
+            StopReason::HistoryStart => "reached the start of the recorded history".to_string(),
+            StopReason::Loop(found) => found.describe(symbols),
+            StopReason::EndOfInput(pc) => {
+                format!("end of input at {}", symbols.format_addr(*pc))
+            }
+        }
+    }
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.describe(&SymbolTable::new()))
+    }
+}
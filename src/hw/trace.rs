@@ -0,0 +1,297 @@
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+
+use serde_json::{json, Value};
+
+use crate::symbols::SymbolTable;
+
+use super::instruction::OpCode;
+use super::register::cond_name;
+
+// Per instruction execution trace, written by the VM as it steps
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    Text,    /* one aligned line per instruction */
+    Jsonl,   /* one JSON object per line */
+    Records, /* kept in memory until Tracer::take_records */
+}
+
+impl TraceFormat {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "text" | "txt" => Some(Self::Text),
+            "jsonl" | "json" => Some(Self::Jsonl),
+            _ => None,
+        }
+    }
+}
+
+// One executed instruction and its effects
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TraceRecord {
+    // instructions executed so far, counting this one
+    pub step: u64,
+    pub pc: u16,
+    pub instruction: u16,
+    pub asm: String,
+    // registers the instruction wrote and their new values
+    pub registers: Vec<(u8, u16)>,
+    // data reads and writes as (address, value)
+    pub reads: Vec<(u16, u16)>,
+    pub writes: Vec<(u16, u16)>,
+    // condition codes after the instruction
+    pub cond: u16,
+}
+
+impl TraceRecord {
+    // "      12  x3002  1021  ADD R0, R0, #1  R0=x0003  CC=P"
+    pub fn to_text(&self) -> String {
+        let mut line = format!(
+            "{:>8}  x{:04X}  {:04X}  {:<24}",
+            self.step, self.pc, self.instruction, self.asm
+        );
+        for (r, value) in &self.registers {
+            line.push_str(&format!("  R{}=x{:04X}", r, value));
+        }
+        for (addr, value) in &self.reads {
+            line.push_str(&format!("  [x{:04X}]->x{:04X}", addr, value));
+        }
+        for (addr, value) in &self.writes {
+            line.push_str(&format!("  [x{:04X}]<-x{:04X}", addr, value));
+        }
+        line.push_str(&format!("  CC={}", cond_name(self.cond)));
+        line
+    }
+
+    pub fn to_json(&self) -> Value {
+        let accesses = |list: &[(u16, u16)]| -> Vec<Value> {
+            list.iter()
+                .map(|(addr, value)| json!({ "addr": addr, "value": value }))
+                .collect()
+        };
+        json!({
+            "step": self.step,
+            "pc": self.pc,
+            "instruction": self.instruction,
+            "asm": self.asm,
+            "registers": self
+                .registers
+                .iter()
+                .map(|(r, value)| json!({ "reg": r, "value": value }))
+                .collect::<Vec<Value>>(),
+            "reads": accesses(&self.reads),
+            "writes": accesses(&self.writes),
+            "cc": cond_name(self.cond),
+        })
+    }
+
+    pub fn from_json(value: &Value) -> Option<Self> {
+        let word = |v: &Value| v.as_u64().and_then(|v| u16::try_from(v).ok());
+        let accesses = |list: &Value| -> Option<Vec<(u16, u16)>> {
+            list.as_array()?
+                .iter()
+                .map(|a| Some((word(&a["addr"])?, word(&a["value"])?)))
+                .collect()
+        };
+        let cond = value["cc"].as_str()?.chars().try_fold(0, |cond, c| {
+            Some(
+                cond | match c {
+                    'N' => 4,
+                    'Z' => 2,
+                    'P' => 1,
+                    _ => return None,
+                },
+            )
+        })?;
+        Some(TraceRecord {
+            step: value["step"].as_u64()?,
+            pc: word(&value["pc"])?,
+            instruction: word(&value["instruction"])?,
+            asm: value["asm"].as_str()?.to_string(),
+            registers: value["registers"]
+                .as_array()?
+                .iter()
+                .map(|r| Some((u8::try_from(r["reg"].as_u64()?).ok()?, word(&r["value"])?)))
+                .collect::<Option<_>>()?,
+            reads: accesses(&value["reads"])?,
+            writes: accesses(&value["writes"])?,
+            cond,
+        })
+    }
+}
+
+// registers an instruction writes, from its encoding
+pub fn destination_registers(instruction: u16) -> Vec<u8> {
+    let dr = ((instruction >> 9) & 0x7) as u8;
+    match OpCode::from_u16(&instruction) {
+        Some(OpCode::OpAdd) | Some(OpCode::OpAnd) | Some(OpCode::OpNot) | Some(OpCode::OpLd)
+        | Some(OpCode::OpLdi) | Some(OpCode::OpLdr) | Some(OpCode::OpLea) => vec![dr],
+        Some(OpCode::OpJsr) => vec![7],
+        // every trap saves PC in R7, GETC and IN also read into R0
+        Some(OpCode::OpTrap) => match instruction & 0xFF {
+            0x20 | 0x23 => vec![7, 0],
+            _ => vec![7],
+        },
+        _ => Vec::new(),
+    }
+}
+
+// Which instructions get traced, everything when both parts are empty
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TraceFilter {
+    pub range: Option<RangeInclusive<u16>>,
+    pub kinds: Vec<OpCode>,
+}
+
+impl TraceFilter {
+    pub fn matches(&self, pc: u16, instruction: u16) -> bool {
+        self.range.as_ref().is_none_or(|range| range.contains(&pc))
+            && (self.kinds.is_empty()
+                || OpCode::from_u16(&instruction).is_some_and(|op| self.kinds.contains(&op)))
+    }
+
+    // START..END, each end an address or a label
+    pub fn parse_range(text: &str, symbols: &SymbolTable) -> Result<RangeInclusive<u16>, String> {
+        let (start, end) = text
+            .split_once("..")
+            .ok_or_else(|| format!("expected START..END, got {:?}", text))?;
+        let resolve = |addr: &str| {
+            symbols
+                .resolve(addr.trim())
+                .ok_or_else(|| format!("unknown address {:?}", addr))
+        };
+        Ok(resolve(start)?..=resolve(end)?)
+    }
+
+    // comma separated opcode names, or the groups alu, load, store, memory
+    // and control
+    pub fn parse_kinds(text: &str) -> Result<Vec<OpCode>, String> {
+        use OpCode::*;
+        let mut kinds = Vec::new();
+        for name in text.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            let group: &[OpCode] = match name.to_ascii_lowercase().as_str() {
+                "alu" => &[OpAdd, OpAnd, OpNot],
+                "load" => &[OpLd, OpLdi, OpLdr],
+                "store" => &[OpSt, OpSti, OpStr],
+                "memory" => &[OpLd, OpLdi, OpLdr, OpSt, OpSti, OpStr],
+                "control" => &[OpBr, OpJmp, OpJsr, OpTrap, OpRti],
+                _ => {
+                    let op = (0..16u16)
+                        .filter_map(|op| OpCode::from_u16(&(op << 12)))
+                        .find(|op| op.name().eq_ignore_ascii_case(name))
+                        .ok_or_else(|| format!("unknown instruction kind {:?}", name))?;
+                    kinds.push(op);
+                    continue;
+                }
+            };
+            kinds.extend_from_slice(group);
+        }
+        Ok(kinds)
+    }
+}
+
+// Writes trace records for the instructions that pass its filter
+pub struct Tracer {
+    out: Box<dyn Write + Send>,
+    pub format: TraceFormat,
+    pub filter: TraceFilter,
+    // the first write error, reported by finish so tracing never stops a run
+    error: Option<io::Error>,
+    records: Vec<TraceRecord>,
+}
+
+impl Tracer {
+    pub fn new(out: Box<dyn Write + Send>, format: TraceFormat) -> Self {
+        Tracer {
+            out,
+            format,
+            filter: TraceFilter::default(),
+            error: None,
+            records: Vec::new(),
+        }
+    }
+
+    // keeps the records for the caller instead of writing them
+    pub fn collecting() -> Self {
+        Tracer::new(Box::new(io::sink()), TraceFormat::Records)
+    }
+
+    pub fn take_records(&mut self) -> Vec<TraceRecord> {
+        std::mem::take(&mut self.records)
+    }
+
+    pub fn with_filter(mut self, filter: TraceFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    pub fn emit(&mut self, record: &TraceRecord) {
+        if self.error.is_some() {
+            return;
+        }
+        let line = match self.format {
+            TraceFormat::Text => record.to_text(),
+            TraceFormat::Jsonl => record.to_json().to_string(),
+            TraceFormat::Records => {
+                self.records.push(record.clone());
+                return;
+            }
+        };
+        if let Err(e) = writeln!(self.out, "{}", line) {
+            self.error = Some(e);
+        }
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        self.out.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record() -> TraceRecord {
+        TraceRecord {
+            step: 3,
+            pc: 0x3002,
+            instruction: 0x7040,
+            asm: "STR R0, R1, #0".to_string(),
+            registers: Vec::new(),
+            reads: Vec::new(),
+            writes: vec![(0x4000, 2)],
+            cond: 1,
+        }
+    }
+
+    #[test]
+    fn test_formats() {
+        assert_eq!(
+            record().to_text(),
+            "       3  x3002  7040  STR R0, R1, #0            [x4000]<-x0002  CC=P"
+        );
+        let json = record().to_json();
+        assert_eq!(json["writes"][0]["addr"], 0x4000);
+        assert_eq!(TraceRecord::from_json(&json), Some(record()));
+    }
+
+    #[test]
+    fn test_filter() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert("LOOP", 0x3001);
+        let filter = TraceFilter {
+            range: Some(TraceFilter::parse_range("LOOP..x3010", &symbols).unwrap()),
+            kinds: TraceFilter::parse_kinds("alu,str").unwrap(),
+        };
+        assert!(filter.matches(0x3002, 0x7040));
+        assert!(filter.matches(0x3001, 0x1021));
+        assert!(!filter.matches(0x3000, 0x1021));
+        assert!(!filter.matches(0x3002, 0x0FFE));
+        assert!(TraceFilter::parse_kinds("jump").is_err());
+        assert_eq!(destination_registers(0xF020), vec![7, 0]);
+    }
+}
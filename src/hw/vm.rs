@@ -1,115 +1,620 @@
+use std::collections::BTreeMap;
 use std::io;
-use std::io::Write;
-use std::process::exit;
+use std::io::{Read, Write};
 
 use crate::hw::register;
+use crate::image::Image;
+use crate::symbols::SymbolTable;
+use crate::terminal::Keyboard;
 
+use super::breakpoint::Breakpoint;
+use super::disasm::disassemble;
+use super::fault::Fault;
+use super::history::{History, UndoEntry};
+use super::icache::InstructionCache;
+use super::input::{AtEnd, InputScript, KBDR, KBSR};
 use super::instruction::sign_extend;
 use super::instruction::OpCode;
-use super::register::COND_REG;
+use super::loops::{self, LoopDetector, LoopKind, LoopState};
+use super::observer::Observer;
 use super::register::PC_REG;
+use super::register::{COND_REG, NUM_REGISTERS};
+use super::stop::StopReason;
+use super::trace::{destination_registers, TraceRecord, Tracer};
+use super::watch::{Access, WatchHit, Watchpoint};
+
+const MEMORY_MAX: usize = 1 << 16;
+
+// user programs may use x3000-xFDFF, and the keyboard registers as there is
+// no operating system here to read the keyboard for them
+fn user_accessible(addr: u16) -> bool {
+    (0x3000..0xFE00).contains(&addr) || addr == KBSR || addr == KBDR
+}
 
-const MEMORY_MAX: u16 = u16::MAX;
 pub struct VM {
-    pub memory: [u16; MEMORY_MAX as usize],
+    pub memory: [u16; MEMORY_MAX],
     pub registers: register::Registers,
+    pub symbols: SymbolTable,
+    pub breakpoints: BTreeMap<u16, Breakpoint>,
+    pub watchpoints: Vec<Watchpoint>,
+    // set by the instruction that triggered a watchpoint, consumed by run()
+    watch_hit: Option<WatchHit>,
+    pub halted: bool,
+    // instructions executed since the VM was created, less any undone
+    pub steps: u64,
+    pub history: History,
+    // when set, console output collects here instead of going to STDOUT
+    pub output: Option<Vec<u8>>,
+    // when set, keyboard input comes from here instead of STDIN
+    pub input: Option<InputScript>,
+    // when set, STDIN is read through it, which lets KBSR poll it
+    pub keyboard: Option<Keyboard>,
+    // STDIN read without a keyboard has ended
+    stdin_ended: bool,
+    // the key last read from KBDR, which it keeps reading until another comes
+    pub(crate) kbdr: u16,
+    // the state at the last KBSR read that found no key, as (registers,
+    // memory writes, inputs taken)
+    idle: Option<([u16; NUM_REGISTERS as usize], u64, u64)>,
+    pub tracer: Option<Tracer>,
+    // data accesses of the instruction being traced, as (addr, value)
+    traced_reads: Vec<(u16, u16)>,
+    traced_writes: Vec<(u16, u16)>,
+    tracing: bool,
+    pub observers: Vec<Box<dyn Observer + Send>>,
+    pub icache: InstructionCache,
+    // per address whether anything was loaded into or written to it, empty
+    // until the first write
+    loaded: Vec<bool>,
+    // when set, run stops on loops the program can never leave
+    pub loop_detector: Option<LoopDetector>,
+    // data writes and input characters taken so far, for the loop detector
+    memory_writes: u64,
+    inputs_taken: u64,
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl VM {
     pub fn new() -> Self {
-        println!("INITTTTT");
         VM {
-            memory: [0; MEMORY_MAX as usize],
+            memory: [0; MEMORY_MAX],
             registers: register::Registers::new(),
+            symbols: SymbolTable::new(),
+            breakpoints: BTreeMap::new(),
+            watchpoints: Vec::new(),
+            watch_hit: None,
+            halted: false,
+            steps: 0,
+            history: History::default(),
+            output: None,
+            input: None,
+            keyboard: None,
+            stdin_ended: false,
+            kbdr: 0,
+            idle: None,
+            tracer: None,
+            traced_reads: Vec::new(),
+            traced_writes: Vec::new(),
+            tracing: false,
+            observers: Vec::new(),
+            icache: InstructionCache::default(),
+            loaded: Vec::new(),
+            loop_detector: None,
+            memory_writes: 0,
+            inputs_taken: 0,
+        }
+    }
+
+    pub fn add_observer(&mut self, observer: impl Observer + Send + 'static) {
+        self.observers.push(Box::new(observer));
+    }
+
+    fn notify(&mut self, event: impl Fn(&mut dyn Observer)) {
+        for observer in &mut self.observers {
+            event(observer.as_mut());
         }
     }
 
+    // a fresh machine with image loaded and PC at its entry. The condition
+    // flags start as Z, as if R0 had just been cleared
+    pub fn with_program(image: &Image, symbols: SymbolTable) -> Self {
+        let mut vm = VM::new();
+        vm.registers.update_cond_register(0);
+        vm.load_image(image);
+        vm.symbols = symbols;
+        vm
+    }
+
     // TODO: ideally returns a Result and checks index
     pub fn write_memory(&mut self, addr_to_write: usize, value: u16) {
         self.memory[addr_to_write] = value;
+        self.icache.invalidate(addr_to_write as u16);
+        if self.loaded.is_empty() {
+            self.loaded = vec![false; MEMORY_MAX];
+        }
+        self.loaded[addr_to_write] = true;
+    }
+
+    // whether anything was loaded into or written to addr, all of memory
+    // counts until something is
+    pub fn loaded(&self, addr: u16) -> bool {
+        self.loaded.get(addr as usize).is_none_or(|loaded| *loaded)
+    }
+
+    // memory restored as a whole, from a snapshot, counts as loaded
+    pub(crate) fn set_all_loaded(&mut self) {
+        self.loaded = vec![true; MEMORY_MAX];
     }
 
     pub fn read_memory(&self, addr_to_read: usize) -> Option<u16> {
-        if addr_to_read >= MEMORY_MAX as usize {
+        if addr_to_read >= MEMORY_MAX {
             return None;
         }
 
         Some(self.memory[addr_to_read])
     }
 
-    // executes the program contained in Memory starting at PC_START
-    pub fn execute_program(&mut self) {
-        while self.registers.get_val(PC_REG) < MEMORY_MAX {
-            // read instruction
-            let instruction_bytes: u16 = self.memory[PC_REG as usize];
+    // copies every segment of an image into memory and points PC at its entry
+    pub fn load_image(&mut self, image: &Image) {
+        for segment in &image.segments {
+            for (i, word) in segment.words.iter().enumerate() {
+                self.write_memory(segment.origin.wrapping_add(i as u16) as usize, *word);
+            }
+        }
+
+        if let Some(entry) = image.entry() {
+            self.registers.update_register(PC_REG, entry);
+        }
+    }
+
+    // executes the program contained in Memory starting at PC until it halts
+    pub fn execute_program(&mut self) -> Result<(), Fault> {
+        while !self.halted {
+            self.step()?;
+        }
+        Ok(())
+    }
+
+    // executes the single instruction at PC, on a fault PC is left pointing
+    // at the offending instruction
+    pub fn step(&mut self) -> Result<(), Fault> {
+        let pc = self.registers.get_val(PC_REG);
+        if !self.loaded(pc) {
+            let fault = Fault::RunawayPc { pc };
+            self.notify(|o| o.on_exception(&fault));
+            return Err(fault);
+        }
+        self.watch_hit = None;
+        if self.history.limit() > 0 {
+            self.history.begin(UndoEntry {
+                pc,
+                registers: self.registers.snapshot(),
+                halted: self.halted,
+                writes: Vec::new(),
+                reads: Vec::new(),
+                input: Vec::new(),
+                memory_writes: self.memory_writes,
+                inputs_taken: self.inputs_taken,
+                kbdr: self.kbdr,
+                idle: self.idle,
+            });
+        }
+
+        // read instruction, decoding it marks the word as code for the
+        // self-modifying write check
+        let instruction_bytes: u16 = self.memory[pc as usize];
+        self.icache.get(pc, instruction_bytes);
+        if !self.observers.is_empty() {
+            self.notify(|o| o.on_fetch(pc, instruction_bytes));
+        }
+
+        self.tracing = self
+            .tracer
+            .as_ref()
+            .is_some_and(|t| t.filter.matches(pc, instruction_bytes));
+        if self.tracing {
+            self.traced_reads.clear();
+            self.traced_writes.clear();
+        }
+
+        // increment PC
+        self.set_register(PC_REG, pc.wrapping_add(1));
+
+        // perform instruction
+        let result = self.perform_instruction(instruction_bytes);
+        match result {
+            Ok(()) => {
+                self.steps += 1;
+                self.history.end();
+                if self.tracing {
+                    self.trace(pc, instruction_bytes);
+                }
+            }
+            Err(fault) => {
+                self.set_register(PC_REG, pc);
+                self.notify(|o| o.on_exception(&fault));
+                self.history.abort();
+            }
+        }
+        result
+    }
+
+    fn trace(&mut self, pc: u16, instruction: u16) {
+        let record = TraceRecord {
+            step: self.steps,
+            pc,
+            instruction,
+            asm: disassemble(instruction, pc, &self.symbols),
+            registers: destination_registers(instruction)
+                .into_iter()
+                .map(|r| (r, self.registers.get_val(r)))
+                .collect(),
+            reads: std::mem::take(&mut self.traced_reads),
+            writes: std::mem::take(&mut self.traced_writes),
+            cond: self.registers.get_val(COND_REG),
+        };
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.emit(&record);
+        }
+    }
+
+    // undoes the most recent recorded instruction, false when there is no
+    // history left to undo
+    pub fn step_back(&mut self) -> bool {
+        self.undo().is_some()
+    }
+
+    fn undo(&mut self) -> Option<UndoEntry> {
+        let entry = self.history.pop()?;
+        for write in entry.writes.iter().rev() {
+            self.write_memory(write.addr as usize, write.old);
+        }
+        self.registers.restore(&entry.registers);
+        self.halted = entry.halted;
+        self.memory_writes = entry.memory_writes;
+        self.inputs_taken = entry.inputs_taken;
+        self.kbdr = entry.kbdr;
+        self.idle = entry.idle;
+        self.steps -= 1;
+        Some(entry)
+    }
+
+    // runs backwards until the start of the recorded history, a breakpoint
+    // whose condition holds, an undone access to a watched address or
+    // max_steps undone instructions. Ignore counts only apply forwards
+    pub fn run_back(&mut self, max_steps: Option<u64>) -> StopReason {
+        let mut undone: u64 = 0;
+        loop {
+            if max_steps.is_some_and(|max| undone >= max) {
+                return StopReason::StepLimit;
+            }
+            let Some(entry) = self.undo() else {
+                return StopReason::HistoryStart;
+            };
+            undone += 1;
+
+            if let Some(hit) = self.undone_watch_hit(&entry) {
+                return StopReason::Watchpoint(hit);
+            }
+            let at_breakpoint = self.breakpoints.get(&entry.pc).is_some_and(|b| {
+                b.condition
+                    .as_ref()
+                    .is_none_or(|condition| condition.is_true(self))
+            });
+            if at_breakpoint {
+                return StopReason::Breakpoint(entry.pc);
+            }
+        }
+    }
+
+    // the first watched access among an undone instruction's reads and
+    // writes, memory already holds the values from before it ran
+    fn undone_watch_hit(&self, entry: &UndoEntry) -> Option<WatchHit> {
+        if self.watchpoints.is_empty() {
+            return None;
+        }
+
+        let reads = entry.reads.iter().map(|addr| {
+            let val = self.memory[*addr as usize];
+            (*addr, Access::Read, val, val)
+        });
+        let writes = entry
+            .writes
+            .iter()
+            .map(|w| (w.addr, Access::Write, w.old, w.new));
+        for (addr, access, old, new) in reads.chain(writes) {
+            let index = self
+                .watchpoints
+                .iter()
+                .position(|w| w.matches(addr, access, new));
+            if let Some(index) = index {
+                return Some(WatchHit {
+                    index,
+                    pc: entry.pc,
+                    addr,
+                    access,
+                    old,
+                    new,
+                });
+            }
+        }
+        None
+    }
+
+    // executes until the program halts, faults, reaches a breakpoint, triggers
+    // a watchpoint or has executed max_steps instructions. The instruction at
+    // PC always runs, so resuming from a breakpoint does not stop on it again.
+    // A watchpoint stops after the accessing instruction has completed
+    pub fn run(&mut self, max_steps: Option<u64>) -> StopReason {
+        self.run_checking_pc(max_steps, false)
+    }
+
+    // like run, but a breakpoint at PC stops before anything executes. For
+    // callers that run in slices and continue where the last slice ended
+    pub fn run_slice(&mut self, max_steps: Option<u64>) -> StopReason {
+        self.run_checking_pc(max_steps, true)
+    }
+
+    fn run_checking_pc(&mut self, max_steps: Option<u64>, check_first: bool) -> StopReason {
+        let mut executed: u64 = 0;
+        loop {
+            if self.halted {
+                return StopReason::Halted;
+            }
+            if max_steps.is_some_and(|max| executed >= max) {
+                return StopReason::StepLimit;
+            }
+
+            let pc = self.registers.get_val(PC_REG);
+            if self.reads_past_input(pc) {
+                return StopReason::EndOfInput(pc);
+            }
+            if (executed > 0 || check_first) && self.breakpoint_hit(pc) {
+                return StopReason::Breakpoint(pc);
+            }
+
+            if let Err(fault) = self.step() {
+                return StopReason::Fault(fault);
+            }
+            executed += 1;
+
+            if let Some(hit) = self.watch_hit.take() {
+                return StopReason::Watchpoint(hit);
+            }
+            if self.loop_detector.is_some() {
+                if let Some(found) = self.check_loop() {
+                    return StopReason::Loop(found);
+                }
+            }
+        }
+    }
+
+    // whether the instruction at pc is GETC or IN, or reads KBSR or KBDR,
+    // with an input script that has ended and stops there
+    fn reads_past_input(&self, pc: u16) -> bool {
+        let Some(input) = &self.input else {
+            return false;
+        };
+        if input.at_end != AtEnd::Stop || !input.is_empty() || self.history.has_replay() {
+            return false;
+        }
+        let instruction = self.memory[pc as usize];
+        instruction == 0xF020
+            || instruction == 0xF023
+            || matches!(
+                loops::read_address(pc, &self.memory, &self.registers.regs),
+                Some(KBSR | KBDR)
+            )
+    }
 
-            // increment PC
-            self.registers
-                .update_register(PC_REG, self.registers.get_val(PC_REG) + 1);
+    // whether the program is stopped at an instruction that would read past
+    // the end of its input script
+    pub fn waiting_for_input(&self) -> bool {
+        !self.halted && self.reads_past_input(self.registers.get_val(PC_REG))
+    }
 
-            // perform instruction
-            self.perform_instruction(instruction_bytes);
+    fn check_loop(&mut self) -> Option<loops::Loop> {
+        let state = LoopState {
+            registers: self.registers.snapshot(),
+            writes: self.memory_writes,
+            inputs: self.inputs_taken,
+        };
+        let (start, end) = self.loop_detector.as_mut()?.check(state)?;
+        let found = loops::classify(start, end, &self.memory, &state.registers);
+        match found.kind {
+            LoopKind::BusyWait if self.input_may_arrive() => None,
+            _ => Some(found),
         }
     }
+
+    // whether a key may still turn up for a program polling KBSR
+    fn input_may_arrive(&self) -> bool {
+        match &self.input {
+            Some(input) if !input.is_empty() => true,
+            Some(input) if input.at_end != AtEnd::Block => false,
+            _ => match &self.keyboard {
+                Some(keyboard) => !keyboard.ended(),
+                None => !self.stdin_ended,
+            },
+        }
+    }
+
+    // whether a KBSR read that found no key comes from the same state as the
+    // one before it. Nothing but a key can then change what the program does,
+    // so the host may wait for one instead of spinning. GETC already waits
+    fn idle(&mut self) -> bool {
+        let state = (
+            self.registers.snapshot(),
+            self.memory_writes,
+            self.inputs_taken,
+        );
+        self.idle.replace(state) == Some(state)
+    }
+
+    // the keyboard when keys now only come from it, never while a script is
+    // still giving them, so scripted runs take the same instructions
+    fn live_keyboard(&mut self) -> Option<&mut Keyboard> {
+        let scripted = self
+            .input
+            .as_ref()
+            .is_some_and(|input| !input.is_empty() || input.at_end != AtEnd::Block);
+        match scripted {
+            true => None,
+            false => self.keyboard.as_mut(),
+        }
+    }
+
+    // whether KBSR reports a key. STDIN without a keyboard to poll it does
+    // until it ends, reading KBDR then waits for one
+    fn key_ready(&mut self) -> bool {
+        if self.history.has_replay() {
+            return true;
+        }
+        if let Some(input) = &self.input {
+            if input.available(self.steps) {
+                return true;
+            }
+            if !input.is_empty() || input.at_end != AtEnd::Block {
+                return false;
+            }
+        }
+        match &mut self.keyboard {
+            Some(keyboard) => keyboard.ready(),
+            None => !self.stdin_ended,
+        }
+    }
+
+    // checks the condition and ignore count of a breakpoint at pc
+    fn breakpoint_hit(&mut self, pc: u16) -> bool {
+        let Some(breakpoint) = self.breakpoints.get(&pc) else {
+            return false;
+        };
+        if !breakpoint
+            .condition
+            .as_ref()
+            .is_none_or(|condition| condition.is_true(self))
+        {
+            return false;
+        }
+
+        let breakpoint = self.breakpoints.get_mut(&pc).unwrap();
+        breakpoint.hits += 1;
+        if breakpoint.ignore > 0 {
+            breakpoint.ignore -= 1;
+            return false;
+        }
+        true
+    }
+
+    // processor status register: bit 15 is the privilege mode, programs
+    // always run in user mode here, bits 2-0 are the condition codes
+    pub fn psr(&self) -> u16 {
+        0x8000 | self.registers.get_val(COND_REG)
+    }
+
+    pub fn set_psr(&mut self, psr: u16) {
+        self.registers.update_register(COND_REG, psr & 0x7);
+    }
+
+    // assembly for the word at addr, with labels from the symbol table
+    pub fn disassemble(&self, addr: u16) -> String {
+        disassemble(self.memory[addr as usize], addr, &self.symbols)
+    }
 }
 
 // VM: impl of instruction related code
 impl VM {
-    fn perform_instruction(&mut self, instruction: u16) {
+    fn perform_instruction(&mut self, instruction: u16) -> Result<(), Fault> {
         let opcode: Option<OpCode> = OpCode::from_u16(&instruction);
+        if !self.observers.is_empty() {
+            let pc = self.current_pc();
+            self.notify(|o| o.on_decode(pc, instruction, opcode));
+        }
         match opcode {
             Some(OpCode::OpAdd) => self.add(instruction),
             Some(OpCode::OpAnd) => self.and(instruction),
             Some(OpCode::OpBr) => self.br(instruction),
             Some(OpCode::OpJmp) => self.jmp(instruction),
             Some(OpCode::OpJsr) => self.jsr(instruction),
-            Some(OpCode::OpLd) => self.ld(instruction),
-            Some(OpCode::OpLdi) => self.ldi(instruction),
-            Some(OpCode::OpLdr) => self.ldr(instruction),
+            Some(OpCode::OpLd) => return self.ld(instruction),
+            Some(OpCode::OpLdi) => return self.ldi(instruction),
+            Some(OpCode::OpLdr) => return self.ldr(instruction),
             Some(OpCode::OpLea) => self.lea(instruction),
             Some(OpCode::OpNot) => self.not(instruction),
-            Some(OpCode::OpRes) => self.res(instruction),
-            Some(OpCode::OpRti) => self.rti(instruction),
-            Some(OpCode::OpSt) => self.st(instruction),
-            Some(OpCode::OpSti) => self.sti(instruction),
-            Some(OpCode::OpStr) => self.str(instruction),
-            Some(OpCode::OpTrap) => self.trap(instruction),
+            Some(OpCode::OpRes) => return self.res(instruction),
+            Some(OpCode::OpRti) => return self.rti(instruction),
+            Some(OpCode::OpSt) => return self.st(instruction),
+            Some(OpCode::OpSti) => return self.sti(instruction),
+            Some(OpCode::OpStr) => return self.str(instruction),
+            Some(OpCode::OpTrap) => return self.trap(instruction),
             None => (),
         }
+        Ok(())
+    }
+
+    // register writes made by instructions, reported to the observers
+    fn set_register(&mut self, register: u8, value: u16) {
+        if !self.observers.is_empty() {
+            let old = self.registers.get_val(register);
+            self.notify(|o| o.on_register_write(register, old, value));
+        }
+        self.registers.update_register(register, value);
+    }
+
+    fn set_cond(&mut self, register: u8) {
+        if self.observers.is_empty() {
+            return self.registers.update_cond_register(register);
+        }
+        let old = self.registers.get_val(COND_REG);
+        self.registers.update_cond_register(register);
+        let new = self.registers.get_val(COND_REG);
+        self.notify(|o| o.on_register_write(COND_REG, old, new));
+    }
+
+    // address of the instruction being performed, PC has already moved past it
+    fn current_pc(&self) -> u16 {
+        self.registers.get_val(PC_REG).wrapping_sub(1)
     }
 
     // ADD instruction layout
     // 15 - 12: 0001, 11-9: DR, 8-6: SR1, 5-3: 0, 2-0: SR2
     // 15 - 12: 0001, 11-9: DR, 8-6: SR1, 5: 1, 4-0: imm5
     fn add(&mut self, full_instruction: u16) {
-        println!("ADD INSTRUCTION");
         let dest_reg: u8 = ((full_instruction >> 9) & 0x7) as u8;
         let source_reg_1: u8 = ((full_instruction >> 6) & 0x7) as u8;
 
         // check if in immediate or register mode
         if (full_instruction >> 5) & 0x1 == 1 {
-            let imm5 = (full_instruction & 0x1f) as u16;
-            println!("IMM5: {:?}", imm5);
-            println!("SIGNEXT IMM5: {:?}", sign_extend(imm5, 5));
+            let imm5 = full_instruction & 0x1f;
 
             // second source operand obtained by sign-extending imm5
-            let val: u16 = self.registers.get_val(source_reg_1) + sign_extend(imm5, 5);
+            let val: u16 = self
+                .registers
+                .get_val(source_reg_1)
+                .wrapping_add(sign_extend(imm5, 5));
 
             // update register
-            self.registers.update_register(dest_reg, val);
+            self.set_register(dest_reg, val);
         } else {
             let source_reg_2: u8 = (full_instruction & 0x7) as u8;
 
-            // TODO: is overflow an issue?
-            let val: u16 =
-                self.registers.get_val(source_reg_1) + self.registers.get_val(source_reg_2);
+            // overflow wraps around as in 16 bit two's complement
+            let val: u16 = self
+                .registers
+                .get_val(source_reg_1)
+                .wrapping_add(self.registers.get_val(source_reg_2));
 
             // update register
-            self.registers.update_register(dest_reg, val);
+            self.set_register(dest_reg, val);
         }
 
         // ADD sets condition register flags
-        self.registers.update_cond_register(dest_reg);
+        self.set_cond(dest_reg);
     }
 
     // AND instruction layout
@@ -121,13 +626,13 @@ impl VM {
 
         // check if in immediate or register mode
         if (full_instruction >> 5) & 0x1 == 1 {
-            let imm5 = (full_instruction & 0x1f) as u16;
+            let imm5 = full_instruction & 0x1f;
 
             // second source operand obtained by sign-extending imm5
             let val: u16 = self.registers.get_val(source_reg_1) & sign_extend(imm5, 5);
 
             // update register
-            self.registers.update_register(dest_reg, val);
+            self.set_register(dest_reg, val);
         } else {
             let source_reg_2: u8 = (full_instruction & 0x7) as u8;
 
@@ -135,11 +640,11 @@ impl VM {
                 self.registers.get_val(source_reg_1) & self.registers.get_val(source_reg_2);
 
             // update register
-            self.registers.update_register(dest_reg, val);
+            self.set_register(dest_reg, val);
         }
 
         // AND sets condition register flags
-        self.registers.update_cond_register(dest_reg);
+        self.set_cond(dest_reg);
     }
 
     // BR instruction layout
@@ -150,8 +655,8 @@ impl VM {
         let conds = (full_instruction >> 9) & 0x7;
         if (conds & self.registers.get_val(COND_REG)) != 0 {
             let pcoff = sign_extend(full_instruction & 0x1ff, 9);
-            let new_pc = self.registers.get_val(PC_REG) + pcoff;
-            self.registers.update_register(PC_REG, new_pc)
+            let new_pc = self.registers.get_val(PC_REG).wrapping_add(pcoff);
+            self.set_register(PC_REG, new_pc)
         }
     }
 
@@ -161,8 +666,7 @@ impl VM {
     fn jmp(&mut self, full_instruction: u16) {
         let to_jmp = (full_instruction >> 6) & 0x7;
         // 111 -> 7, which corresponds to R7
-        self.registers
-            .update_register(PC_REG, self.registers.get_val(to_jmp as u8))
+        self.set_register(PC_REG, self.registers.get_val(to_jmp as u8))
     }
 
     // JSR (Jump Sub routine)
@@ -170,20 +674,22 @@ impl VM {
     // JSRR
     // 15-12: 0100, 11-9: 000, 8-6: BaseR, 5-0: 0
     fn jsr(&mut self, full_instruction: u16) {
+        // read BaseR before R7 is overwritten so JSRR R7 jumps to the old R7
+        let base_r = self
+            .registers
+            .get_val(((full_instruction >> 6) & 0x7) as u8);
         // save PC in R7
-        self.registers
-            .update_register(7, self.registers.get_val(PC_REG));
+        self.set_register(7, self.registers.get_val(PC_REG));
         if ((full_instruction >> 11) & 1) == 1 {
             // JSR - jump to PC + sign-extend(bits 10-0);
-            let new_pc = self.registers.get_val(PC_REG) + sign_extend(full_instruction & 0x7FF, 11);
-            self.registers.update_register(PC_REG, new_pc);
+            let new_pc = self
+                .registers
+                .get_val(PC_REG)
+                .wrapping_add(sign_extend(full_instruction & 0x7FF, 11));
+            self.set_register(PC_REG, new_pc);
         } else {
             // JSRR - jump to base reg
-            self.registers.update_register(
-                PC_REG,
-                self.registers
-                    .get_val(((full_instruction >> 6) & 0x7) as u8),
-            );
+            self.set_register(PC_REG, base_r);
         }
     }
 
@@ -191,52 +697,50 @@ impl VM {
     // 15-12: 0010, 11-9: DR, 8-0: pcoffset9
     // Contents of memory loaded into DR and cond codes are set
     // Address of memory is sign_extend(Pcoffset9) + 16
-    fn ld(&mut self, full_instruction: u16) {
-        // add as usize to avoid overflow, so we can detect if mem loc is out of bounds
-        let mem_addr = sign_extend(full_instruction & 0x1ff, 9) as usize
-            + self.registers.get_val(PC_REG) as usize;
+    fn ld(&mut self, full_instruction: u16) -> Result<(), Fault> {
+        // addresses wrap around the 16 bit address space
+        let mem_addr = self.pc_relative(full_instruction);
         let dr: u8 = ((full_instruction >> 9) & 0x7) as u8;
-        self.registers
-            .update_register(dr, self.read_memory(mem_addr).unwrap());
-        self.registers.update_cond_register(dr)
+        let val = self.user_load(mem_addr)?;
+        self.set_register(dr, val);
+        self.set_cond(dr);
+        Ok(())
     }
 
     // LDI (Load Indirect)
     // 15-12: 1010, 11-9: DR, 8-0: PCOffset9
     // DR = mem[mem[PC + sign_ext(PCOffset9)]]
-    fn ldi(&mut self, full_instruction: u16) {
-        let mem_addr_1 = sign_extend(full_instruction & 0x1ff, 9) as usize
-            + self.registers.get_val(PC_REG) as usize;
-        let mem_addr_2 = self.read_memory(mem_addr_1).unwrap() as usize;
+    fn ldi(&mut self, full_instruction: u16) -> Result<(), Fault> {
+        let mem_addr_1 = self.pc_relative(full_instruction);
+        let mem_addr_2 = self.user_load(mem_addr_1)?;
         let dr = ((full_instruction >> 9) & 0x7) as u8;
-        self.registers
-            .update_register(dr, self.read_memory(mem_addr_2).unwrap());
-        self.registers.update_cond_register(dr)
+        let val = self.user_load(mem_addr_2)?;
+        self.set_register(dr, val);
+        self.set_cond(dr);
+        Ok(())
     }
 
     // LDR (Load Base+Offset)
     // 15-12: 0110, 11-9: DR, 8-6: BaseR, 5-0: Offset6
     // DR = mem[BaseR + sign_ext(Offset6)], set cond codes
-    fn ldr(&mut self, full_instruction: u16) {
+    fn ldr(&mut self, full_instruction: u16) -> Result<(), Fault> {
         let base_r = ((full_instruction >> 6) & 0x7) as u8;
-        // add as usize to avoid overflow, so we can detect if mem loc is out of bounds
-        let mem_addr = sign_extend(full_instruction & 0x3f, 6) as usize
-            + self.registers.get_val(base_r) as usize;
+        let mem_addr = self.base_relative(full_instruction, base_r);
         let dr: u8 = ((full_instruction >> 9) & 0x7) as u8;
-        self.registers
-            .update_register(dr, self.read_memory(mem_addr).unwrap());
-        self.registers.update_cond_register(dr)
+        let val = self.user_load(mem_addr)?;
+        self.set_register(dr, val);
+        self.set_cond(dr);
+        Ok(())
     }
 
     // LEA (Load Effective Address)
     // 15-12: 1110, 11-9: DR, 8-0: PCoffset9
     // DR = PC + sign_ext(PCOffset9), set cond codes
     fn lea(&mut self, full_instruction: u16) {
-        // TODO: what do we do about out of bounds here?
-        let new_addr = sign_extend(full_instruction & 0x1ff, 9) + self.registers.get_val(PC_REG);
+        let new_addr = self.pc_relative(full_instruction);
         let dr: u8 = ((full_instruction >> 9) & 0x7) as u8;
-        self.registers.update_register(dr, new_addr);
-        self.registers.update_cond_register(dr)
+        self.set_register(dr, new_addr);
+        self.set_cond(dr)
     }
 
     // NOT
@@ -245,85 +749,324 @@ impl VM {
     fn not(&mut self, full_instruction: u16) {
         let dr: u8 = ((full_instruction >> 9) & 0x7) as u8;
         let sr: u8 = ((full_instruction >> 6) & 0x7) as u8;
-        self.registers
-            .update_register(dr, !(self.registers.get_val(sr)));
-        self.registers.update_cond_register(dr)
+        self.set_register(dr, !(self.registers.get_val(sr)));
+        self.set_cond(dr)
     }
 
-    fn res(&mut self, _full_instruction: u16) {
-        panic!("ERROR: unused opcode for RES")
+    fn res(&mut self, full_instruction: u16) -> Result<(), Fault> {
+        Err(Fault::IllegalOpcode {
+            pc: self.current_pc(),
+            instruction: full_instruction,
+        })
     }
 
     // RTI command is only available for a processor "Supervisor mode"
-    fn rti(&mut self, _full_instruction: u16) {
-        panic!("ERROR: must be in Supervisor mode for RTI")
+    fn rti(&mut self, _full_instruction: u16) -> Result<(), Fault> {
+        Err(Fault::PrivilegeViolation {
+            pc: self.current_pc(),
+        })
     }
 
     // ST (Store)
     // 15-12: 0011, 11-9: SR, 8-0: PCOffset9
     // mem[PC + sign_ext(PCOffset9)] = SR
-    fn st(&mut self, full_instruction: u16) {
+    fn st(&mut self, full_instruction: u16) -> Result<(), Fault> {
         let sr: u8 = ((full_instruction >> 9) & 0x7) as u8;
-        // add as usize to avoid overflow, so we can detect if mem loc is out of bounds
-        let new_addr = sign_extend(full_instruction & 0x1ff, 9) as usize
-            + self.registers.get_val(PC_REG) as usize;
-        self.write_memory(new_addr, self.registers.get_val(sr))
+        let new_addr = self.pc_relative(full_instruction);
+        self.user_store(new_addr, self.registers.get_val(sr))
     }
 
     // STI (Store Indirect)
     // 15-12: 1011, 11-9: SR, 8-0: PCOffset9
     // mem[mem[PC + sign_ext(PCOffset9)]] = SR;
-    fn sti(&mut self, full_instruction: u16) {
+    fn sti(&mut self, full_instruction: u16) -> Result<(), Fault> {
         let sr: u8 = ((full_instruction >> 9) & 0x7) as u8;
-        // add as usize to avoid overflow, so we can detect if mem loc is out of bounds
-        let mem_addr_1 = sign_extend(full_instruction & 0x1ff, 9) as usize
-            + self.registers.get_val(PC_REG) as usize;
-        self.write_memory(
-            self.read_memory(mem_addr_1).unwrap() as usize,
-            self.registers.get_val(sr),
-        )
+        let mem_addr_1 = self.pc_relative(full_instruction);
+        let mem_addr_2 = self.user_load(mem_addr_1)?;
+        self.user_store(mem_addr_2, self.registers.get_val(sr))
     }
 
     // STR (Store Base + Offset)
     // 15-12: 1011, 11-9: SR, 8-6: BaseR, 5-0: Offset6
     // mem[BaseR + sign_ext(Offset6)] = SR;
-    fn str(&mut self, full_instruction: u16) {
+    fn str(&mut self, full_instruction: u16) -> Result<(), Fault> {
         let sr: u8 = ((full_instruction >> 9) & 0x7) as u8;
         let base_r: u8 = ((full_instruction >> 6) & 0x7) as u8;
-        // add as usize to avoid overflow, so we can detect if mem loc is out of bounds
-        let mem_addr = sign_extend(full_instruction & 0x3f, 6) as usize
-            + self.registers.get_val(base_r) as usize;
-        self.write_memory(mem_addr, self.registers.get_val(sr))
+        let mem_addr = self.base_relative(full_instruction, base_r);
+        self.user_store(mem_addr, self.registers.get_val(sr))
+    }
+
+    // next input character, characters given back by step_back come first
+    fn read_input(&mut self) -> u16 {
+        if let Some(c) = self.history.take_replay() {
+            self.inputs_taken += 1;
+            self.history.record_input(c);
+            return c;
+        }
+        let now = self.steps;
+        let scripted = self
+            .input
+            .as_mut()
+            .map(|input| (input.take(now), input.at_end));
+        let c = match scripted {
+            Some((Some(c), _)) => {
+                self.inputs_taken += 1;
+                u16::from(c)
+            }
+            // the end of a script takes nothing
+            Some((None, AtEnd::Eof(value))) => value,
+            Some((None, AtEnd::Stop)) => 0,
+            Some((None, AtEnd::Block)) | None => {
+                let key = match &mut self.keyboard {
+                    Some(keyboard) => keyboard.read(),
+                    None => read_char(),
+                };
+                // the end of STDIN reads as 0 and takes nothing
+                let Some(key) = key else {
+                    self.stdin_ended = true;
+                    self.history.record_input(0);
+                    return 0;
+                };
+                self.inputs_taken += 1;
+                if let Some(input) = &mut self.input {
+                    input.consumed.push((now, key));
+                }
+                u16::from(key)
+            }
+        };
+        self.history.record_input(c);
+        c
+    }
+
+    // a word of memory or a keyboard register
+    fn read_word(&mut self, addr: u16) -> u16 {
+        match addr {
+            KBSR => {
+                let ready = self.key_ready();
+                if !ready && self.idle() {
+                    if let Some(keyboard) = self.live_keyboard() {
+                        keyboard.wait();
+                        return u16::from(self.key_ready()) << 15;
+                    }
+                }
+                u16::from(ready) << 15
+            }
+            KBDR => {
+                if self.key_ready() {
+                    self.kbdr = self.read_input();
+                }
+                self.kbdr
+            }
+            _ => self.memory[addr as usize],
+        }
+    }
+
+    fn write_output(&mut self, bytes: &[u8]) {
+        // a program printing while it polls is not idle
+        self.idle = None;
+        match &mut self.output {
+            Some(output) => output.extend_from_slice(bytes),
+            None => write_bytes(bytes),
+        }
+    }
+
+    // data read made by a user program instruction
+    fn user_load(&mut self, addr: u16) -> Result<u16, Fault> {
+        match user_accessible(addr) {
+            true => Ok(self.load(addr)),
+            false => Err(Fault::AccessViolation {
+                pc: self.current_pc(),
+                addr,
+            }),
+        }
+    }
+
+    fn user_store(&mut self, addr: u16, val: u16) -> Result<(), Fault> {
+        if !user_accessible(addr) {
+            return Err(Fault::AccessViolation {
+                pc: self.current_pc(),
+                addr,
+            });
+        }
+        self.store(addr, val);
+        Ok(())
+    }
+
+    // data read made by an instruction or a service routine, which runs
+    // privileged, checked against the watchpoints
+    fn load(&mut self, addr: u16) -> u16 {
+        let val = self.read_word(addr);
+        self.history.record_read(addr);
+        if self.tracing {
+            self.traced_reads.push((addr, val));
+        }
+        if !self.observers.is_empty() {
+            self.notify(|o| o.on_memory_read(addr, val));
+        }
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(addr, Access::Read, val, val);
+        }
+        val
+    }
+
+    // data write made by an instruction, checked against the watchpoints
+    fn store(&mut self, addr: u16, val: u16) {
+        let old = self.memory[addr as usize];
+        self.memory_writes += 1;
+        self.history.record_write(addr, old, val);
+        if self.tracing {
+            self.traced_writes.push((addr, val));
+        }
+        if !self.observers.is_empty() {
+            self.notify(|o| o.on_memory_write(addr, old, val));
+        }
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(addr, Access::Write, old, val);
+        }
+        if self.icache.contains(addr) {
+            self.icache.record_write(self.current_pc(), addr);
+        }
+        self.write_memory(addr as usize, val);
+    }
+
+    // remembers the first watchpoint hit of the instruction for run()
+    fn check_watchpoints(&mut self, addr: u16, access: Access, old: u16, new: u16) {
+        if self.watch_hit.is_some() {
+            return;
+        }
+        let index = self
+            .watchpoints
+            .iter()
+            .position(|w| w.matches(addr, access, new));
+        if let Some(index) = index {
+            self.watch_hit = Some(WatchHit {
+                index,
+                pc: self.current_pc(),
+                addr,
+                access,
+                old,
+                new,
+            });
+        }
+    }
+
+    // PC + sign_ext(PCoffset9), wrapping around the address space
+    fn pc_relative(&self, full_instruction: u16) -> u16 {
+        self.registers
+            .get_val(PC_REG)
+            .wrapping_add(sign_extend(full_instruction & 0x1ff, 9))
+    }
+
+    // BaseR + sign_ext(offset6), wrapping around the address space
+    fn base_relative(&self, full_instruction: u16, base_r: u8) -> u16 {
+        self.registers
+            .get_val(base_r)
+            .wrapping_add(sign_extend(full_instruction & 0x3f, 6))
     }
 
     // TRAP (System Call)
     // 15-12: 1111, 11-8: 0000, 7-0: trapvect8
     // Mem locations x0000 -> 0x00FF are available to contain
-    // starting addrs for system calls specified by their trap vectors
-    fn trap(&mut self, full_instruction: u16) {
-        // save PC
-        self.registers
-            .update_register(7, self.registers.get_val(PC_REG));
+    // starting addrs for system calls specified by their trap vectors.
+    // The standard service routines are performed natively here rather
+    // than by jumping into an OS image
+    fn trap(&mut self, full_instruction: u16) -> Result<(), Fault> {
         let mem_loc = full_instruction & 0xFF;
+        let pc = self.current_pc();
+        // a vector with no service routine faults before R7 is touched
+        if !(0x20..=0x25).contains(&mem_loc) {
+            return Err(Fault::UnknownTrap {
+                pc,
+                vector: mem_loc as u8,
+            });
+        }
+        // save PC
+        self.set_register(7, self.registers.get_val(PC_REG));
+        if !self.observers.is_empty() {
+            self.notify(|o| o.on_trap_entry(pc, mem_loc as u8));
+        }
         match mem_loc {
             // GETC
-            0x20 => todo!(),
+            0x20 => {
+                let c = self.read_input();
+                self.set_register(0, c);
+                self.set_cond(0);
+            }
             // OUT
-            0x21 => todo!(),
-            // PUTS
-            0x22 => todo!(),
-            // IN
-            0x23 => todo!(),
-            // PUTSP
-            0x24 => todo!(),
+            0x21 => {
+                let c = self.registers.get_val(0) as u8;
+                self.write_output(&[c]);
+            }
+            // PUTS: one char per word until a zero word
+            0x22 => {
+                let mut addr = self.registers.get_val(0);
+                let mut out = Vec::new();
+                loop {
+                    let word = self.load(addr);
+                    if word == 0 {
+                        break;
+                    }
+                    out.push(word as u8);
+                    addr = addr.wrapping_add(1);
+                }
+                self.write_output(&out);
+            }
+            // IN: prompt and echo the character read
+            0x23 => {
+                self.write_output(b"Enter a character: ");
+                let c = self.read_input();
+                self.write_output(&[c as u8]);
+                self.set_register(0, c);
+                self.set_cond(0);
+            }
+            // PUTSP: two chars per word, low byte first, until a zero word
+            0x24 => {
+                let mut addr = self.registers.get_val(0);
+                let mut out = Vec::new();
+                loop {
+                    let word = self.load(addr);
+                    if word == 0 {
+                        break;
+                    }
+                    out.push((word & 0xFF) as u8);
+                    if word >> 8 != 0 {
+                        out.push((word >> 8) as u8);
+                    }
+                    addr = addr.wrapping_add(1);
+                }
+                self.write_output(&out);
+            }
             // HALT
             0x25 => {
-                println!("HALT detected");
                 io::stdout().flush().expect("Failed to flush STDOUT");
-                exit(1);
+                self.halted = true;
             }
-            _ => panic!("ERROR: TRAP NOT FOUND"),
+            _ => unreachable!(),
         }
+        if !self.observers.is_empty() {
+            self.notify(|o| o.on_trap_exit(pc, mem_loc as u8));
+            if self.halted {
+                // step() counts the HALT once it returns
+                let steps = self.steps + 1;
+                self.notify(|o| o.on_halt(pc, steps));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_bytes(bytes: &[u8]) {
+    let mut stdout = io::stdout();
+    stdout.write_all(bytes).expect("Failed to write STDOUT");
+    stdout.flush().expect("Failed to flush STDOUT");
+}
+
+// a single byte from STDIN, None at its end
+fn read_char() -> Option<u8> {
+    let mut buf = [0u8; 1];
+    match io::stdin().read(&mut buf) {
+        Ok(1) => Some(buf[0]),
+        _ => None,
     }
 }
 
@@ -399,6 +1142,15 @@ mod tests {
         // instr: 0b0000_0_0_1_011111111
         vm.br(0b0000001011111111);
         assert_eq!(vm.registers.get_val(PC_REG), 255 + PC_START);
+
+        // ADD R0 R0 -2 - set N cond flag
+        vm.add(0b0001000000111110);
+        // BRz 1 - doesn't branch
+        vm.br(0b0000010000000001);
+        assert_eq!(vm.registers.get_val(PC_REG), 255 + PC_START);
+        // BRn 1 - branches
+        vm.br(0b0000100000000001);
+        assert_eq!(vm.registers.get_val(PC_REG), 256 + PC_START);
     }
 
     #[test]
@@ -442,10 +1194,10 @@ mod tests {
         assert_eq!(vm.registers.get_val(6), 3);
         // ST R6 mem[PC + 1]
         // instr: 0b0011_110_000000001
-        vm.st(0b0011110000000001);
+        vm.st(0b0011110000000001).unwrap();
         // LD R5 mem[PC + 1]
         // instr: 0b0010_101_000000001
-        vm.ld(0b0010101000000001);
+        vm.ld(0b0010101000000001).unwrap();
         assert_eq!(vm.registers.get_val(5), 3);
     }
 
@@ -498,4 +1250,424 @@ mod tests {
         // Mem locations x0000 -> 0x00FF are available to contain
         // starting addrs for system calls specified by their trap vectors
     }
+
+    #[test]
+    fn test_execute_program() {
+        let mut vm = VM::new();
+        // AND R0 R0 0; ADD R0 R0 5; LOOP ADD R0 R0 -1; BRp LOOP; HALT
+        vm.load_image(&Image::new(
+            PC_START,
+            vec![0x5020, 0x1025, 0x103F, 0x03FE, 0xF025],
+        ));
+        assert_eq!(vm.execute_program(), Ok(()));
+        assert!(vm.halted);
+        assert_eq!(vm.registers.get_val(0), 0);
+        assert_eq!(vm.registers.get_val(PC_REG), PC_START + 5);
+    }
+
+    #[test]
+    fn test_fault() {
+        let mut vm = VM::new();
+        // ADD R0 R0 1; RES
+        vm.load_image(&Image::new(PC_START, vec![0x1021, 0xD000]));
+        vm.symbols.insert("START", PC_START);
+        let fault = vm.execute_program().unwrap_err();
+        assert_eq!(
+            fault,
+            Fault::IllegalOpcode {
+                pc: PC_START + 1,
+                instruction: 0xD000
+            }
+        );
+        assert_eq!(
+            fault.describe(&vm.symbols),
+            "illegal opcode xD000: fault at START+1 (x3001)"
+        );
+
+        // an unknown trap leaves R7 and PC as they were
+        let mut vm = VM::new();
+        vm.load_image(&Image::new(PC_START, vec![0xF0FF]));
+        vm.registers.update_register(7, 0x1234);
+        assert_eq!(
+            vm.step(),
+            Err(Fault::UnknownTrap {
+                pc: PC_START,
+                vector: 0xFF
+            })
+        );
+        assert_eq!(vm.registers.get_val(7), 0x1234);
+        assert_eq!(vm.registers.get_val(PC_REG), PC_START);
+
+        // LDI R0 PTR; HALT; PTR .FILL x0100
+        let mut vm = VM::new();
+        vm.load_image(&Image::new(PC_START, vec![0xA001, 0xF025, 0x0100]));
+        let fault = Fault::AccessViolation {
+            pc: PC_START,
+            addr: 0x0100,
+        };
+        assert_eq!(vm.step(), Err(fault));
+        assert_eq!(vm.registers.get_val(0), 0);
+        assert_eq!(vm.registers.get_val(PC_REG), PC_START);
+        assert_eq!(
+            fault.describe(&vm.symbols),
+            "access control violation at x0100: fault at x3000"
+        );
+
+        // ST R0 #-2 below user memory
+        let mut vm = VM::new();
+        vm.load_image(&Image::new(PC_START, vec![0x31FE]));
+        assert_eq!(
+            vm.step(),
+            Err(Fault::AccessViolation {
+                pc: PC_START,
+                addr: 0x2FFF
+            })
+        );
+        assert_eq!(vm.memory[0x2FFF], 0);
+
+        // AND R0 R0 0; BRnzp #1 over the HALT, into memory nothing was loaded into
+        let mut vm = VM::new();
+        vm.load_image(&Image::new(PC_START, vec![0x5020, 0x0E01, 0xF025]));
+        assert_eq!(
+            vm.execute_program(),
+            Err(Fault::RunawayPc { pc: PC_START + 3 })
+        );
+    }
+
+    #[test]
+    fn test_watchpoint() {
+        use crate::hw::watch::{CmpOp, WatchKind};
+
+        let mut vm = VM::new();
+        // LOOP ADD R0 R0 1; STR R0 R1 #0; BRnzp LOOP
+        vm.load_image(&Image::new(PC_START, vec![0x1021, 0x7040, 0x0FFD]));
+        vm.registers.update_register(1, 0x4000);
+        vm.watchpoints.push(Watchpoint {
+            start: 0x4000,
+            end: 0x4000,
+            kind: WatchKind::Write,
+            condition: Some((CmpOp::Eq, 3)),
+        });
+
+        let stop = vm.run(Some(100));
+        let StopReason::Watchpoint(hit) = stop else {
+            panic!("expected a watchpoint, got {:?}", stop)
+        };
+        assert_eq!(hit.pc, PC_START + 1);
+        assert_eq!((hit.access, hit.old, hit.new), (Access::Write, 2, 3));
+        // the store has completed and PC is past it
+        assert_eq!(vm.memory[0x4000], 3);
+        assert_eq!(vm.registers.get_val(PC_REG), PC_START + 2);
+    }
+
+    #[test]
+    fn test_step_back() {
+        use crate::hw::history::History;
+        use crate::hw::watch::WatchKind;
+
+        let mut vm = VM::new();
+        vm.history = History::with_limit(3);
+        // LOOP ADD R0 R0 1; STR R0 R1 #0; BRnzp LOOP
+        vm.load_image(&Image::new(PC_START, vec![0x1021, 0x7040, 0x0FFD]));
+        vm.registers.update_register(1, 0x4000);
+        vm.run(Some(6));
+        assert_eq!(vm.memory[0x4000], 2);
+
+        // only the last 3 instructions can be undone
+        assert!(vm.step_back());
+        assert!(vm.step_back());
+        assert_eq!(vm.memory[0x4000], 1);
+        assert_eq!(vm.registers.get_val(0), 2);
+        assert!(vm.step_back());
+        assert!(!vm.step_back());
+        assert_eq!(vm.steps, 3);
+        assert_eq!(vm.memory_writes, 1);
+
+        vm.run(Some(6));
+        vm.watchpoints.push(Watchpoint {
+            start: 0x4000,
+            end: 0x4000,
+            kind: WatchKind::Write,
+            condition: None,
+        });
+        let StopReason::Watchpoint(hit) = vm.run_back(None) else {
+            panic!("expected a watchpoint")
+        };
+        assert_eq!((hit.old, hit.new), (2, 3));
+        assert_eq!(vm.memory[0x4000], 2);
+        assert_eq!(vm.registers.get_val(PC_REG), PC_START + 1);
+
+        // GETC; GETC; HALT, the undone character is taken again
+        let mut vm = VM::new();
+        vm.history = History::with_limit(3);
+        vm.input = Some(b"ab".to_vec().into());
+        vm.load_image(&Image::new(PC_START, vec![0xF020, 0xF020, 0xF025]));
+        vm.run(Some(2));
+        assert_eq!(vm.inputs_taken, 2);
+        assert!(vm.step_back());
+        assert_eq!(vm.inputs_taken, 1);
+        vm.step().unwrap();
+        assert_eq!(vm.registers.get_val(0), u16::from(b'b'));
+        assert_eq!(vm.inputs_taken, 2);
+    }
+
+    // a writer the test can still read after handing it to the tracer
+    #[derive(Clone, Default)]
+    struct SharedBuffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_trace() {
+        use super::super::trace::{TraceFilter, TraceFormat};
+
+        // AND R0 R0 0; ADD R0 R0 1; STR R0 R1 #0; HALT
+        let program = vec![0x5020, 0x1021, 0x7040, 0xF025];
+        let run = |filter: TraceFilter| {
+            let buffer = SharedBuffer::default();
+            let mut vm = VM::new();
+            vm.load_image(&Image::new(PC_START, program.clone()));
+            vm.registers.update_register(1, 0x4000);
+            vm.tracer =
+                Some(Tracer::new(Box::new(buffer.clone()), TraceFormat::Jsonl).with_filter(filter));
+            vm.execute_program().unwrap();
+            vm.tracer.as_mut().unwrap().finish().unwrap();
+            let text = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+            text.lines()
+                .map(|l| TraceRecord::from_json(&serde_json::from_str(l).unwrap()).unwrap())
+                .collect::<Vec<TraceRecord>>()
+        };
+
+        let records = run(TraceFilter::default());
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[1].registers, vec![(0, 1)]);
+        assert_eq!(records[2].writes, vec![(0x4000, 1)]);
+        assert_eq!(records[3].asm, "HALT");
+
+        let records = run(TraceFilter {
+            kinds: TraceFilter::parse_kinds("store").unwrap(),
+            ..TraceFilter::default()
+        });
+        assert_eq!(records.len(), 1);
+        assert_eq!((records[0].step, records[0].pc), (3, PC_START + 2));
+    }
+
+    #[test]
+    fn test_observer() {
+        use std::sync::{Arc, Mutex};
+
+        #[derive(Default)]
+        struct Events(Vec<String>);
+
+        impl Observer for Events {
+            fn on_fetch(&mut self, pc: u16, _instruction: u16) {
+                self.0.push(format!("fetch {:04X}", pc));
+            }
+
+            fn on_register_write(&mut self, register: u8, _old: u16, new: u16) {
+                if register != PC_REG {
+                    self.0.push(format!("R{}={}", register, new));
+                }
+            }
+
+            fn on_memory_write(&mut self, addr: u16, old: u16, new: u16) {
+                self.0.push(format!("[{:04X}] {}->{}", addr, old, new));
+            }
+
+            fn on_trap_entry(&mut self, _pc: u16, vector: u8) {
+                self.0.push(format!("trap {:02X}", vector));
+            }
+
+            fn on_trap_exit(&mut self, _pc: u16, vector: u8) {
+                self.0.push(format!("trap {:02X} done", vector));
+            }
+
+            fn on_exception(&mut self, fault: &Fault) {
+                self.0.push(format!("fault {:04X}", fault.pc()));
+            }
+
+            fn on_halt(&mut self, pc: u16, steps: u64) {
+                self.0.push(format!("halt {:04X} {}", pc, steps));
+            }
+        }
+
+        // ADD R0 R0 1; STR R0 R1 #0; HALT
+        let mut vm = VM::new();
+        vm.load_image(&Image::new(PC_START, vec![0x1021, 0x7040, 0xF025]));
+        vm.registers.update_register(1, 0x4000);
+        let events = Arc::new(Mutex::new(Events::default()));
+        vm.add_observer(events.clone());
+        vm.execute_program().unwrap();
+        assert_eq!(
+            events.lock().unwrap().0,
+            vec![
+                "fetch 3000",
+                "R0=1",
+                "R9=1",
+                "fetch 3001",
+                "[4000] 0->1",
+                "fetch 3002",
+                "R7=12291",
+                "trap 25",
+                "trap 25 done",
+                "halt 3002 3",
+            ]
+        );
+
+        // an unknown trap faults without entering or touching R7
+        events.lock().unwrap().0.clear();
+        vm.load_image(&Image::new(PC_START, vec![0xF0FF]));
+        vm.halted = false;
+        assert!(vm.step().is_err());
+        assert_eq!(events.lock().unwrap().0, vec!["fetch 3000", "fault 3000"]);
+    }
+
+    #[test]
+    fn test_self_modifying_write() {
+        // PATCH is rewritten each time round the loop after it has run, and
+        // the second pass runs the new instruction
+        let source = "        .ORIG x3000
+        AND R6, R6, #0
+LOOP    JSR SUB
+PATCH   ADD R6, R6, #1
+        LD R0, NEWOP
+        ST R0, PATCH
+        ADD R7, R6, #-4
+        BRn LOOP
+        HALT
+SUB     ADD R6, R6, #2
+        RET
+NEWOP   .FILL x1DBF
+        .END";
+        let assembly = crate::asm::assemble(source).unwrap();
+        let mut vm = VM::new();
+        vm.load_image(&assembly.image);
+        vm.execute_program().unwrap();
+
+        assert_eq!(vm.registers.get_val(6), 4);
+        let patch = assembly.symbols.lookup("PATCH").unwrap();
+        assert_eq!(vm.icache.writes, BTreeMap::from([((patch + 2, patch), 2)]));
+        assert_eq!(
+            vm.icache.describe_writes(&assembly.symbols),
+            vec!["PATCH+2 (x3004) modified the instruction at PATCH (x3002) (2 times)"]
+        );
+    }
+
+    #[test]
+    fn test_input_script() {
+        // GETC; ADD R1 R1 1; BRnzp -3
+        let image = Image::new(PC_START, vec![0xF020, 0x1261, 0x0FFD]);
+        let mut vm = VM::new();
+        vm.load_image(&image);
+        let mut input = InputScript::parse("0 a\n10 b\n").unwrap();
+        input.at_end = AtEnd::Stop;
+        vm.input = Some(input);
+        assert_eq!(vm.run(Some(100)), StopReason::EndOfInput(PC_START));
+        assert_eq!(vm.registers.get_val(1), 2);
+        // the second key is taken early, as if GETC waited for it
+        let input = vm.input.as_ref().unwrap();
+        assert_eq!(input.consumed, vec![(0, b'a'), (3, b'b')]);
+        assert_eq!(input.consumed_script(), "0 a\n3 b\n");
+
+        let mut vm = VM::new();
+        vm.load_image(&image);
+        let mut input = InputScript::default();
+        input.at_end = AtEnd::Eof(0xFFFF);
+        vm.input = Some(input);
+        assert_eq!(vm.run(Some(4)), StopReason::StepLimit);
+        assert_eq!(vm.registers.get_val(0), 0xFFFF);
+    }
+
+    #[test]
+    fn test_keyboard_registers() {
+        let source = ".ORIG x3000
+POLL    LDI R1, KBSRP
+        BRzp POLL
+        LDI R0, KBDRP
+        LDI R2, KBDRP
+        HALT
+KBSRP   .FILL xFE00
+KBDRP   .FILL xFE02
+        .END";
+        let assembly = crate::asm::assemble(source).unwrap();
+        let mut vm = VM::new();
+        vm.load_image(&assembly.image);
+        vm.input = Some(InputScript::parse("100 x").unwrap());
+        // a key still to come keeps the polling loop from counting as one
+        // the program can never leave
+        vm.loop_detector = Some(LoopDetector::default());
+        assert_eq!(vm.run(Some(1000)), StopReason::Halted);
+        assert_eq!(vm.registers.get_val(0), u16::from(b'x'));
+        // KBDR keeps the last key once there are no more
+        assert_eq!(vm.registers.get_val(2), u16::from(b'x'));
+        let input = vm.input.as_ref().unwrap();
+        assert!(input.consumed[0].0 >= 100);
+        assert!(vm.steps > 100);
+
+        // undoing a KBDR read gives back the key it held before
+        let mut vm = VM::new();
+        vm.history = History::with_limit(10);
+        vm.load_image(&assembly.image);
+        vm.input = Some(b"ab".to_vec().into());
+        vm.run(None);
+        assert_eq!(vm.registers.get_val(2), u16::from(b'b'));
+        assert!(vm.step_back());
+        assert!(vm.step_back());
+        assert_eq!(vm.kbdr, u16::from(b'a'));
+        assert!(vm.step_back());
+        assert_eq!(vm.kbdr, 0);
+    }
+
+    #[test]
+    fn test_idle_polling() {
+        // a key typed a while after the program starts polling for it
+        struct Typist(bool);
+
+        impl Read for Typist {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                if std::mem::replace(&mut self.0, true) {
+                    return Ok(0);
+                }
+                std::thread::sleep(std::time::Duration::from_millis(100));
+                buf[0] = b'x';
+                Ok(1)
+            }
+        }
+
+        let source = ".ORIG x3000
+POLL    LDI R1, KBSRP
+        BRzp POLL
+        LDI R0, KBDRP
+        HALT
+KBSRP   .FILL xFE00
+KBDRP   .FILL xFE02
+        .END";
+        let assembly = crate::asm::assemble(source).unwrap();
+        let mut vm = VM::new();
+        vm.load_image(&assembly.image);
+        vm.keyboard = Some(Keyboard::spawn(Typist(false), false));
+        assert_eq!(vm.run(Some(1_000_000)), StopReason::Halted);
+        assert_eq!(vm.registers.get_val(0), u16::from(b'x'));
+        // the host waited for the key rather than running the loop meanwhile
+        assert!(vm.steps < 100, "{} steps", vm.steps);
+
+        // undoing a KBSR read that found no key forgets the state it saw
+        let mut vm = VM::new();
+        vm.history = History::with_limit(10);
+        vm.load_image(&assembly.image);
+        let mut input = InputScript::default();
+        input.at_end = AtEnd::Stop;
+        vm.input = Some(input);
+        vm.step().unwrap();
+        assert!(vm.idle.is_some());
+        assert!(vm.step_back());
+        assert_eq!(vm.idle, None);
+    }
 }
@@ -0,0 +1,167 @@
+use crate::symbols::SymbolTable;
+
+// Which accesses a watchpoint stops on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access, /* read or write */
+}
+
+// A single data access made by an instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl CmpOp {
+    pub fn from_symbol(op: &str) -> Option<Self> {
+        match op {
+            "==" => Some(CmpOp::Eq),
+            "!=" => Some(CmpOp::Ne),
+            "<" => Some(CmpOp::Lt),
+            "<=" => Some(CmpOp::Le),
+            ">" => Some(CmpOp::Gt),
+            ">=" => Some(CmpOp::Ge),
+            _ => None,
+        }
+    }
+
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            CmpOp::Eq => "==",
+            CmpOp::Ne => "!=",
+            CmpOp::Lt => "<",
+            CmpOp::Le => "<=",
+            CmpOp::Gt => ">",
+            CmpOp::Ge => ">=",
+        }
+    }
+
+    // values compare as unsigned words
+    pub fn apply(&self, left: u16, right: u16) -> bool {
+        match self {
+            CmpOp::Eq => left == right,
+            CmpOp::Ne => left != right,
+            CmpOp::Lt => left < right,
+            CmpOp::Le => left <= right,
+            CmpOp::Gt => left > right,
+            CmpOp::Ge => left >= right,
+        }
+    }
+}
+
+// Stop when a word in start..=end is accessed, optionally only when the
+// value read or written satisfies `value OP operand`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub start: u16,
+    pub end: u16,
+    pub kind: WatchKind,
+    pub condition: Option<(CmpOp, u16)>,
+}
+
+impl Watchpoint {
+    pub fn matches(&self, addr: u16, access: Access, value: u16) -> bool {
+        let kind_matches = matches!(
+            (self.kind, access),
+            (WatchKind::Access, _)
+                | (WatchKind::Read, Access::Read)
+                | (WatchKind::Write, Access::Write)
+        );
+        kind_matches
+            && self.covers(addr)
+            && self
+                .condition
+                .is_none_or(|(op, operand)| op.apply(value, operand))
+    }
+
+    // whether addr is in start..=end, a range that runs past xFFFF wraps
+    // round to x0000
+    pub fn covers(&self, addr: u16) -> bool {
+        addr.wrapping_sub(self.start) <= self.end.wrapping_sub(self.start)
+    }
+
+    pub fn describe(&self, symbols: &SymbolTable) -> String {
+        let kind = match self.kind {
+            WatchKind::Read => "read",
+            WatchKind::Write => "write",
+            WatchKind::Access => "access",
+        };
+        let mut text = format!("{} {}", kind, symbols.format_addr(self.start));
+        if self.end != self.start {
+            text.push_str(&format!(" to {}", symbols.format_addr(self.end)));
+        }
+        if let Some((op, operand)) = self.condition {
+            text.push_str(&format!(" if value {} x{:04X}", op.symbol(), operand));
+        }
+        text
+    }
+}
+
+// Details of the access that triggered a watchpoint, index is the position
+// in VM::watchpoints, for a read old and new are both the value read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    pub index: usize,
+    pub pc: u16,
+    pub addr: u16,
+    pub access: Access,
+    pub old: u16,
+    pub new: u16,
+}
+
+impl WatchHit {
+    pub fn describe(&self, symbols: &SymbolTable) -> String {
+        match self.access {
+            Access::Read => format!(
+                "watchpoint {}: read of {} by {}, value x{:04X}",
+                self.index + 1,
+                symbols.format_addr(self.addr),
+                symbols.format_addr(self.pc),
+                self.new
+            ),
+            Access::Write => format!(
+                "watchpoint {}: write to {} by {}, x{:04X} -> x{:04X}",
+                self.index + 1,
+                symbols.format_addr(self.addr),
+                symbols.format_addr(self.pc),
+                self.old,
+                self.new
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrapping_range() {
+        let watch = Watchpoint {
+            start: 0xFFF0,
+            end: 0x000F,
+            kind: WatchKind::Write,
+            condition: None,
+        };
+        assert!(watch.matches(0xFFF0, Access::Write, 0));
+        assert!(watch.matches(0xFFFF, Access::Write, 0));
+        assert!(watch.matches(0x0000, Access::Write, 0));
+        assert!(watch.matches(0x000F, Access::Write, 0));
+        assert!(!watch.matches(0x0010, Access::Write, 0));
+        assert!(!watch.matches(0xFFEF, Access::Write, 0));
+        assert!(!watch.matches(0x0000, Access::Read, 0));
+    }
+}
@@ -0,0 +1,25 @@
+use std::io;
+
+use super::{parse_text_lines, Image};
+
+// binary text layout (lc3convert .bin): one 16 digit binary string per line,
+// origin first
+pub fn parse(bytes: &[u8]) -> io::Result<Image> {
+    parse_text_lines(bytes, parse_bin_word)
+}
+
+pub fn write(image: &Image) -> io::Result<Vec<u8>> {
+    let segment = image.single_segment()?;
+    let mut out = format!("{:016b}\n", segment.origin);
+    for word in &segment.words {
+        out.push_str(&format!("{:016b}\n", word));
+    }
+    Ok(out.into_bytes())
+}
+
+fn parse_bin_word(word: &str) -> Option<u16> {
+    if word.len() != 16 || !word.chars().all(|c| c == '0' || c == '1') {
+        return None;
+    }
+    u16::from_str_radix(word, 2).ok()
+}
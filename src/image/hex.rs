@@ -0,0 +1,17 @@
+use std::io;
+
+use super::{parse_hex_word, parse_text_lines, Image};
+
+// hex text layout: one word per line as four hex digits, origin first
+pub fn parse(bytes: &[u8]) -> io::Result<Image> {
+    parse_text_lines(bytes, parse_hex_word)
+}
+
+pub fn write(image: &Image) -> io::Result<Vec<u8>> {
+    let segment = image.single_segment()?;
+    let mut out = format!("{:04X}\n", segment.origin);
+    for word in &segment.words {
+        out.push_str(&format!("{:04X}\n", word));
+    }
+    Ok(out.into_bytes())
+}
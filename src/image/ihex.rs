@@ -0,0 +1,230 @@
+use std::collections::BTreeMap;
+use std::io;
+
+use super::{invalid_data, strip_comment, Image, Segment};
+
+const REC_DATA: u8 = 0x00;
+const REC_EOF: u8 = 0x01;
+const REC_EXT_SEGMENT: u8 = 0x02;
+const REC_EXT_LINEAR: u8 = 0x04;
+const BYTES_PER_RECORD: usize = 16;
+
+// Intel HEX layout: LC-3 word at address A is stored big-endian in the
+// bytes at 2A and 2A + 1, so the upper half of memory needs an extended
+// linear address record
+pub fn parse(bytes: &[u8]) -> io::Result<Image> {
+    let text = std::str::from_utf8(bytes).map_err(|_| invalid_data("image is not valid text"))?;
+
+    let mut data: BTreeMap<u32, u8> = BTreeMap::new();
+    let mut base: u32 = 0;
+    let mut seen_eof = false;
+
+    for (n, line) in text.lines().enumerate() {
+        let line = strip_comment(line);
+        if line.is_empty() {
+            continue;
+        }
+        if seen_eof {
+            return Err(invalid_data(format!("line {}: record after EOF", n + 1)));
+        }
+
+        let record =
+            parse_record(line).map_err(|e| invalid_data(format!("line {}: {}", n + 1, e)))?;
+        match record.kind {
+            REC_DATA => {
+                for (i, byte) in record.data.iter().enumerate() {
+                    data.insert(base + record.offset as u32 + i as u32, *byte);
+                }
+            }
+            REC_EOF => seen_eof = true,
+            REC_EXT_SEGMENT | REC_EXT_LINEAR if record.data.len() != 2 => {
+                return Err(invalid_data(format!(
+                    "line {}: address record type {:02X} has {} data bytes, expected 2",
+                    n + 1,
+                    record.kind,
+                    record.data.len()
+                )))
+            }
+            REC_EXT_SEGMENT => {
+                base = (u16::from_be_bytes([record.data[0], record.data[1]]) as u32) << 4;
+            }
+            REC_EXT_LINEAR => {
+                base = (u16::from_be_bytes([record.data[0], record.data[1]]) as u32) << 16;
+            }
+            // start address records (03, 05) carry nothing we can use
+            0x03 | 0x05 => (),
+            kind => {
+                return Err(invalid_data(format!(
+                    "line {}: unsupported record type {:02X}",
+                    n + 1,
+                    kind
+                )))
+            }
+        }
+    }
+
+    to_image(&data)
+}
+
+pub fn write(image: &Image) -> io::Result<Vec<u8>> {
+    let mut out = String::new();
+    let mut upper: u16 = 0;
+
+    for segment in &image.segments {
+        let bytes: Vec<u8> = segment.words.iter().flat_map(|w| w.to_be_bytes()).collect();
+        let start = 2 * segment.origin as u32;
+
+        for (i, chunk) in bytes.chunks(BYTES_PER_RECORD).enumerate() {
+            let addr = start + (i * BYTES_PER_RECORD) as u32;
+            // a record may not straddle a 64K boundary, chunks are 16 byte
+            // aligned relative to an even start so split if needed
+            let split = (0x10000 - (addr & 0xFFFF)) as usize;
+            let (first, rest) = chunk.split_at(split.min(chunk.len()));
+            for (addr, part) in [(addr, first), (addr + first.len() as u32, rest)] {
+                if part.is_empty() {
+                    continue;
+                }
+                if (addr >> 16) as u16 != upper {
+                    upper = (addr >> 16) as u16;
+                    out.push_str(&record(REC_EXT_LINEAR, 0, &upper.to_be_bytes()));
+                }
+                out.push_str(&record(REC_DATA, addr as u16, part));
+            }
+        }
+    }
+    out.push_str(&record(REC_EOF, 0, &[]));
+    Ok(out.into_bytes())
+}
+
+struct Record {
+    kind: u8,
+    offset: u16,
+    data: Vec<u8>,
+}
+
+fn parse_record(line: &str) -> Result<Record, String> {
+    let digits = line
+        .strip_prefix(':')
+        .ok_or("record does not start with ':'")?;
+    if digits.len() % 2 != 0 || digits.len() < 10 {
+        return Err("record is truncated".to_string());
+    }
+
+    let raw = (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .map_err(|_| "record contains non hex digits")?;
+
+    let len = raw[0] as usize;
+    if raw.len() != len + 5 {
+        return Err(format!("record length {} does not match its data", len));
+    }
+    if raw.iter().fold(0u8, |acc, b| acc.wrapping_add(*b)) != 0 {
+        return Err("bad checksum".to_string());
+    }
+
+    Ok(Record {
+        kind: raw[3],
+        offset: u16::from_be_bytes([raw[1], raw[2]]),
+        data: raw[4..4 + len].to_vec(),
+    })
+}
+
+fn record(kind: u8, offset: u16, data: &[u8]) -> String {
+    let mut raw = vec![data.len() as u8];
+    raw.extend_from_slice(&offset.to_be_bytes());
+    raw.push(kind);
+    raw.extend_from_slice(data);
+    let checksum = raw
+        .iter()
+        .fold(0u8, |acc, b| acc.wrapping_add(*b))
+        .wrapping_neg();
+    raw.push(checksum);
+
+    let mut line = String::from(":");
+    for byte in raw {
+        line.push_str(&format!("{:02X}", byte));
+    }
+    line.push('\n');
+    line
+}
+
+// group the byte map into word segments, a word missing one of its bytes
+// is treated as zero in that byte
+fn to_image(data: &BTreeMap<u32, u8>) -> io::Result<Image> {
+    let mut segments: Vec<Segment> = Vec::new();
+
+    for (&byte_addr, _) in data.iter() {
+        let word_addr = byte_addr / 2;
+        if word_addr > u16::MAX as u32 {
+            return Err(invalid_data(format!(
+                "byte address {:#X} is outside LC-3 memory",
+                byte_addr
+            )));
+        }
+        let word_addr = word_addr as u16;
+        let word = u16::from_be_bytes([
+            *data.get(&(2 * word_addr as u32)).unwrap_or(&0),
+            *data.get(&(2 * word_addr as u32 + 1)).unwrap_or(&0),
+        ]);
+
+        match segments.last_mut() {
+            // both bytes of a word visit this loop, skip the second one
+            Some(s) if s.origin as u32 + s.words.len() as u32 == word_addr as u32 + 1 => (),
+            Some(s) if s.origin as u32 + s.words.len() as u32 == word_addr as u32 => {
+                s.words.push(word)
+            }
+            _ => segments.push(Segment {
+                origin: word_addr,
+                words: vec![word],
+            }),
+        }
+    }
+
+    if segments.is_empty() {
+        return Err(invalid_data("image contains no data records"));
+    }
+    Ok(Image { segments })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_segments_and_upper_memory() {
+        let image = Image {
+            segments: vec![
+                Segment {
+                    origin: 0x3000,
+                    words: vec![0x1234, 0x5678],
+                },
+                Segment {
+                    origin: 0xFFF0,
+                    words: (0..16).collect(),
+                },
+            ],
+        };
+        let bytes = write(&image).unwrap();
+        // x8000 and above live past the first 64K of bytes
+        assert!(std::str::from_utf8(&bytes)
+            .unwrap()
+            .contains(":020000040001F9"));
+        assert_eq!(parse(&bytes).unwrap(), image);
+    }
+
+    #[test]
+    fn test_bad_checksum() {
+        assert!(parse(b":0260000012345600\n:00000001FF\n").is_err());
+    }
+
+    #[test]
+    fn test_bad_address_record() {
+        let error = parse(b":03000004000100F8\n:00000001FF\n").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "line 1: address record type 04 has 3 data bytes, expected 2"
+        );
+    }
+}
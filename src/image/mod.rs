@@ -0,0 +1,220 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub mod bin;
+pub mod hex;
+pub mod ihex;
+pub mod obj;
+
+// On-disk representations of an LC-3 program image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Obj,      /* big-endian words, first word is the origin (lc3as .obj) */
+    Hex,      /* one hex word per line, first line is the origin */
+    Bin,      /* one 16 digit binary string per line, first line is the origin */
+    IntelHex, /* Intel HEX records, LC-3 words stored big-endian at 2 * addr */
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "obj" => Some(Self::Obj),
+            "hex" => Some(Self::Hex),
+            "bin" => Some(Self::Bin),
+            "ihex" | "ihx" | "intelhex" => Some(Self::IntelHex),
+            _ => None,
+        }
+    }
+
+    // guess the format of an image from its file name and contents
+    // .hex is used both for lc3convert text and for Intel HEX, so the
+    // contents always win over the extension when they are unambiguous
+    pub fn detect(path: &Path, bytes: &[u8]) -> Self {
+        let ext = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_ascii_lowercase());
+
+        match ext.as_deref() {
+            Some("obj") => return Self::Obj,
+            Some("ihex") | Some("ihx") => return Self::IntelHex,
+            _ => (),
+        }
+
+        Self::sniff(bytes).unwrap_or(match ext.as_deref() {
+            Some("bin") => Self::Bin,
+            Some("hex") => Self::Hex,
+            _ => Self::Obj,
+        })
+    }
+
+    // look at the first meaningful line of a text image
+    fn sniff(bytes: &[u8]) -> Option<Self> {
+        let text = std::str::from_utf8(bytes).ok()?;
+        let line = text.lines().map(strip_comment).find(|l| !l.is_empty())?;
+
+        if line.starts_with(':') {
+            Some(Self::IntelHex)
+        } else if line.len() == 16 && line.chars().all(|c| c == '0' || c == '1') {
+            Some(Self::Bin)
+        } else if parse_hex_word(line).is_some() {
+            Some(Self::Hex)
+        } else {
+            None
+        }
+    }
+}
+
+// A contiguous run of words starting at origin
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub origin: u16,
+    pub words: Vec<u16>,
+}
+
+// A loadable program: one or more segments, in the order they were read
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Image {
+    pub segments: Vec<Segment>,
+}
+
+impl Image {
+    pub fn new(origin: u16, words: Vec<u16>) -> Self {
+        Image {
+            segments: vec![Segment { origin, words }],
+        }
+    }
+
+    // address the program expects to start at (origin of the first segment)
+    pub fn entry(&self) -> Option<u16> {
+        self.segments.first().map(|s| s.origin)
+    }
+
+    pub fn parse(bytes: &[u8], format: Format) -> io::Result<Self> {
+        match format {
+            Format::Obj => obj::parse(bytes),
+            Format::Hex => hex::parse(bytes),
+            Format::Bin => bin::parse(bytes),
+            Format::IntelHex => ihex::parse(bytes),
+        }
+    }
+
+    pub fn to_bytes(&self, format: Format) -> io::Result<Vec<u8>> {
+        match format {
+            Format::Obj => obj::write(self),
+            Format::Hex => hex::write(self),
+            Format::Bin => bin::write(self),
+            Format::IntelHex => ihex::write(self),
+        }
+    }
+
+    // read an image from disk, detecting its format
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let bytes = fs::read(path)?;
+        Self::parse(&bytes, Format::detect(path, &bytes))
+    }
+
+    pub fn save(&self, path: &Path, format: Format) -> io::Result<()> {
+        fs::write(path, self.to_bytes(format)?)
+    }
+
+    // the single origin based formats cannot describe gaps between segments
+    fn single_segment(&self) -> io::Result<&Segment> {
+        match self.segments.as_slice() {
+            [segment] => Ok(segment),
+            [] => Err(invalid_data("image has no segments")),
+            _ => Err(invalid_data(
+                "image has more than one segment, use Intel HEX to export it",
+            )),
+        }
+    }
+}
+
+pub(crate) fn invalid_data(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+// text formats allow blank lines and ; or # comments
+pub(crate) fn strip_comment(line: &str) -> &str {
+    let end = line.find([';', '#']).unwrap_or(line.len());
+    line[..end].trim()
+}
+
+// accepts 3000, x3000 and 0x3000
+pub(crate) fn parse_hex_word(word: &str) -> Option<u16> {
+    let digits = word
+        .strip_prefix("0x")
+        .or_else(|| word.strip_prefix("0X"))
+        .or_else(|| word.strip_prefix('x'))
+        .or_else(|| word.strip_prefix('X'))
+        .unwrap_or(word);
+    if digits.is_empty() || digits.len() > 4 {
+        return None;
+    }
+    u16::from_str_radix(digits, 16).ok()
+}
+
+// parse a text image holding one word per line, the first word is the origin
+pub(crate) fn parse_text_lines(
+    bytes: &[u8],
+    parse_word: fn(&str) -> Option<u16>,
+) -> io::Result<Image> {
+    let text = std::str::from_utf8(bytes).map_err(|_| invalid_data("image is not valid text"))?;
+    let mut words = text
+        .lines()
+        .enumerate()
+        .map(|(n, l)| (n + 1, strip_comment(l)))
+        .filter(|(_, l)| !l.is_empty())
+        .map(|(n, l)| {
+            parse_word(l).ok_or_else(|| invalid_data(format!("line {}: bad word {:?}", n, l)))
+        });
+
+    let origin = words
+        .next()
+        .ok_or_else(|| invalid_data("image is empty"))??;
+    Ok(Image::new(origin, words.collect::<io::Result<Vec<u16>>>()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Image {
+        // .ORIG x3000; AND R0 R0 0; ADD R0 R0 1; HALT
+        Image::new(0x3000, vec![0x5020, 0x1021, 0xF025])
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for format in [Format::Obj, Format::Hex, Format::Bin, Format::IntelHex] {
+            let bytes = sample().to_bytes(format).unwrap();
+            assert_eq!(Image::parse(&bytes, format).unwrap(), sample());
+        }
+    }
+
+    #[test]
+    fn test_detect() {
+        let hex = sample().to_bytes(Format::Hex).unwrap();
+        let bin = sample().to_bytes(Format::Bin).unwrap();
+        let ihex = sample().to_bytes(Format::IntelHex).unwrap();
+        let obj = sample().to_bytes(Format::Obj).unwrap();
+
+        assert_eq!(Format::detect(Path::new("a.hex"), &hex), Format::Hex);
+        assert_eq!(Format::detect(Path::new("a.hex"), &ihex), Format::IntelHex);
+        assert_eq!(Format::detect(Path::new("a.bin"), &bin), Format::Bin);
+        assert_eq!(Format::detect(Path::new("a.txt"), &bin), Format::Bin);
+        assert_eq!(Format::detect(Path::new("a.obj"), &obj), Format::Obj);
+        assert_eq!(Format::detect(Path::new("a"), &obj), Format::Obj);
+    }
+
+    #[test]
+    fn test_text_comments() {
+        let text = b"; hand written\nx3000\n\n5020 ; AND R0 R0 0\n0xF025\n";
+        assert_eq!(
+            Image::parse(text, Format::Hex).unwrap(),
+            Image::new(0x3000, vec![0x5020, 0xF025])
+        );
+        assert!(Image::parse(b"3000\nzzzz\n", Format::Hex).is_err());
+    }
+}
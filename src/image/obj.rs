@@ -0,0 +1,33 @@
+use std::io::{self, Cursor};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use super::{invalid_data, Image};
+
+// .obj layout (lc3as): a big-endian origin word followed by big-endian words
+pub fn parse(bytes: &[u8]) -> io::Result<Image> {
+    if !bytes.len().is_multiple_of(2) {
+        return Err(invalid_data("object file has an odd number of bytes"));
+    }
+
+    let mut reader = Cursor::new(bytes);
+    let origin = reader
+        .read_u16::<BigEndian>()
+        .map_err(|_| invalid_data("object file is empty"))?;
+
+    let mut words = Vec::with_capacity(bytes.len() / 2 - 1);
+    while let Ok(word) = reader.read_u16::<BigEndian>() {
+        words.push(word);
+    }
+    Ok(Image::new(origin, words))
+}
+
+pub fn write(image: &Image) -> io::Result<Vec<u8>> {
+    let segment = image.single_segment()?;
+    let mut out = Vec::with_capacity(2 * (segment.words.len() + 1));
+    out.write_u16::<BigEndian>(segment.origin)?;
+    for word in &segment.words {
+        out.write_u16::<BigEndian>(*word)?;
+    }
+    Ok(out)
+}
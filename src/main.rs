@@ -1,38 +1,911 @@
-use byteorder::{BigEndian, ReadBytesExt};
 use std::{
     env,
-    fs::{self, File},
-    io::BufReader,
-    usize,
+    fs::File,
+    io::{self, BufWriter, IsTerminal, Write},
+    net::TcpListener,
+    os::unix::net::UnixListener,
+    path::{Path, PathBuf},
+    process::exit,
+    sync::{Arc, Mutex},
+    thread,
 };
 
+use hw::stop::StopReason;
+use hw::trace::{TraceFilter, TraceFormat, Tracer};
+use image::{Format, Image};
+use symbols::SymbolTable;
+
+pub mod asm;
+pub mod convention;
+pub mod coverage;
+pub mod dap;
+pub mod debugger;
+pub mod diff;
+pub mod expr;
+pub mod gdb;
+pub mod grader;
 pub mod hw;
+pub mod image;
+pub mod profile;
+pub mod shadow;
+pub mod symbols;
+pub mod terminal;
+pub mod tui;
+pub mod unittest;
+
+fn usage() -> ! {
+    eprintln!("Usage: ./vm <file_path> [--sym SYM_PATH] [--break ADDR]... [--save-state PATH]");
+    eprintln!("                        [--core PATH [--core-history N]] [TRACE_OPTIONS]");
+    eprintln!("                        [--check-calls PATH [--preserved R1,R2,...]]");
+    eprintln!(
+        "                        [--shadow PATH [--source ASM_PATH]] [--garbage SEED|random]"
+    );
+    eprintln!("                        [INPUT_OPTIONS]");
+    eprintln!("       ./vm debug <file_path> [--sym SYM_PATH]");
+    eprintln!("       ./vm tui <file_path> [--sym SYM_PATH]");
+    eprintln!("       ./vm dap");
+    eprintln!("       ./vm grade <dir> <spec.json> [--jobs N] [--json PATH] [--csv PATH]");
+    eprintln!("       ./vm test <file_path> <tests.json> [--sym SYM_PATH]");
+    eprintln!("       ./vm inspect <core_path> [--sym SYM_PATH]");
+    eprintln!("       ./vm disasm <file_path> [--sym SYM_PATH]");
+    eprintln!("       ./vm gdb <file_path> [--sym SYM_PATH] (--port PORT | --unix SOCKET_PATH)");
+    eprintln!("       ./vm profile <file_path> [--sym SYM_PATH] [--folded PATH] [--top N]");
+    eprintln!("                                [--limit N]");
+    eprintln!("       ./vm coverage <file_path> [--sym SYM_PATH] [COVERAGE_OPTIONS]");
+    eprintln!("       ./vm diff <expected> <actual> [DIFF_OPTIONS]");
+    eprintln!("       ./vm convert <in_path> <out_path> [--from FORMAT] [--to FORMAT]");
+    eprintln!("FORMAT is one of obj, hex, bin, ihex");
+    eprintln!("file_path may be a snapshot from --save-state, which resumes it");
+    eprintln!("the reports of --check-calls and --shadow go to STDERR for -");
+    eprintln!("INPUT_OPTIONS: [--input PATH | --input-text TEXT | --input-script PATH]");
+    eprintln!("               [--at-end eof[:VALUE]|block|stop] [--input-log PATH]");
+    eprintln!("an input script has a \"STEP TEXT\" line per key run, the keys due at STEP");
+    eprintln!("TRACE_OPTIONS: --trace PATH (- for STDOUT) [--trace-format text|jsonl]");
+    eprintln!("               [--trace-range START..END] [--trace-kind KIND,...]");
+    eprintln!("KIND is an opcode name or one of alu, load, store, memory, control");
+    eprintln!("COVERAGE_OPTIONS: [--source ASM_PATH] [--input PATH] [--merge LCOV_PATH]");
+    eprintln!("                  [--lcov PATH] [--listing PATH (- for STDOUT)] [--limit N]");
+    eprintln!("an image needs --source, the listing goes to STDOUT when neither is given");
+    eprintln!("DIFF_OPTIONS: [--sym-a SYM_PATH] [--sym-b SYM_PATH] [--input PATH (- for STDIN)]");
+    eprintln!("              [--max-steps N] [--context N]");
+    eprintln!("each side of a diff is a .jsonl trace or an image to run");
+    exit(2)
+}
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        panic!("Usage: ./vm <file_path>")
+    match args.get(1).map(String::as_str) {
+        Some("convert") => convert(&args[2..]),
+        Some("debug") => debug(&args[2..]),
+        Some("tui") => tui(&args[2..]),
+        Some("dap") => dap(&args[2..]),
+        Some("disasm") => disasm(&args[2..]),
+        Some("gdb") => gdb(&args[2..]),
+        Some("diff") => diff(&args[2..]),
+        Some("profile") => profile(&args[2..]),
+        Some("coverage") => coverage(&args[2..]),
+        Some("inspect") => inspect(&args[2..]),
+        Some("grade") => grade(&args[2..]),
+        Some("test") => unit_test(&args[2..]),
+        Some(_) => run(&args[1..]),
+        _ => usage(),
+    }
+}
+
+struct ProgramArgs {
+    path: String,
+    sym_path: Option<PathBuf>,
+    symbols: SymbolTable,
+    // any further --flag VALUE pairs the subcommand accepts
+    options: Vec<(String, String)>,
+}
+
+impl ProgramArgs {
+    fn option(&self, flag: &str) -> Option<&str> {
+        self.options
+            .iter()
+            .find(|(f, _)| f == flag)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+// <file_path> [--sym SYM_PATH] [--FLAG VALUE]..., without --sym the lc3as
+// .sym written next to the image is used when there is one
+fn parse_program_args(args: &[String], flags: &[&str]) -> ProgramArgs {
+    let Some((path, rest)) = args.split_first() else {
+        usage()
+    };
+    let mut sym_path = None;
+    let mut options = Vec::new();
+    let mut iter = rest.iter();
+    while let Some(flag) = iter.next() {
+        let value = iter.next().unwrap_or_else(|| usage());
+        match flag.as_str() {
+            "--sym" => sym_path = Some(PathBuf::from(value)),
+            _ if flags.contains(&flag.as_str()) => options.push((flag.clone(), value.clone())),
+            _ => usage(),
+        }
     }
 
-    let mut vm = hw::vm::VM::new();
-    // update_cond gets value from input register so this will set ZERO flag
-    vm.registers.update_cond_register(0);
+    let symbols = match &sym_path {
+        Some(sym) => SymbolTable::load(sym).unwrap_or_else(|e| {
+            eprintln!("Unable to load {}: {}", sym.display(), e);
+            exit(1)
+        }),
+        None => SymbolTable::load_beside(Path::new(path)).unwrap_or_default(),
+    };
+    ProgramArgs {
+        path: path.clone(),
+        sym_path,
+        symbols,
+        options,
+    }
+}
 
-    let f: File = File::open(args[1].clone()).expect("Unable to read file");
-    let mut f_reader = BufReader::new(f);
+// the program is an image, or a snapshot to resume from
+fn load_vm(args: &ProgramArgs) -> hw::vm::VM {
+    load_program(args).0
+}
 
-    let base_addr: u16 = f_reader
-        .read_u16::<BigEndian>()
-        .expect("Error reading file as u16");
+// as load_vm, along with the image when the program is not a snapshot
+fn load_program(args: &ProgramArgs) -> (hw::vm::VM, Option<Image>) {
+    let path = Path::new(&args.path);
+    let loaded = std::fs::read(path).and_then(|bytes| {
+        if bytes.starts_with(hw::snapshot::MAGIC) {
+            let mut vm = hw::vm::VM::new();
+            vm.load_state(&mut bytes.as_slice())?;
+            vm.symbols = args.symbols.clone();
+            return Ok((vm, None));
+        }
+        let image = Image::parse(&bytes, Format::detect(path, &bytes))?;
+        let vm = hw::vm::VM::with_program(&image, args.symbols.clone());
+        Ok((vm, Some(image)))
+    });
+    loaded.unwrap_or_else(|e| {
+        eprintln!("Unable to load {}: {}", args.path, e);
+        exit(1)
+    })
+}
 
-    let mut addr = base_addr as usize;
-    loop {
-        match f_reader.read_u16::<BigEndian>() {
-            Ok(instruction) => {
-                vm.write_memory(addr, instruction);
-                addr += 1;
+fn load_image(path: &str, format: Option<Format>) -> Image {
+    let path = Path::new(path);
+    let result = match format {
+        Some(format) => std::fs::read(path).and_then(|b| Image::parse(&b, format)),
+        None => Image::load(path),
+    };
+    result.unwrap_or_else(|e| {
+        eprintln!("Unable to load {}: {}", path.display(), e);
+        exit(1)
+    })
+}
+
+const RUN_FLAGS: [&str; 18] = [
+    "--trace",
+    "--trace-format",
+    "--trace-range",
+    "--trace-kind",
+    "--break",
+    "--save-state",
+    "--core",
+    "--core-history",
+    "--check-calls",
+    "--preserved",
+    "--shadow",
+    "--source",
+    "--garbage",
+    "--input",
+    "--input-text",
+    "--input-script",
+    "--at-end",
+    "--input-log",
+];
+
+// runs until the program halts or reaches a --break address, --save-state
+// then writes a snapshot that can be run again to carry on. A fault writes
+// a core file for inspect when --core is given. --check-calls reports
+// breaches of the calling convention and --shadow uses of uninitialised
+// memory once the program stops. --input, --input-text and --input-script
+// give the keys up front and --input-log records the ones taken. Otherwise
+// a terminal on STDIN is raw while the program runs, for games that poll KBSR
+fn run(args: &[String]) {
+    let args = parse_program_args(args, &RUN_FLAGS);
+    // a snapshot has no program to keep the stack out of, nor a record of
+    // which words were ever written
+    let (mut vm, image) = load_program(&args);
+    vm.tracer = tracer(&args, &vm.symbols);
+    if let (Some(seed), Some(image)) = (args.option("--garbage"), &image) {
+        let seed = match seed {
+            "random" => shadow::random_seed(),
+            seed => seed.parse().unwrap_or_else(|_| usage()),
+        };
+        eprintln!("Filling memory with garbage from seed {}", seed);
+        shadow::fill_garbage(&mut vm, image, seed);
+    }
+    let shadow = args.option("--shadow").map(|_| {
+        let Some(image) = &image else {
+            eprintln!("--shadow needs an image, not a snapshot");
+            exit(2)
+        };
+        let shadow = match args.option("--source") {
+            Some(source) => {
+                let assembly = asm::Assembly::load(Path::new(source)).unwrap_or_else(|e| {
+                    eprintln!("Unable to assemble {}: {}", source, e);
+                    exit(1)
+                });
+                shadow::ShadowMemory::from_assembly(&assembly)
             }
-            Err(_) => break,
+            None => shadow::ShadowMemory::new(image),
+        };
+        let shadow = Arc::new(Mutex::new(shadow));
+        vm.add_observer(shadow.clone());
+        shadow
+    });
+    let checker = args.option("--check-calls").map(|_| {
+        let image = image.clone().unwrap_or_default();
+        let mut checker = convention::ConventionChecker::new(&vm, &image);
+        if let Some(list) = args.option("--preserved") {
+            checker.preserved = convention::parse_registers(list).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                exit(2)
+            });
+        }
+        let checker = Arc::new(Mutex::new(checker));
+        vm.add_observer(checker.clone());
+        checker
+    });
+    let recent = args.option("--core").map(|_| {
+        let limit = match args.option("--core-history") {
+            Some(n) => n.parse().unwrap_or_else(|_| usage()),
+            None => hw::coredump::DEFAULT_RECENT,
+        };
+        let recent = Arc::new(Mutex::new(hw::coredump::RecentInstructions::new(limit)));
+        vm.add_observer(recent.clone());
+        recent
+    });
+    if let Some(input) = input_script(&args) {
+        vm.input = Some(input);
+    }
+    for (flag, addr) in &args.options {
+        if flag == "--break" {
+            let addr = vm.symbols.resolve(addr).unwrap_or_else(|| {
+                eprintln!("unknown address {:?}", addr);
+                exit(2)
+            });
+            vm.breakpoints
+                .insert(addr, hw::breakpoint::Breakpoint::default());
+        }
+    }
+
+    // nobody is watching a run, so a loop it cannot leave ends it
+    vm.loop_detector = Some(hw::loops::LoopDetector::default());
+    // keys STDIN may still give are read as they come so KBSR can poll for
+    // them, a terminal is raw for the run so they come unbuffered and unechoed
+    let reads_stdin = vm
+        .input
+        .as_ref()
+        .is_none_or(|input| input.at_end == hw::input::AtEnd::Block);
+    let raw = match reads_stdin && io::stdin().is_terminal() {
+        true => terminal::RawMode::console().ok(),
+        false => None,
+    };
+    if reads_stdin {
+        vm.keyboard = Some(terminal::Keyboard::spawn(io::stdin(), raw.is_some()));
+    }
+    let stop = vm.run(None);
+    drop(raw);
+    if let Some(Err(e)) = vm.tracer.as_mut().map(Tracer::finish) {
+        eprintln!("Unable to write trace: {}", e);
+    }
+    if let (Some(path), StopReason::Halted | StopReason::Breakpoint(_)) =
+        (args.option("--save-state"), stop)
+    {
+        let result = File::create(path).and_then(|file| {
+            let mut out = BufWriter::new(file);
+            vm.save_state(&mut out)?;
+            out.flush()
+        });
+        if let Err(e) = result {
+            eprintln!("Unable to write {}: {}", path, e);
+            exit(1);
+        }
+    }
+    if let (Some(path), Some(recent), StopReason::Fault(fault)) =
+        (args.option("--core"), recent, stop)
+    {
+        let recent: Vec<(u16, u16)> = recent.lock().unwrap().entries.iter().copied().collect();
+        let result = File::create(path).and_then(|file| {
+            let mut out = BufWriter::new(file);
+            hw::coredump::write_core(&vm, &fault, &recent, &mut out)?;
+            out.flush()
+        });
+        match result {
+            Ok(()) => eprintln!("Core written to {}", path),
+            Err(e) => eprintln!("Unable to write {}: {}", path, e),
+        }
+    }
+    if let (Some(path), Some(input)) = (args.option("--input-log"), &vm.input) {
+        if let Err(e) = std::fs::write(path, input.consumed_script()) {
+            eprintln!("Unable to write {}: {}", path, e);
+        }
+    }
+    for line in vm.icache.describe_writes(&vm.symbols) {
+        eprintln!("warning: self-modifying code, {}", line);
+    }
+    if let (Some(path), Some(checker)) = (args.option("--check-calls"), checker) {
+        let checker = checker.lock().unwrap();
+        let mut report = String::new();
+        for violation in &checker.violations {
+            report.push_str(&violation.describe(&vm.symbols));
+            report.push('\n');
         }
+        report.push_str(&format!(
+            "{} calling convention violations\n",
+            checker.violations.len()
+        ));
+        write_report(path, &report);
+    }
+    if let (Some(path), Some(shadow)) = (args.option("--shadow"), shadow) {
+        let shadow = shadow.lock().unwrap();
+        let mut report = String::new();
+        for finding in &shadow.findings {
+            report.push_str(&finding.describe(&vm.symbols));
+            report.push('\n');
+        }
+        report.push_str(&format!(
+            "{} shadow memory findings\n",
+            shadow.findings.len()
+        ));
+        write_report(path, &report);
+    }
+    match stop {
+        StopReason::Halted => (),
+        StopReason::Breakpoint(_) => eprintln!("\nStopped: {}", stop.describe(&vm.symbols)),
+        _ => {
+            eprintln!("{}", stop.describe(&vm.symbols));
+            exit(1);
+        }
+    }
+}
+
+// the keyboard input of run, None to read STDIN as it comes
+fn input_script(args: &ProgramArgs) -> Option<hw::input::InputScript> {
+    let fail = |e: String| -> ! {
+        eprintln!("{}", e);
+        exit(2)
+    };
+    let read = |path: &str| {
+        std::fs::read(path).unwrap_or_else(|e| fail(format!("Unable to read {}: {}", path, e)))
+    };
+    let mut input = match (
+        args.option("--input"),
+        args.option("--input-text"),
+        args.option("--input-script"),
+    ) {
+        (None, None, None) => match args.option("--at-end") {
+            Some(_) => hw::input::InputScript::default(),
+            None => return None,
+        },
+        (Some(path), None, None) => read(path).into(),
+        (None, Some(text), None) => {
+            hw::input::InputScript::parse(&format!("0 {}", text)).unwrap_or_else(|e| fail(e))
+        }
+        (None, None, Some(path)) => {
+            let text = String::from_utf8_lossy(&read(path)).into_owned();
+            hw::input::InputScript::parse(&text)
+                .unwrap_or_else(|e| fail(format!("{}: {}", path, e)))
+        }
+        _ => fail("only one of --input, --input-text and --input-script".to_string()),
+    };
+    if let Some(at_end) = args.option("--at-end") {
+        input.at_end = hw::input::AtEnd::parse(at_end).unwrap_or_else(|e| fail(e));
+    }
+    Some(input)
+}
+
+// a report of run goes to STDERR for -, to a file otherwise
+fn write_report(path: &str, report: &str) {
+    let result = match path {
+        "-" => io::stderr().write_all(report.as_bytes()),
+        path => std::fs::write(path, report),
+    };
+    if let Err(e) = result {
+        eprintln!("Unable to write {}: {}", path, e);
+    }
+}
+
+// the tracer asked for by TRACE_OPTIONS, the format defaults to JSON Lines
+// for .jsonl and .json paths and to text otherwise
+fn tracer(args: &ProgramArgs, symbols: &SymbolTable) -> Option<Tracer> {
+    let path = args.option("--trace")?;
+    let fail = |msg: String| -> ! {
+        eprintln!("{}", msg);
+        exit(2)
+    };
+
+    let format = match args.option("--trace-format") {
+        Some(name) => TraceFormat::from_name(name).unwrap_or_else(|| usage()),
+        None if path.ends_with(".jsonl") || path.ends_with(".json") => TraceFormat::Jsonl,
+        None => TraceFormat::Text,
+    };
+    let filter = TraceFilter {
+        range: args
+            .option("--trace-range")
+            .map(|range| TraceFilter::parse_range(range, symbols).unwrap_or_else(|e| fail(e))),
+        kinds: args
+            .option("--trace-kind")
+            .map(|kinds| TraceFilter::parse_kinds(kinds).unwrap_or_else(|e| fail(e)))
+            .unwrap_or_default(),
+    };
+
+    let out: Box<dyn Write + Send> = match path {
+        "-" => Box::new(io::stdout()),
+        _ => Box::new(BufWriter::new(File::create(path).unwrap_or_else(|e| {
+            fail(format!("Unable to create {}: {}", path, e))
+        }))),
+    };
+    Some(Tracer::new(out, format).with_filter(filter))
+}
+
+fn debugger(args: &[String]) -> debugger::Debugger {
+    let args = parse_program_args(args, &[]);
+    debugger::Debugger::new(
+        load_image(&args.path, None),
+        PathBuf::from(&args.path),
+        args.symbols,
+    )
+    .with_sym_path(args.sym_path)
+}
+
+fn debug(args: &[String]) {
+    if let Err(e) = debugger(args).repl() {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+fn tui(args: &[String]) {
+    if let Err(e) = tui::Tui::new(debugger(args)).run() {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+// Debug Adapter Protocol over STDIN and STDOUT, the program to debug is
+// named by the client's launch request
+fn dap(args: &[String]) {
+    if !args.is_empty() {
+        usage()
+    }
+    let requests = dap::spawn_reader(io::BufReader::new(io::stdin()));
+    if let Err(e) = dap::DapServer::new(requests, io::stdout()).serve() {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+// runs every image in a directory against a spec of test cases, prints a
+// summary and writes the full reports. Exits with 1 unless everything passed
+fn grade(args: &[String]) {
+    let [dir, spec, rest @ ..] = args else {
+        usage()
+    };
+    let mut jobs = thread::available_parallelism().map_or(1, |n| n.get());
+    let (mut json_path, mut csv_path) = (None, None);
+    for pair in rest.chunks(2) {
+        match pair {
+            [flag, n] if flag == "--jobs" => jobs = n.parse().unwrap_or_else(|_| usage()),
+            [flag, path] if flag == "--json" => json_path = Some(path),
+            [flag, path] if flag == "--csv" => csv_path = Some(path),
+            _ => usage(),
+        }
+    }
+    let fail = |msg: String| -> ! {
+        eprintln!("{}", msg);
+        exit(2)
+    };
+
+    let tests = std::fs::read_to_string(spec)
+        .map_err(|e| e.to_string())
+        .and_then(|text| grader::parse_spec(&text))
+        .unwrap_or_else(|e| fail(format!("Unable to load {}: {}", spec, e)));
+    let submissions = grader::Submission::load_dir(Path::new(dir))
+        .unwrap_or_else(|e| fail(format!("Unable to read {}: {}", dir, e)));
+    let reports = grader::grade(&submissions, &tests, jobs);
+
+    for report in &reports {
+        println!(
+            "{:<24} {}/{}",
+            report.submission,
+            report.passed(),
+            report.results.len()
+        );
+        for result in report.results.iter().filter(|r| !r.passed()) {
+            println!("    {}: {}", result.test, result.reasons.join("; "));
+        }
+    }
+    let write = |path: &String, text: String| {
+        std::fs::write(path, text)
+            .unwrap_or_else(|e| fail(format!("Unable to write {}: {}", path, e)))
+    };
+    if let Some(path) = json_path {
+        write(path, format!("{:#}\n", grader::to_json(&reports)));
+    }
+    if let Some(path) = csv_path {
+        write(path, grader::to_csv(&reports));
+    }
+    if reports.iter().any(|r| r.passed() < r.results.len()) {
+        exit(1);
+    }
+}
+
+// runs the subroutine tests of a test file against one program, exits with
+// 1 when any of them fails
+fn unit_test(args: &[String]) {
+    let [path, tests_path, rest @ ..] = args else {
+        usage()
+    };
+    let mut program_args = vec![path.clone()];
+    program_args.extend_from_slice(rest);
+    let args = parse_program_args(&program_args, &[]);
+    let fail = |msg: String| -> ! {
+        eprintln!("{}", msg);
+        exit(2)
+    };
+
+    let tests = std::fs::read_to_string(tests_path)
+        .map_err(|e| e.to_string())
+        .and_then(|text| unittest::parse_tests(&text))
+        .unwrap_or_else(|e| fail(format!("Unable to load {}: {}", tests_path, e)));
+    let (image, symbols) = if path.ends_with(".asm") {
+        let assembly = asm::Assembly::load(Path::new(path))
+            .unwrap_or_else(|e| fail(format!("Unable to assemble {}: {}", path, e)));
+        (assembly.image, assembly.symbols)
+    } else {
+        (load_image(path, None), args.symbols)
+    };
+
+    let mut failed = 0;
+    for test in &tests {
+        let result = unittest::run_test(&image, &symbols, test);
+        match result.passed() {
+            true => println!("PASS  {} ({} instructions)", result.test, result.steps),
+            false => {
+                failed += 1;
+                println!("FAIL  {}", result.test);
+                for reason in &result.reasons {
+                    println!("      {}", reason);
+                }
+            }
+        }
+    }
+    println!("{} passed, {} failed", tests.len() - failed, failed);
+    if failed > 0 {
+        exit(1);
+    }
+}
+
+// prints what a core file recorded, then on a terminal takes debugger
+// commands to examine the faulted machine
+fn inspect(args: &[String]) {
+    let args = parse_program_args(args, &[]);
+    let core = File::open(&args.path)
+        .and_then(|file| hw::coredump::read_core(&mut io::BufReader::new(file)))
+        .unwrap_or_else(|e| {
+            eprintln!("Unable to load {}: {}", args.path, e);
+            exit(1)
+        });
+    // symbols given with --sym or found beside the core win over the saved ones
+    let symbols = match args.symbols.is_empty() {
+        true => core.vm.symbols.clone(),
+        false => args.symbols,
+    };
+    print!("{}", core.describe(&symbols));
+
+    if io::stdin().is_terminal() {
+        println!();
+        let image = Image::new(0, core.vm.memory.to_vec());
+        let mut debugger = debugger::Debugger::new(image, PathBuf::from(&args.path), symbols);
+        let mut vm = core.vm;
+        vm.symbols = std::mem::take(&mut debugger.vm.symbols);
+        debugger.vm = vm;
+        if let Err(e) = debugger.repl() {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    }
+}
+
+// prints every loaded word with its address, label and assembly
+fn disasm(args: &[String]) {
+    let ProgramArgs { path, symbols, .. } = parse_program_args(args, &[]);
+    let image = load_image(&path, None);
+    for segment in &image.segments {
+        for (i, word) in segment.words.iter().enumerate() {
+            let addr = segment.origin.wrapping_add(i as u16);
+            println!(
+                "x{:04X}  {:04X}  {:<12} {}",
+                addr,
+                word,
+                symbols.name_at(addr).unwrap_or(""),
+                hw::disasm::disassemble(*word, addr, &symbols)
+            );
+        }
+    }
+}
+
+// serves a single GDB client on a local TCP port or a Unix socket
+fn gdb(args: &[String]) {
+    let args = parse_program_args(args, &["--port", "--unix"]);
+    let vm = load_vm(&args);
+    let result = match (args.option("--port"), args.option("--unix")) {
+        (Some(port), None) => {
+            let port: u16 = port.parse().unwrap_or_else(|_| usage());
+            TcpListener::bind(("127.0.0.1", port)).and_then(|listener| {
+                eprintln!("Waiting for GDB on 127.0.0.1:{}", port);
+                let (conn, _) = listener.accept()?;
+                gdb::GdbStub::new(vm, conn).serve()
+            })
+        }
+        (None, Some(socket)) => UnixListener::bind(socket).and_then(|listener| {
+            eprintln!("Waiting for GDB on {}", socket);
+            let (conn, _) = listener.accept()?;
+            let result = gdb::GdbStub::new(vm, conn).serve();
+            let _ = std::fs::remove_file(socket);
+            result
+        }),
+        _ => usage(),
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+// instructions profile and coverage run before giving up on a program,
+// --limit changes it
+const STEP_LIMIT: u64 = 100_000_000;
+
+// runs the program and prints its profile once it stops, --folded also
+// writes the call stacks in the folded format flame graph tools read. The
+// report covers whatever ran, whether or not the program halted
+fn profile(args: &[String]) {
+    let args = parse_program_args(args, &["--folded", "--top", "--limit"]);
+    let top = match args.option("--top") {
+        Some(n) => n.parse().unwrap_or_else(|_| usage()),
+        None => 20,
+    };
+    let limit = match args.option("--limit") {
+        Some(n) => n.parse().unwrap_or_else(|_| usage()),
+        None => STEP_LIMIT,
+    };
+    let mut vm = load_vm(&args);
+    let profiler = Arc::new(Mutex::new(profile::Profiler::new()));
+    vm.add_observer(profiler.clone());
+    vm.loop_detector = Some(hw::loops::LoopDetector::default());
+
+    let stop = vm.run(Some(limit));
+    let profiler = profiler.lock().unwrap();
+    print!("\n{}", profiler.report(&vm, top));
+    if let Some(path) = args.option("--folded") {
+        if let Err(e) = std::fs::write(path, profiler.folded(&vm.symbols)) {
+            eprintln!("Unable to write {}: {}", path, e);
+            exit(1);
+        }
+    }
+    if stop != StopReason::Halted {
+        eprintln!("{}", stop.describe(&vm.symbols));
+        exit(1);
+    }
+}
+
+const COVERAGE_FLAGS: [&str; 6] = [
+    "--source",
+    "--input",
+    "--merge",
+    "--lcov",
+    "--listing",
+    "--limit",
+];
+
+// runs the program and reports which source lines ran. An .asm program is
+// assembled here, an image needs the source it was assembled from. The
+// report covers whatever ran, whether or not the program halted
+fn coverage(args: &[String]) {
+    let args = parse_program_args(args, &COVERAGE_FLAGS);
+    let fail = |msg: String| -> ! {
+        eprintln!("{}", msg);
+        exit(1)
+    };
+    let source = match args.option("--source") {
+        Some(source) => source,
+        None if args.path.ends_with(".asm") => &args.path,
+        None => usage(),
+    };
+    let limit = match args.option("--limit") {
+        Some(n) => n.parse().unwrap_or_else(|_| usage()),
+        None => STEP_LIMIT,
+    };
+    let assembly = asm::Assembly::load(Path::new(source))
+        .unwrap_or_else(|e| fail(format!("Unable to assemble {}: {}", source, e)));
+    let mut vm = if args.path.ends_with(".asm") {
+        hw::vm::VM::with_program(&assembly.image, assembly.symbols.clone())
+    } else {
+        load_vm(&args)
+    };
+    if let Some(path) = args.option("--input") {
+        let input =
+            std::fs::read(path).unwrap_or_else(|e| fail(format!("Unable to read {}: {}", path, e)));
+        vm.input = Some(input.into());
+    }
+
+    let recorder = Arc::new(Mutex::new(coverage::Coverage::default()));
+    vm.add_observer(recorder.clone());
+    vm.loop_detector = Some(hw::loops::LoopDetector::default());
+    let stop = vm.run(Some(limit));
+    let mut lines = coverage::LineCoverage::new(source, &assembly, &recorder.lock().unwrap());
+
+    // records of an earlier run are added in, those of other sources kept
+    let mut others = Vec::new();
+    if let Some(path) = args.option("--merge") {
+        let records = std::fs::read_to_string(path)
+            .map_err(|e| e.to_string())
+            .and_then(|text| coverage::LineCoverage::parse_lcov(&text))
+            .unwrap_or_else(|e| fail(format!("Unable to read {}: {}", path, e)));
+        for record in records {
+            match record.source == source {
+                true => lines.merge(&record),
+                false => others.push(record),
+            }
+        }
+    }
+
+    if let Some(path) = args.option("--lcov") {
+        let info: String = others
+            .iter()
+            .chain([&lines])
+            .map(coverage::LineCoverage::to_lcov)
+            .collect();
+        std::fs::write(path, info)
+            .unwrap_or_else(|e| fail(format!("Unable to write {}: {}", path, e)));
+    }
+    let listing = match args.option("--listing") {
+        None if args.option("--lcov").is_some() => None,
+        path => Some(path.unwrap_or("-")),
+    };
+    if let Some(path) = listing {
+        let text = std::fs::read_to_string(source)
+            .unwrap_or_else(|e| fail(format!("Unable to read {}: {}", source, e)));
+        let listing = lines.annotate(&text);
+        match path {
+            "-" => print!("\n{}", listing),
+            _ => std::fs::write(path, listing)
+                .unwrap_or_else(|e| fail(format!("Unable to write {}: {}", path, e))),
+        }
+    }
+    if stop != StopReason::Halted {
+        fail(stop.describe(&vm.symbols));
+    }
+}
+
+// compares two recorded JSON Lines traces or two runs on the same input,
+// exiting with 1 at the first step where they differ
+fn diff(args: &[String]) {
+    let [expected, actual, rest @ ..] = args else {
+        usage()
+    };
+    let mut options = Vec::new();
+    for pair in rest.chunks(2) {
+        match pair {
+            [flag, value] if flag.starts_with("--") => options.push((flag.as_str(), value)),
+            _ => usage(),
+        }
+    }
+    let option = |flag: &str| options.iter().find(|(f, _)| *f == flag).map(|(_, v)| v);
+    let number = |flag: &str, default: u64| match option(flag) {
+        Some(n) => n.parse().unwrap_or_else(|_| usage()),
+        None => default,
+    };
+    if options.iter().any(|(flag, _)| {
+        !["--sym-a", "--sym-b", "--input", "--max-steps", "--context"].contains(flag)
+    }) {
+        usage()
+    }
+    let max_steps = number("--max-steps", 10_000_000);
+    let context = number("--context", 5) as usize;
+
+    let input = option("--input").map(|path| {
+        let result = match path.as_str() {
+            "-" => {
+                let mut bytes = Vec::new();
+                io::Read::read_to_end(&mut io::stdin(), &mut bytes).map(|_| bytes)
+            }
+            _ => std::fs::read(path),
+        };
+        result.unwrap_or_else(|e| {
+            eprintln!("Unable to read {}: {}", path, e);
+            exit(1)
+        })
+    });
+    let open = |path: &String, sym_flag: &str| {
+        let mut sym_args = vec![path.clone()];
+        if let Some(sym) = option(sym_flag) {
+            sym_args.extend(["--sym".to_string(), sym.to_string()]);
+        }
+        let args = parse_program_args(&sym_args, &[]);
+        if path.ends_with(".jsonl") || path.ends_with(".json") {
+            let file = diff::TraceFile::open(Path::new(path)).unwrap_or_else(|e| {
+                eprintln!("Unable to open {}: {}", path, e);
+                exit(1)
+            });
+            return (diff::Source::File(file), args.symbols);
+        }
+        // console output is kept off STDOUT and both runs read the same bytes
+        let mut vm = load_vm(&args);
+        vm.output = Some(Vec::new());
+        vm.input = Some(input.clone().unwrap_or_default().into());
+        (
+            diff::Source::Vm(Box::new(diff::VmTrace::new(vm, max_steps))),
+            args.symbols,
+        )
+    };
+    let (mut a, sym_a) = open(expected, "--sym-a");
+    let (mut b, sym_b) = open(actual, "--sym-b");
+
+    let divergence = diff::first_divergence(&mut a, &mut b, context);
+    for (path, source, symbols) in [(expected, &a, &sym_a), (actual, &b, &sym_b)] {
+        if let Some(e) = source.error(symbols) {
+            eprintln!("{}: {}", path, e);
+        }
+    }
+    match divergence {
+        Some(divergence) => {
+            print!("{}", divergence.describe(&sym_a, &sym_b));
+            exit(1)
+        }
+        None => println!("traces are identical"),
+    }
+}
+
+// convert <in> <out> [--from FORMAT] [--to FORMAT]
+// the output format defaults to the one implied by the output extension
+fn convert(args: &[String]) {
+    let mut paths = Vec::new();
+    let mut from = None;
+    let mut to = None;
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--from" | "--to" => {
+                let format = iter
+                    .next()
+                    .and_then(|f| Format::from_name(f))
+                    .unwrap_or_else(|| usage());
+                if arg == "--from" {
+                    from = Some(format);
+                } else {
+                    to = Some(format);
+                }
+            }
+            _ => paths.push(arg),
+        }
+    }
+    let [in_path, out_path] = paths.as_slice() else {
+        usage()
+    };
+
+    let image = load_image(in_path, from);
+    let to = to
+        .or_else(|| {
+            Path::new(out_path.as_str())
+                .extension()
+                .and_then(|e| e.to_str())
+                .and_then(Format::from_name)
+        })
+        .unwrap_or(Format::Obj);
+
+    if let Err(e) = image.save(Path::new(out_path.as_str()), to) {
+        eprintln!("Unable to write {}: {}", out_path, e);
+        exit(1);
     }
 }
@@ -0,0 +1,291 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::hw::branches::BranchCounter;
+use crate::hw::instruction::{OpCode, RET};
+use crate::hw::observer::Observer;
+use crate::hw::register::PC_REG;
+use crate::hw::vm::VM;
+use crate::symbols::SymbolTable;
+
+// Instruction level profile of a run, gathered as an observer of the VM
+
+// One subroutine in the call tree, a subroutine called from two places has
+// a node under each caller
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CallNode {
+    // entry address, the program entry for the root
+    pub addr: u16,
+    pub calls: u64,
+    // instructions executed in the subroutine itself
+    pub exclusive: u64,
+    pub children: BTreeMap<u16, usize>,
+}
+
+#[derive(Debug, Default)]
+pub struct Profiler {
+    pub steps: u64,
+    pub counts: BTreeMap<u16, u64>,
+    // instructions executed per opcode, indexed by bits 15-12
+    pub opcodes: [u64; 16],
+    pub branches: BranchCounter,
+    // nodes[0] is the root once anything has executed
+    pub nodes: Vec<CallNode>,
+    stack: Vec<usize>,
+    // the instruction being executed, to see what its PC write means
+    current: Option<(u16, u16)>,
+}
+
+impl Observer for Profiler {
+    fn on_fetch(&mut self, pc: u16, instruction: u16) {
+        if self.stack.is_empty() {
+            self.nodes.push(new_node(pc));
+            self.nodes[0].calls = 1;
+            self.stack.push(0);
+        }
+        self.steps += 1;
+        *self.counts.entry(pc).or_default() += 1;
+        self.opcodes[(instruction >> 12) as usize] += 1;
+        self.nodes[*self.stack.last().unwrap()].exclusive += 1;
+        self.current = Some((pc, instruction));
+        self.branches.on_fetch(pc, instruction);
+
+        if instruction == RET && self.stack.len() > 1 {
+            self.stack.pop();
+        }
+    }
+
+    fn on_register_write(&mut self, register: u8, old: u16, new: u16) {
+        self.branches.on_register_write(register, old, new);
+        let Some((pc, instruction)) = self.current else {
+            return;
+        };
+        // the write moving PC past the instruction is not a jump
+        if register != PC_REG || (old == pc && new == pc.wrapping_add(1)) {
+            return;
+        }
+        if OpCode::from_u16(&instruction) == Some(OpCode::OpJsr) {
+            self.call(new);
+        }
+    }
+}
+
+fn new_node(addr: u16) -> CallNode {
+    CallNode {
+        addr,
+        calls: 0,
+        exclusive: 0,
+        children: BTreeMap::new(),
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler::default()
+    }
+
+    fn call(&mut self, addr: u16) {
+        let caller = *self.stack.last().unwrap();
+        let node = match self.nodes[caller].children.get(&addr) {
+            Some(&node) => node,
+            None => {
+                self.nodes.push(new_node(addr));
+                let node = self.nodes.len() - 1;
+                self.nodes[caller].children.insert(addr, node);
+                node
+            }
+        };
+        self.nodes[node].calls += 1;
+        self.stack.push(node);
+    }
+
+    // instructions executed in a subroutine and everything it called
+    pub fn inclusive(&self, node: usize) -> u64 {
+        let node = &self.nodes[node];
+        node.exclusive
+            + node
+                .children
+                .values()
+                .map(|&child| self.inclusive(child))
+                .sum::<u64>()
+    }
+
+    // counts summed per nearest preceding label, addresses below every
+    // label are left out
+    pub fn label_counts(&self, symbols: &SymbolTable) -> Vec<(String, u64)> {
+        let mut labels: BTreeMap<String, u64> = BTreeMap::new();
+        for (addr, count) in &self.counts {
+            if let Some((name, _)) = symbols.nearest(*addr) {
+                *labels.entry(name.to_string()).or_default() += count;
+            }
+        }
+        let mut labels: Vec<(String, u64)> = labels.into_iter().collect();
+        labels.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        labels
+    }
+
+    // "MAIN;SORT;SWAP 42" lines of exclusive counts, for flame graph tools
+    pub fn folded(&self, symbols: &SymbolTable) -> String {
+        let mut out = String::new();
+        if !self.nodes.is_empty() {
+            self.fold(0, String::new(), symbols, &mut out);
+        }
+        out
+    }
+
+    fn fold(&self, node: usize, prefix: String, symbols: &SymbolTable, out: &mut String) {
+        let node = &self.nodes[node];
+        let stack = match prefix.is_empty() {
+            true => frame_name(node.addr, symbols),
+            false => format!("{};{}", prefix, frame_name(node.addr, symbols)),
+        };
+        if node.exclusive > 0 {
+            let _ = writeln!(out, "{} {}", stack, node.exclusive);
+        }
+        for &child in node.children.values() {
+            self.fold(child, stack.clone(), symbols, out);
+        }
+    }
+
+    // the whole profile as text, top limits the hot address list
+    pub fn report(&self, vm: &VM, top: usize) -> String {
+        let symbols = &vm.symbols;
+        let percent = |count: u64| 100.0 * count as f64 / self.steps.max(1) as f64;
+        let mut out = format!("{} instructions executed\n", self.steps);
+
+        out.push_str("\nhottest addresses:\n");
+        let mut hottest: Vec<(&u16, &u64)> = self.counts.iter().collect();
+        hottest.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (addr, count) in hottest.into_iter().take(top) {
+            let _ = writeln!(
+                out,
+                "{:>10} {:>6.2}%  x{:04X}  {:<16} {}",
+                count,
+                percent(*count),
+                addr,
+                symbols.label_for(*addr).unwrap_or_default(),
+                vm.disassemble(*addr)
+            );
+        }
+
+        let labels = self.label_counts(symbols);
+        if !labels.is_empty() {
+            out.push_str("\nby label:\n");
+            for (name, count) in labels {
+                let _ = writeln!(out, "{:>10} {:>6.2}%  {}", count, percent(count), name);
+            }
+        }
+
+        out.push_str("\ninstruction mix:\n");
+        let mut mix: Vec<(u16, u64)> = (0..16u16)
+            .map(|op| (op, self.opcodes[op as usize]))
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        mix.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        for (op, count) in mix {
+            let name = OpCode::from_u16(&(op << 12)).unwrap().name();
+            let _ = writeln!(out, "{:>10} {:>6.2}%  {}", count, percent(count), name);
+        }
+
+        if !self.branches.counts.is_empty() {
+            out.push_str("\nbranches:       taken  not taken\n");
+            for (addr, (taken, not_taken)) in &self.branches.counts {
+                let _ = writeln!(
+                    out,
+                    "  x{:04X}  {:>10} {:>10}  {:>6.2}% taken  {} {}",
+                    addr,
+                    taken,
+                    not_taken,
+                    100.0 * *taken as f64 / (taken + not_taken).max(1) as f64,
+                    symbols.label_for(*addr).unwrap_or_default(),
+                    vm.disassemble(*addr)
+                );
+            }
+        }
+
+        if !self.nodes.is_empty() {
+            out.push_str("\ncall tree:  inclusive  exclusive      calls\n");
+            self.write_tree(0, 0, symbols, &mut out);
+        }
+        out
+    }
+
+    fn write_tree(&self, node: usize, depth: usize, symbols: &SymbolTable, out: &mut String) {
+        let entry = &self.nodes[node];
+        let _ = writeln!(
+            out,
+            "{:>21} {:>10} {:>10}  {}{}",
+            self.inclusive(node),
+            entry.exclusive,
+            entry.calls,
+            "  ".repeat(depth),
+            frame_name(entry.addr, symbols)
+        );
+        let mut children: Vec<(usize, u64)> = entry
+            .children
+            .values()
+            .map(|&child| (child, self.inclusive(child)))
+            .collect();
+        children.sort_by_key(|&(_, inclusive)| std::cmp::Reverse(inclusive));
+        for (child, _) in children {
+            self.write_tree(child, depth + 1, symbols, out);
+        }
+    }
+}
+
+fn frame_name(addr: u16, symbols: &SymbolTable) -> String {
+    symbols
+        .label_for(addr)
+        .unwrap_or_else(|| format!("x{:04X}", addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hw::register::PC_START;
+    use crate::image::Image;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_profile() {
+        let program = vec![
+            0x5020, // MAIN AND R0, R0, #0
+            0x1222, //      ADD R1, R0, #2
+            0x4803, // LOOP JSR SUB
+            0x127F, //      ADD R1, R1, #-1
+            0x03FD, //      BRp LOOP
+            0xF025, //      HALT
+            0x1021, // SUB  ADD R0, R0, #1
+            0xC1C0, //      RET
+        ];
+        let mut vm = VM::new();
+        vm.load_image(&Image::new(PC_START, program));
+        vm.symbols.insert("MAIN", 0x3000);
+        vm.symbols.insert("LOOP", 0x3002);
+        vm.symbols.insert("SUB", 0x3006);
+        let profiler = Arc::new(Mutex::new(Profiler::new()));
+        vm.add_observer(profiler.clone());
+        vm.execute_program().unwrap();
+
+        let profiler = profiler.lock().unwrap();
+        assert_eq!(profiler.steps, 13);
+        assert_eq!(profiler.counts[&0x3002], 2);
+        assert_eq!(profiler.opcodes[OpCode::OpAdd as usize], 5);
+        assert_eq!(profiler.branches.counts[&0x3004], (1, 1));
+        assert_eq!(
+            profiler.label_counts(&vm.symbols),
+            vec![
+                ("LOOP".to_string(), 7),
+                ("SUB".to_string(), 4),
+                ("MAIN".to_string(), 2)
+            ]
+        );
+        assert_eq!(profiler.inclusive(0), 13);
+        assert_eq!(profiler.folded(&vm.symbols), "MAIN 9\nMAIN;SUB 4\n");
+
+        let report = profiler.report(&vm, 3);
+        assert!(report.contains("  x3004           1          1   50.00% taken  LOOP+2 BRp LOOP"));
+        assert!(report.contains("                   13          9          1  MAIN\n"));
+        assert!(report.contains("                    4          4          2    SUB\n"));
+    }
+}
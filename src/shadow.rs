@@ -0,0 +1,236 @@
+use std::collections::HashSet;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::asm::Assembly;
+use crate::hw::observer::Observer;
+use crate::hw::register::PC_REG;
+use crate::hw::vm::VM;
+use crate::image::Image;
+use crate::symbols::SymbolTable;
+
+// Shadow memory: a few flags per word recording whether it was ever given
+// a value and whether it has been used as code or as data. Memory starts
+// zeroed here, so a program reading a word it never wrote works by luck
+// and breaks on simulators that leave garbage behind
+
+const WRITTEN: u8 = 1;
+const CODE: u8 = 2;
+const DATA: u8 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ShadowKind {
+    // a load from a word nothing ever wrote
+    ReadUninitialised,
+    // a fetch from a word nothing ever wrote
+    ExecutedUninitialised,
+    // a fetch from a word that holds data
+    ExecutedData,
+    // a store into a word that holds code
+    WroteCode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Finding {
+    // the instruction at fault
+    pub pc: u16,
+    // the word it read, wrote or executed
+    pub addr: u16,
+    pub kind: ShadowKind,
+}
+
+impl Finding {
+    pub fn describe(&self, symbols: &SymbolTable) -> String {
+        let addr = symbols.format_addr(self.addr);
+        let what = match self.kind {
+            ShadowKind::ReadUninitialised => format!("read of uninitialised {}", addr),
+            ShadowKind::ExecutedUninitialised => "executing uninitialised memory".to_string(),
+            ShadowKind::ExecutedData => "executing a data word".to_string(),
+            ShadowKind::WroteCode => format!("write into code at {}", addr),
+        };
+        format!("{}: {}", symbols.format_addr(self.pc), what)
+    }
+}
+
+// An observer keeping the shadow of every word. Which words are code is
+// known from the source when there is one and learnt from execution
+// otherwise, so without a source a data word is only caught once it has
+// been read as data. A finding is kept once per instruction and
+// kind so loops do not repeat it
+#[derive(Debug, Clone)]
+pub struct ShadowMemory {
+    pub findings: Vec<Finding>,
+    flags: Vec<u8>,
+    pc: u16,
+    seen: HashSet<(u16, ShadowKind)>,
+}
+
+impl ShadowMemory {
+    // the words of image count as written
+    pub fn new(image: &Image) -> Self {
+        let mut flags = vec![0; 1 << 16];
+        for segment in &image.segments {
+            for i in 0..segment.words.len() {
+                flags[segment.origin.wrapping_add(i as u16) as usize] = WRITTEN;
+            }
+        }
+        ShadowMemory {
+            findings: Vec::new(),
+            flags,
+            pc: 0,
+            seen: HashSet::new(),
+        }
+    }
+
+    // the words assembled from instructions are code, the rest data
+    pub fn from_assembly(assembly: &Assembly) -> Self {
+        let mut shadow = ShadowMemory::new(&assembly.image);
+        for &addr in assembly.lines.keys() {
+            shadow.flags[addr as usize] |= match assembly.code.contains(&addr) {
+                true => CODE,
+                false => DATA,
+            };
+        }
+        shadow
+    }
+
+    fn report(&mut self, addr: u16, kind: ShadowKind) {
+        if self.seen.insert((self.pc, kind)) {
+            self.findings.push(Finding {
+                pc: self.pc,
+                addr,
+                kind,
+            });
+        }
+    }
+}
+
+impl Observer for ShadowMemory {
+    fn on_fetch(&mut self, pc: u16, _instruction: u16) {
+        self.pc = pc;
+        let flags = self.flags[pc as usize];
+        if flags & WRITTEN == 0 {
+            self.report(pc, ShadowKind::ExecutedUninitialised);
+        } else if flags & (DATA | CODE) == DATA {
+            self.report(pc, ShadowKind::ExecutedData);
+        }
+        self.flags[pc as usize] |= CODE;
+    }
+
+    fn on_memory_read(&mut self, addr: u16, _value: u16) {
+        if self.flags[addr as usize] & WRITTEN == 0 {
+            self.report(addr, ShadowKind::ReadUninitialised);
+        }
+        self.flags[addr as usize] |= DATA;
+    }
+
+    fn on_memory_write(&mut self, addr: u16, _old: u16, _new: u16) {
+        if self.flags[addr as usize] & CODE != 0 {
+            self.report(addr, ShadowKind::WroteCode);
+        }
+        // a store alone does not make a word data, it may be code being
+        // put in place
+        self.flags[addr as usize] |= WRITTEN;
+    }
+}
+
+// a seed for fill_garbage that differs from run to run
+pub fn random_seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64)
+}
+
+// Fills R0-R7 and every word outside image with pseudo-random values, as
+// memory is found on real hardware. The same seed gives the same garbage
+pub fn fill_garbage(vm: &mut VM, image: &Image, seed: u64) {
+    // xorshift64*, which must not start from 0
+    let mut state = seed | 1;
+    let mut next = || {
+        state ^= state >> 12;
+        state ^= state << 25;
+        state ^= state >> 27;
+        (state.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 48) as u16
+    };
+    let shadow = ShadowMemory::new(image);
+    // straight into memory, garbage counts as never loaded so running
+    // into it is still a runaway PC
+    for addr in 0..vm.memory.len() {
+        if shadow.flags[addr] & WRITTEN == 0 {
+            vm.memory[addr] = next();
+        }
+    }
+    for r in 0..PC_REG {
+        vm.registers.update_register(r, next());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use std::sync::{Arc, Mutex};
+
+    // reads the uninitialised word after the program, patches its own ADD
+    // and then runs on into DATA
+    const SOURCE: &str = "        .ORIG x3000
+        LD R0, DATA
+        LDI R1, PTR
+        ST R1, PATCH
+PATCH   ADD R0, R0, #0
+DATA    .FILL x1021
+PTR     .FILL x3006
+        .END";
+
+    fn check(shadow: ShadowMemory, vm: &mut VM) -> Vec<Finding> {
+        let shadow = Arc::new(Mutex::new(shadow));
+        vm.add_observer(shadow.clone());
+        for _ in 0..5 {
+            vm.step().unwrap();
+        }
+        let findings = shadow.lock().unwrap().findings.clone();
+        findings
+    }
+
+    #[test]
+    fn test_findings() {
+        let assembly = assemble(SOURCE).unwrap();
+        let mut vm = VM::new();
+        vm.load_image(&assembly.image);
+        let findings = check(ShadowMemory::from_assembly(&assembly), &mut vm);
+        let report: Vec<String> = findings
+            .iter()
+            .map(|f| f.describe(&assembly.symbols))
+            .collect();
+        assert_eq!(
+            report,
+            vec![
+                "x3001: read of uninitialised PTR+1 (x3006)",
+                "x3002: write into code at PATCH (x3003)",
+                "DATA (x3004): executing a data word",
+            ]
+        );
+
+        // without the source DATA is only known to be data once it is read
+        let mut vm = VM::new();
+        vm.load_image(&assembly.image);
+        let findings = check(ShadowMemory::new(&assembly.image), &mut vm);
+        assert_eq!(findings.len(), 2);
+        assert_eq!(findings[1].kind, ShadowKind::ExecutedData);
+    }
+
+    #[test]
+    fn test_fill_garbage() {
+        let assembly = assemble(SOURCE).unwrap();
+        let mut vm = VM::new();
+        fill_garbage(&mut vm, &assembly.image, 7);
+        vm.load_image(&assembly.image);
+        let mut again = VM::new();
+        fill_garbage(&mut again, &assembly.image, 7);
+        assert_eq!(vm.memory[0x3006], again.memory[0x3006]);
+        assert_ne!(vm.memory[0x3006], vm.memory[0x3007]);
+        assert_eq!(vm.memory[0x3000], 0x2003);
+        assert_ne!(vm.registers.get_val(1), 0);
+        assert!(vm.loaded(0x3000));
+        assert!(!vm.loaded(0x3006));
+    }
+}
@@ -0,0 +1,179 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::image::{invalid_data, parse_hex_word};
+
+// Labels of a program, looked up either by name or by address
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SymbolTable {
+    by_name: HashMap<String, u16>,
+    by_addr: BTreeMap<u16, String>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        SymbolTable::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    // a later definition of a name replaces the earlier one, if two labels
+    // share an address the first one inserted names it
+    pub fn insert(&mut self, name: &str, addr: u16) {
+        if let Some(old) = self.by_name.insert(name.to_string(), addr) {
+            if self.by_addr.get(&old).map(String::as_str) == Some(name) {
+                self.by_addr.remove(&old);
+            }
+        }
+        self.by_addr.entry(addr).or_insert_with(|| name.to_string());
+    }
+
+    // labels are matched exactly first, then ignoring case as lc3as does
+    pub fn lookup(&self, name: &str) -> Option<u16> {
+        self.by_name.get(name).copied().or_else(|| {
+            self.by_name
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .map(|(_, a)| *a)
+        })
+    }
+
+    pub fn name_at(&self, addr: u16) -> Option<&str> {
+        self.by_addr.get(&addr).map(String::as_str)
+    }
+
+    // closest label at or below addr and the distance to it
+    pub fn nearest(&self, addr: u16) -> Option<(&str, u16)> {
+        self.by_addr
+            .range(..=addr)
+            .next_back()
+            .map(|(a, n)| (n.as_str(), addr - a))
+    }
+
+    // label relative name for an address: LOOP, LOOP+3 or None
+    pub fn label_for(&self, addr: u16) -> Option<String> {
+        self.nearest(addr).map(|(name, off)| match off {
+            0 => name.to_string(),
+            _ => format!("{}+{}", name, off),
+        })
+    }
+
+    // "LOOP+3 (x3012)" when a label is known, "x3012" otherwise
+    pub fn format_addr(&self, addr: u16) -> String {
+        match self.label_for(addr) {
+            Some(label) => format!("{} (x{:04X})", label, addr),
+            None => format!("x{:04X}", addr),
+        }
+    }
+
+    // all symbols in address order
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &str)> {
+        self.by_addr.iter().map(|(a, n)| (*a, n.as_str()))
+    }
+
+    // addresses typed by a user: x3000, 0x3000, #12288, LOOP or LOOP+3
+    pub fn resolve(&self, text: &str) -> Option<u16> {
+        let text = text.trim();
+        if let Some(dec) = text.strip_prefix('#') {
+            return dec
+                .parse::<i32>()
+                .ok()
+                .filter(|v| (-0x8000..=0xFFFF).contains(v))
+                .map(|v| v as u16);
+        }
+        if let Some(addr) = self.lookup(text) {
+            return Some(addr);
+        }
+        if let Some(addr) = parse_hex_word(text) {
+            return Some(addr);
+        }
+
+        let (base, off, negative) = match (text.rfind('+'), text.rfind('-')) {
+            (Some(i), _) => (&text[..i], &text[i + 1..], false),
+            (None, Some(i)) if i > 0 => (&text[..i], &text[i + 1..], true),
+            _ => return None,
+        };
+        let base = self.resolve(base)?;
+        let off: u16 = off.trim().parse().ok()?;
+        Some(match negative {
+            true => base.wrapping_sub(off),
+            false => base.wrapping_add(off),
+        })
+    }
+
+    // lc3as .sym layout, every entry is a comment line:
+    // //	Symbol Name       Page Address
+    // //	----------------  ------------
+    // //	LOOP              3002
+    pub fn parse(text: &str) -> io::Result<Self> {
+        let mut table = SymbolTable::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim_start_matches('/').trim();
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                [name, addr] if !name.starts_with('-') && *name != "Symbol" => {
+                    let addr = parse_hex_word(addr).ok_or_else(|| {
+                        invalid_data(format!("line {}: bad address {:?}", n + 1, addr))
+                    })?;
+                    table.insert(name, addr);
+                }
+                _ => (),
+            }
+        }
+        Ok(table)
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    // the symbol file lc3as writes next to an image: prog.obj -> prog.sym
+    pub fn load_beside(image_path: &Path) -> Option<Self> {
+        let sym_path = image_path.with_extension("sym");
+        if sym_path == image_path || !sym_path.exists() {
+            return None;
+        }
+        Self::load(&sym_path).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYM: &str = "// Symbol table
+// Scope level 0:
+//\tSymbol Name       Page Address
+//\t----------------  ------------
+//\tSTART             3000
+//\tLOOP              300F
+//\tDATA              3020
+";
+
+    #[test]
+    fn test_parse_lc3as() {
+        let table = SymbolTable::parse(SYM).unwrap();
+        assert_eq!(table.lookup("LOOP"), Some(0x300F));
+        assert_eq!(table.lookup("loop"), Some(0x300F));
+        assert_eq!(table.name_at(0x3020), Some("DATA"));
+        assert_eq!(table.iter().count(), 3);
+    }
+
+    #[test]
+    fn test_format_and_resolve() {
+        let table = SymbolTable::parse(SYM).unwrap();
+        assert_eq!(table.format_addr(0x3012), "LOOP+3 (x3012)");
+        assert_eq!(table.format_addr(0x3000), "START (x3000)");
+        assert_eq!(table.format_addr(0x2FFF), "x2FFF");
+
+        assert_eq!(table.resolve("LOOP+3"), Some(0x3012));
+        assert_eq!(table.resolve("DATA-1"), Some(0x301F));
+        assert_eq!(table.resolve("x4000"), Some(0x4000));
+        assert_eq!(table.resolve("#16"), Some(16));
+        assert_eq!(table.resolve("NOPE"), None);
+    }
+}
@@ -0,0 +1,151 @@
+use std::io::{self, Read};
+use std::process::{exit, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::sync::{Mutex, Once};
+use std::{panic, thread};
+
+// Terminal control through stty(1) on the controlling terminal, which keeps
+// the crate free of platform bindings
+
+fn stty(args: &[&str]) -> io::Result<String> {
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::null())
+        .output()?;
+    if !output.status.success() {
+        return Err(io::Error::other("stty failed, is STDIN a terminal?"));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+// settings from before raw mode, put back by whichever of drop, Ctrl-C or a
+// panic comes first
+static SAVED: Mutex<Option<String>> = Mutex::new(None);
+
+pub fn restore() {
+    let saved = SAVED.lock().unwrap_or_else(|e| e.into_inner()).take();
+    if let Some(saved) = saved {
+        let _ = stty(&[&saved]);
+    }
+}
+
+// Unbuffered, non-echoing input until dropped, when the saved settings are
+// put back
+pub struct RawMode {
+    _private: (),
+}
+
+impl RawMode {
+    pub fn enable() -> io::Result<Self> {
+        Self::enable_with(&["raw", "-echo"])
+    }
+
+    // raw input for a running program, with output processing and CR to NL
+    // left on so its newlines start a line and Enter reads as a newline
+    pub fn console() -> io::Result<Self> {
+        Self::enable_with(&["raw", "-echo", "opost", "icrnl"])
+    }
+
+    fn enable_with(args: &[&str]) -> io::Result<Self> {
+        static HOOK: Once = Once::new();
+        HOOK.call_once(|| {
+            let previous = panic::take_hook();
+            panic::set_hook(Box::new(move |info| {
+                restore();
+                previous(info);
+            }));
+        });
+        let saved = stty(&["-g"])?;
+        stty(args)?;
+        *SAVED.lock().unwrap_or_else(|e| e.into_inner()) = Some(saved);
+        Ok(RawMode { _private: () })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        restore();
+    }
+}
+
+// Keys from STDIN, or any reader, read on a thread of their own so a program
+// can poll for one without blocking. With interrupt set, as when the
+// terminal is raw, Ctrl-C puts the terminal back and exits the way SIGINT
+// would
+pub struct Keyboard {
+    keys: Receiver<u8>,
+    next: Option<u8>,
+    ended: bool,
+}
+
+impl Keyboard {
+    pub fn spawn(mut input: impl Read + Send + 'static, interrupt: bool) -> Self {
+        let (sender, keys) = mpsc::channel();
+        thread::spawn(move || {
+            // a byte at a time, each key is passed on as soon as it comes
+            let mut buf = [0u8; 1];
+            while let Ok(1) = input.read(&mut buf) {
+                let byte = buf[0];
+                if interrupt && byte == 0x03 {
+                    restore();
+                    exit(130);
+                }
+                if sender.send(byte).is_err() {
+                    break;
+                }
+            }
+        });
+        Keyboard {
+            keys,
+            next: None,
+            ended: false,
+        }
+    }
+
+    // whether a key is waiting, never blocks
+    pub fn ready(&mut self) -> bool {
+        if self.next.is_none() {
+            match self.keys.try_recv() {
+                Ok(key) => self.next = Some(key),
+                Err(TryRecvError::Disconnected) => self.ended = true,
+                Err(TryRecvError::Empty) => (),
+            }
+        }
+        self.next.is_some()
+    }
+
+    // waits until a key is ready or the input has ended
+    pub fn wait(&mut self) {
+        if self.next.is_none() && !self.ended {
+            match self.keys.recv() {
+                Ok(key) => self.next = Some(key),
+                Err(_) => self.ended = true,
+            }
+        }
+    }
+
+    // waits for the next key, None once the input has ended
+    pub fn read(&mut self) -> Option<u8> {
+        let key = self.next.take().or_else(|| self.keys.recv().ok());
+        self.ended = key.is_none();
+        key
+    }
+
+    // the input has ended and every key has been read
+    pub fn ended(&self) -> bool {
+        self.ended && self.next.is_none()
+    }
+}
+
+// rows and columns of the terminal, 24x80 when they cannot be found out
+pub fn size() -> (usize, usize) {
+    stty(&["size"])
+        .ok()
+        .and_then(|size| {
+            let (rows, cols) = size.split_once(' ')?;
+            Some((rows.parse().ok()?, cols.parse().ok()?))
+        })
+        .filter(|&(rows, cols)| rows > 0 && cols > 0)
+        .unwrap_or((24, 80))
+}
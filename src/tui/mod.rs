@@ -0,0 +1,551 @@
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::Duration;
+
+use crate::debugger::commands::{self, Command};
+use crate::debugger::{cond_name, Debugger};
+use crate::hw::input::{AtEnd, InputScript};
+use crate::hw::register::{COND_REG, NUM_REGISTERS, PC_REG};
+use crate::terminal::{self, RawMode};
+
+use screen::{title, Line, Style};
+
+pub mod screen;
+
+// Full screen debugger. Keys are turned into debugger commands so the TUI
+// behaves exactly like the line REPL, the panes only show the machine
+
+// instructions a single continue runs before handing back the keyboard
+const RUN_LIMIT: u64 = 1_000_000;
+// words PageUp and PageDown move the memory view by
+const MEMORY_PAGE: u16 = 0x40;
+const CONSOLE_ROWS: usize = 5;
+
+pub const KEYS: &str = "s step  c continue  u step back  b breakpoint  j/k move  . follow PC  \
+                        PgUp/PgDn memory  g goto  i input  : command  r reset  q quit";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Escape,
+    Up,
+    Down,
+    PageUp,
+    PageDown,
+    Interrupt,
+}
+
+// how long the rest of an escape sequence may take to follow its ESC, a
+// terminal sends the whole sequence at once
+const ESCAPE_WAIT: Duration = Duration::from_millis(50);
+
+// Keypresses from a terminal in raw mode. The bytes are read on a thread so
+// that a lone ESC can be told from the start of an escape sequence
+pub struct Keys {
+    bytes: Receiver<u8>,
+    // a byte that followed an ESC without being part of a sequence
+    pending: Option<u8>,
+}
+
+impl Keys {
+    pub fn spawn(mut input: impl Read + Send + 'static) -> Self {
+        let (sender, bytes) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0u8; 1];
+            while let Ok(1) = input.read(&mut buf) {
+                if sender.send(buf[0]).is_err() {
+                    break;
+                }
+            }
+        });
+        Keys {
+            bytes,
+            pending: None,
+        }
+    }
+
+    // the next byte, or None at end of input or when wait passes first
+    fn next(&mut self, wait: Option<Duration>) -> Option<u8> {
+        if let Some(byte) = self.pending.take() {
+            return Some(byte);
+        }
+        match wait {
+            Some(wait) => self.bytes.recv_timeout(wait).ok(),
+            None => self.bytes.recv().ok(),
+        }
+    }
+
+    // the next keypress, None at end of input
+    pub fn read_key(&mut self) -> Option<Key> {
+        let key = match self.next(None)? {
+            b'\r' | b'\n' => Key::Enter,
+            0x7f | 0x08 => Key::Backspace,
+            0x03 => Key::Interrupt,
+            0x1b => match self.next(Some(ESCAPE_WAIT)) {
+                Some(b'[') => match self.next(Some(ESCAPE_WAIT)) {
+                    Some(b'A') => Key::Up,
+                    Some(b'B') => Key::Down,
+                    Some(digit @ (b'5' | b'6')) => {
+                        // PageUp is ESC [ 5 ~, PageDown ESC [ 6 ~
+                        self.next(Some(ESCAPE_WAIT));
+                        if digit == b'5' {
+                            Key::PageUp
+                        } else {
+                            Key::PageDown
+                        }
+                    }
+                    _ => Key::Escape,
+                },
+                other => {
+                    self.pending = other;
+                    Key::Escape
+                }
+            },
+            byte => Key::Char(byte as char),
+        };
+        Some(key)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Prompt {
+    // a debugger command line
+    Command,
+    // text queued as keyboard input for the program
+    Input,
+    // address for the memory view
+    Goto,
+}
+
+pub struct Tui {
+    pub debugger: Debugger,
+    // everything the program has written since the last reset
+    console: Vec<u8>,
+    status: String,
+    // register values before the last command, to highlight what changed
+    previous: [u16; NUM_REGISTERS as usize],
+    // first address of the memory view
+    memory_addr: u16,
+    // disassembly cursor, the view follows PC while it is unset
+    cursor: Option<u16>,
+    prompt: Option<(Prompt, String)>,
+}
+
+impl Tui {
+    pub fn new(mut debugger: Debugger) -> Self {
+        // the terminal belongs to the TUI, the program's console is a pane
+        debugger.vm.output = Some(Vec::new());
+        // a program reading with nothing queued stops so the input prompt
+        // can open, rather than reading 0
+        let mut input = InputScript::default();
+        input.at_end = AtEnd::Stop;
+        debugger.vm.input = Some(input);
+        let pc = debugger.vm.registers.get_val(PC_REG);
+        Tui {
+            previous: debugger.vm.registers.snapshot(),
+            debugger,
+            console: Vec::new(),
+            status: String::new(),
+            memory_addr: pc & !0x7,
+            cursor: None,
+            prompt: None,
+        }
+    }
+
+    pub fn run(&mut self) -> io::Result<()> {
+        let raw = RawMode::enable()?;
+        let mut keys = Keys::spawn(io::stdin());
+        let mut stdout = io::stdout();
+        // alternate screen, hidden cursor
+        write!(stdout, "\x1b[?1049h\x1b[?25l")?;
+
+        let result = (|| loop {
+            let (rows, cols) = terminal::size();
+            write!(stdout, "\x1b[H{}", self.screen(rows, cols).join("\r\n"))?;
+            stdout.flush()?;
+            match keys.read_key() {
+                Some(key) if self.handle_key(key)? => (),
+                _ => return Ok(()),
+            }
+        })();
+
+        write!(stdout, "\x1b[?1049l\x1b[?25h")?;
+        stdout.flush()?;
+        drop(raw);
+        result
+    }
+
+    // returns false when the TUI should exit
+    pub fn handle_key(&mut self, key: Key) -> io::Result<bool> {
+        if let Some((prompt, mut text)) = self.prompt.take() {
+            match key {
+                Key::Char(c) => {
+                    text.push(c);
+                    self.prompt = Some((prompt, text));
+                }
+                Key::Backspace => {
+                    text.pop();
+                    self.prompt = Some((prompt, text));
+                }
+                Key::Enter => return self.submit(prompt, &text),
+                _ => self.status.clear(),
+            }
+            return Ok(true);
+        }
+
+        let vm = &self.debugger.vm;
+        let pc = vm.registers.get_val(PC_REG);
+        let at = self.cursor.unwrap_or(pc);
+        match key {
+            Key::Char('q') | Key::Interrupt => return Ok(false),
+            Key::Char('s') => return self.run_command(Command::Step(1)),
+            Key::Char('c') => {
+                let steps = vm.steps;
+                let result = self.run_command(Command::Step(RUN_LIMIT));
+                let vm = &self.debugger.vm;
+                if vm.steps - steps == RUN_LIMIT && !vm.halted {
+                    self.status =
+                        format!("still running after {} instructions, c goes on", RUN_LIMIT);
+                }
+                return result;
+            }
+            Key::Char('u') => return self.run_command(Command::ReverseStep(1)),
+            Key::Char('r') => return self.run_command(Command::Reset),
+            Key::Char('b') => {
+                let command = match vm.breakpoints.contains_key(&at) {
+                    true => Command::Delete(Some(at)),
+                    false => Command::Break(at, None),
+                };
+                return self.run_command(command);
+            }
+            Key::Up | Key::Char('k') => self.cursor = Some(at.wrapping_sub(1)),
+            Key::Down | Key::Char('j') => self.cursor = Some(at.wrapping_add(1)),
+            Key::Char('.') => self.cursor = None,
+            Key::PageUp => self.memory_addr = self.memory_addr.wrapping_sub(MEMORY_PAGE),
+            Key::PageDown => self.memory_addr = self.memory_addr.wrapping_add(MEMORY_PAGE),
+            Key::Char(':') => self.prompt = Some((Prompt::Command, String::new())),
+            Key::Char('i') => self.prompt = Some((Prompt::Input, String::new())),
+            Key::Char('g') => self.prompt = Some((Prompt::Goto, String::new())),
+            _ => self.status = KEYS.to_string(),
+        }
+        Ok(true)
+    }
+
+    fn submit(&mut self, prompt: Prompt, text: &str) -> io::Result<bool> {
+        match prompt {
+            Prompt::Command => match commands::parse(text, &self.debugger.vm.symbols) {
+                Ok(command) => return self.run_command(command),
+                Err(msg) => self.status = msg,
+            },
+            Prompt::Input => {
+                let input = self.debugger.vm.input.get_or_insert_with(Default::default);
+                input.extend(text.bytes().chain([b'\n']));
+                self.status = format!("{} characters of input queued", input.len());
+            }
+            Prompt::Goto => match self.debugger.vm.symbols.resolve(text.trim()) {
+                Some(addr) => self.memory_addr = addr,
+                None => self.status = format!("unknown address {:?}", text.trim()),
+            },
+        }
+        Ok(true)
+    }
+
+    // runs a debugger command, its output becomes the status line
+    fn run_command(&mut self, command: Command) -> io::Result<bool> {
+        let reset = matches!(command, Command::Reset | Command::Reload);
+        self.previous = self.debugger.vm.registers.snapshot();
+
+        let mut out = Vec::new();
+        let keep_going = self.debugger.execute(command, &mut out)?;
+        if reset {
+            self.console.clear();
+        }
+        if let Some(output) = self.debugger.vm.output.as_mut() {
+            self.console.append(output);
+        }
+        self.status = String::from_utf8_lossy(&out)
+            .lines()
+            .map(str::trim)
+            .filter(|l| !l.is_empty())
+            .collect::<Vec<&str>>()
+            .join("  |  ");
+        if self.debugger.vm.waiting_for_input() {
+            self.prompt = Some((Prompt::Input, String::new()));
+        }
+        Ok(keep_going)
+    }
+
+    // the whole screen, one string per terminal row
+    pub fn screen(&self, rows: usize, cols: usize) -> Vec<String> {
+        if rows < 14 || cols < 60 {
+            let mut screen = vec![" ".repeat(cols); rows];
+            screen[0] = Line::plain("terminal too small").render(cols);
+            return screen;
+        }
+
+        // panes above the console pane and the status line
+        let top = rows - CONSOLE_ROWS - 2;
+        let left_width = cols * 3 / 5;
+        let right_width = cols - left_width - 1;
+
+        let mut left = vec![title("Disassembly", left_width)];
+        left.extend(self.disassembly(top - 1));
+
+        let stack_rows = ((top - 6) / 3).min(8);
+        let mut right = vec![title("Registers", right_width)];
+        right.extend(self.registers());
+        right.push(title("Stack", right_width));
+        right.extend(self.stack(stack_rows));
+        right.push(title("Memory", right_width));
+        // rows start at multiples of the words they hold
+        let per_row: u16 = [8, 4, 2, 1]
+            .into_iter()
+            .find(|n| 7 + 5 * *n as usize <= right_width)
+            .unwrap_or(1);
+        right.extend(self.memory(top - right.len(), per_row));
+
+        let mut screen: Vec<String> = (0..top)
+            .map(|i| {
+                let blank = Line::new();
+                format!(
+                    "{}│{}",
+                    left.get(i).unwrap_or(&blank).render(left_width),
+                    right.get(i).unwrap_or(&blank).render(right_width)
+                )
+            })
+            .collect();
+        screen.push(title("Console", cols).render(cols));
+        let console = self.console(CONSOLE_ROWS);
+        for i in 0..CONSOLE_ROWS {
+            screen.push(console.get(i).cloned().unwrap_or_default().render(cols));
+        }
+        screen.push(self.status_line().render(cols));
+        screen
+    }
+
+    fn status_line(&self) -> Line {
+        match &self.prompt {
+            Some((prompt, text)) => {
+                let label = match prompt {
+                    Prompt::Command => "(lc3)",
+                    Prompt::Input => "input>",
+                    Prompt::Goto => "goto>",
+                };
+                Line::styled(format!("{} {}_", label, text), Style::Title)
+            }
+            None if self.status.is_empty() => Line::plain(KEYS),
+            None => Line::plain(self.status.as_str()),
+        }
+    }
+
+    // instructions around the cursor, or around PC while it follows PC
+    pub fn disassembly(&self, rows: usize) -> Vec<Line> {
+        let vm = &self.debugger.vm;
+        let pc = vm.registers.get_val(PC_REG);
+        let start = self.cursor.unwrap_or(pc).wrapping_sub(rows as u16 / 3);
+        (0..rows as u16)
+            .map(|i| {
+                let addr = start.wrapping_add(i);
+                let style = if addr == pc {
+                    Style::Current
+                } else if Some(addr) == self.cursor {
+                    Style::Cursor
+                } else if vm.breakpoints.contains_key(&addr) {
+                    Style::Breakpoint
+                } else {
+                    Style::Plain
+                };
+                Line::styled(self.debugger.format_line(addr), style)
+            })
+            .collect()
+    }
+
+    // R0-R7, PC, PSR and the condition codes, changed values highlighted
+    pub fn registers(&self) -> Vec<Line> {
+        let registers = &self.debugger.vm.registers;
+        let style = |r: u8| match registers.get_val(r) == self.previous[r as usize] {
+            true => Style::Plain,
+            false => Style::Changed,
+        };
+
+        let mut lines: Vec<Line> = (0..2)
+            .map(|row| {
+                let mut line = Line::new();
+                for r in row * 4..row * 4 + 4 {
+                    line.push(format!("R{} ", r), Style::Plain);
+                    line.push(format!("x{:04X}", registers.get_val(r)), style(r));
+                    line.push("  ", Style::Plain);
+                }
+                line
+            })
+            .collect();
+
+        let mut line = Line::plain("PC ");
+        line.push(format!("x{:04X}", registers.get_val(PC_REG)), style(PC_REG));
+        line.push("  PSR ", Style::Plain);
+        line.push(format!("x{:04X}", self.debugger.vm.psr()), style(COND_REG));
+        line.push("  CC ", Style::Plain);
+        line.push(cond_name(registers.get_val(COND_REG)), style(COND_REG));
+        lines.push(line);
+        lines
+    }
+
+    // words from R6 upwards, the top of a stack growing down
+    pub fn stack(&self, rows: usize) -> Vec<Line> {
+        let vm = &self.debugger.vm;
+        let sp = vm.registers.get_val(6);
+        (0..rows as u16)
+            .map(|i| {
+                let addr = sp.wrapping_add(i);
+                Line::plain(format!(
+                    "{} x{:04X}  {:04X}  {}",
+                    if i == 0 { "R6 →" } else { "    " },
+                    addr,
+                    vm.memory[addr as usize],
+                    vm.symbols.name_at(addr).unwrap_or("")
+                ))
+            })
+            .collect()
+    }
+
+    // hex words from the memory view address, the word at PC highlighted
+    pub fn memory(&self, rows: usize, per_row: u16) -> Vec<Line> {
+        let vm = &self.debugger.vm;
+        let pc = vm.registers.get_val(PC_REG);
+        (0..rows as u16)
+            .map(|row| {
+                let start = self.memory_addr.wrapping_add(row * per_row);
+                let mut line = Line::plain(format!("x{:04X} ", start));
+                for i in 0..per_row {
+                    let addr = start.wrapping_add(i);
+                    let style = match addr == pc {
+                        true => Style::Current,
+                        false => Style::Plain,
+                    };
+                    line.push(" ", Style::Plain);
+                    line.push(format!("{:04X}", vm.memory[addr as usize]), style);
+                }
+                line
+            })
+            .collect()
+    }
+
+    // the last rows lines the program wrote
+    pub fn console(&self, rows: usize) -> Vec<Line> {
+        let text = String::from_utf8_lossy(&self.console);
+        let lines: Vec<&str> = text.split('\n').collect();
+        lines[lines.len().saturating_sub(rows)..]
+            .iter()
+            .map(|l| Line::plain(l.replace('\r', "")))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hw::register::PC_START;
+    use crate::image::Image;
+    use crate::symbols::SymbolTable;
+    use std::path::PathBuf;
+
+    fn tui() -> Tui {
+        // AND R0 R0 0; LOOP ADD R0 R0 1; OUT; BRnzp LOOP
+        let image = Image::new(PC_START, vec![0x5020, 0x1021, 0xF021, 0x0FFD]);
+        let mut symbols = SymbolTable::new();
+        symbols.insert("LOOP", PC_START + 1);
+        Tui::new(Debugger::new(image, PathBuf::from("loop.obj"), symbols))
+    }
+
+    fn keys(tui: &mut Tui, keys: &str) {
+        for c in keys.chars() {
+            let key = if c == '\n' { Key::Enter } else { Key::Char(c) };
+            assert!(tui.handle_key(key).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_step_highlights() {
+        let mut tui = tui();
+        keys(&mut tui, "ss");
+
+        let disassembly = tui.disassembly(6);
+        let current = disassembly
+            .iter()
+            .find(|l| l.spans[0].1 == Style::Current)
+            .unwrap();
+        assert!(current.text().contains("=> x3002"));
+
+        let registers = tui.registers();
+        assert_eq!(registers[0].style_of("x0001"), Some(Style::Changed));
+        assert_eq!(registers[0].style_of("R1"), Some(Style::Plain));
+        assert!(registers[2].text().contains("CC P"));
+    }
+
+    #[test]
+    fn test_breakpoint_and_console() {
+        let mut tui = tui();
+        // a breakpoint on the OUT through the cursor, then on LOOP by command
+        keys(&mut tui, "jjb:break LOOP\nc");
+        assert!(tui.debugger.vm.breakpoints.contains_key(&(PC_START + 2)));
+        assert!(tui.status.contains("breakpoint at LOOP"));
+
+        keys(&mut tui, "cccc");
+        assert_eq!(tui.console(1)[0].text(), "\u{1}\u{2}");
+        assert_eq!(tui.screen(24, 80).len(), 24);
+
+        keys(&mut tui, "r");
+        assert!(tui.console.is_empty());
+    }
+
+    #[test]
+    fn test_input_prompt() {
+        // GETC; OUT; HALT
+        let image = Image::new(PC_START, vec![0xF020, 0xF021, 0xF025]);
+        let debugger = Debugger::new(image, PathBuf::from("echo.obj"), SymbolTable::new());
+        let mut tui = Tui::new(debugger);
+        keys(&mut tui, "c");
+        assert!(tui.status.contains("end of input at x3000"));
+        assert_eq!(tui.prompt, Some((Prompt::Input, String::new())));
+
+        keys(&mut tui, "x\nc");
+        assert!(tui.debugger.vm.halted);
+        assert_eq!(tui.console, b"x");
+    }
+
+    #[test]
+    fn test_read_key() {
+        let mut keys = Keys::spawn(&b"s\x1b[A\x1b[6~\r\x03\x1bq\x1b"[..]);
+        let mut read = Vec::new();
+        while let Some(key) = keys.read_key() {
+            read.push(key);
+        }
+        assert_eq!(
+            read,
+            vec![
+                Key::Char('s'),
+                Key::Up,
+                Key::PageDown,
+                Key::Enter,
+                Key::Interrupt,
+                Key::Escape,
+                Key::Char('q'),
+                Key::Escape
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lone_escape() {
+        // the ESC is a key of its own while nothing follows it
+        let (reader, mut writer) = io::pipe().unwrap();
+        let mut keys = Keys::spawn(reader);
+        writer.write_all(b"\x1b").unwrap();
+        assert_eq!(keys.read_key(), Some(Key::Escape));
+        writer.write_all(b"i").unwrap();
+        assert_eq!(keys.read_key(), Some(Key::Char('i')));
+    }
+}
@@ -0,0 +1,101 @@
+// A line of styled text, rendered to a fixed width with ANSI escapes
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Style {
+    Plain,
+    Title,      /* pane headings */
+    Current,    /* the instruction at PC */
+    Cursor,     /* the disassembly cursor */
+    Breakpoint, /* instructions with a breakpoint */
+    Changed,    /* registers the last command changed */
+}
+
+impl Style {
+    fn code(self) -> &'static str {
+        match self {
+            Style::Plain => "",
+            Style::Title => "\x1b[1m",
+            Style::Current => "\x1b[7m",
+            Style::Cursor => "\x1b[4m",
+            Style::Breakpoint => "\x1b[31m",
+            Style::Changed => "\x1b[1;33m",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Line {
+    pub spans: Vec<(String, Style)>,
+}
+
+impl Line {
+    pub fn new() -> Self {
+        Line::default()
+    }
+
+    pub fn styled(text: impl Into<String>, style: Style) -> Self {
+        let mut line = Line::new();
+        line.push(text, style);
+        line
+    }
+
+    pub fn plain(text: impl Into<String>) -> Self {
+        Line::styled(text, Style::Plain)
+    }
+
+    pub fn push(&mut self, text: impl Into<String>, style: Style) {
+        self.spans.push((text.into(), style));
+    }
+
+    // the text without styling
+    pub fn text(&self) -> String {
+        self.spans.iter().map(|(text, _)| text.as_str()).collect()
+    }
+
+    pub fn style_of(&self, text: &str) -> Option<Style> {
+        self.spans
+            .iter()
+            .find(|(t, _)| t.contains(text))
+            .map(|(_, style)| *style)
+    }
+
+    // cut or padded with spaces to exactly width columns
+    pub fn render(&self, width: usize) -> String {
+        let mut out = String::new();
+        let mut left = width;
+        for (text, style) in &self.spans {
+            let part: String = text.chars().take(left).collect();
+            left -= part.chars().count();
+            if *style == Style::Plain {
+                out.push_str(&part);
+            } else {
+                out.push_str(style.code());
+                out.push_str(&part);
+                out.push_str("\x1b[0m");
+            }
+        }
+        out.extend(std::iter::repeat_n(' ', left));
+        out
+    }
+}
+
+// "─ Title ──────" heading filling width
+pub fn title(name: &str, width: usize) -> Line {
+    let text = format!("─ {} ", name);
+    let rest = width.saturating_sub(text.chars().count());
+    Line::styled(format!("{}{}", text, "─".repeat(rest)), Style::Title)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let mut line = Line::plain("R0 ");
+        line.push("x0001", Style::Changed);
+        assert_eq!(line.render(10), "R0 \x1b[1;33mx0001\x1b[0m  ");
+        assert_eq!(line.render(4), "R0 \x1b[1;33mx\x1b[0m");
+        assert_eq!(title("Stack", 10).text(), "─ Stack ──");
+    }
+}
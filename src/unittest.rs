@@ -0,0 +1,230 @@
+use serde_json::Value;
+
+use crate::grader::{self, TestCase, TestResult};
+use crate::hw::breakpoint::Breakpoint;
+use crate::hw::instruction::{CALLEE_SAVED, STACK_POINTER};
+use crate::hw::register::PC_REG;
+use crate::hw::stop::StopReason;
+use crate::hw::vm::VM;
+use crate::image::Image;
+use crate::symbols::SymbolTable;
+
+// Tests of single subroutines. Test files use the grader's spec format,
+// with "call" naming the subroutine each test runs instead of the whole
+// program, plus which registers the convention says callees preserve:
+// { "preserved": ["R1", "R2", "R3", "R4", "R5", "R6"],
+//   "tests": [ { "name": "sums", "call": "SUM", "registers": { "R0": 2 },
+//                "expect": { "registers": { "R0": 5 } } } ] }
+
+// The call goes through a JSRR R7 placed here, so the return address is
+// SHIM + 1. x0200 is the start of the operating system area, which
+// programs do not use as traps run natively
+pub const SHIM: u16 = 0x0200;
+pub const SENTINEL: u16 = SHIM + 1;
+
+// JSRR R7
+const JSRR_R7: u16 = 0x41C0;
+
+// the convention's callee-saved registers and the stack pointer, unless a
+// test file says otherwise
+pub const DEFAULT_PRESERVED: [u8; 6] = {
+    let [r1, r2, r3, r4, r5] = CALLEE_SAVED;
+    [r1, r2, r3, r4, r5, STACK_POINTER]
+};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SubroutineTest {
+    pub case: TestCase,
+    // label or address of the subroutine
+    pub call: String,
+    // registers the subroutine must leave as it found them, a register the
+    // test expects a value in is a result instead
+    pub preserved: Vec<u8>,
+}
+
+pub fn parse_tests(text: &str) -> Result<Vec<SubroutineTest>, String> {
+    let spec: Value = serde_json::from_str(text).map_err(|e| e.to_string())?;
+    let limit = match &spec["limit"] {
+        Value::Null => grader::DEFAULT_LIMIT,
+        limit => limit.as_u64().ok_or("limit must be a number")?,
+    };
+    let preserved = match &spec["preserved"] {
+        Value::Null => DEFAULT_PRESERVED.to_vec(),
+        Value::Array(names) => {
+            let registers: serde_json::Map<String, Value> = names
+                .iter()
+                .map(|n| Some((n.as_str()?.to_string(), Value::from(0))))
+                .collect::<Option<_>>()
+                .ok_or("preserved must list register names")?;
+            let registers = grader::parse_registers(&Value::Object(registers))?;
+            registers.into_iter().map(|(r, _)| r).collect()
+        }
+        _ => return Err("preserved must list register names".to_string()),
+    };
+
+    let tests = spec["tests"].as_array().ok_or("expected a tests array")?;
+    tests
+        .iter()
+        .enumerate()
+        .map(|(i, test)| {
+            let at = |e: String| format!("test {}: {}", i + 1, e);
+            Ok(SubroutineTest {
+                case: grader::parse_test(test, limit).map_err(at)?,
+                call: test["call"]
+                    .as_str()
+                    .ok_or_else(|| at("every test needs a call".to_string()))?
+                    .to_string(),
+                preserved: preserved.clone(),
+            })
+        })
+        .collect()
+}
+
+// Calls the subroutine at target as a JSR would and runs until it returns,
+// executing at most limit of its instructions. PC is left at SENTINEL
+pub fn call(vm: &mut VM, target: u16, limit: u64) -> Result<(), String> {
+    let saved_word = vm.memory[SHIM as usize];
+    let saved_breakpoint = vm.breakpoints.insert(SENTINEL, Breakpoint::default());
+    vm.write_memory(SHIM as usize, JSRR_R7);
+    vm.registers.update_register(7, target);
+    vm.registers.update_register(PC_REG, SHIM);
+    vm.halted = false;
+
+    // one more for the shim itself
+    let stop = vm.run(Some(limit + 1));
+    vm.write_memory(SHIM as usize, saved_word);
+    match saved_breakpoint {
+        Some(breakpoint) => vm.breakpoints.insert(SENTINEL, breakpoint),
+        None => vm.breakpoints.remove(&SENTINEL),
+    };
+    match stop {
+        StopReason::Breakpoint(SENTINEL) => Ok(()),
+        StopReason::Halted => Err("halted instead of returning".to_string()),
+        StopReason::StepLimit => Err(format!("timeout: no return within {} instructions", limit)),
+        stop => Err(stop.describe(&vm.symbols)),
+    }
+}
+
+pub fn run_test(image: &Image, symbols: &SymbolTable, test: &SubroutineTest) -> TestResult {
+    let case = &test.case;
+    let mut result = TestResult {
+        test: case.name.clone(),
+        reasons: Vec::new(),
+        steps: 0,
+    };
+    let Some(target) = symbols.resolve(&test.call) else {
+        result
+            .reasons
+            .push(format!("unknown subroutine {:?}", test.call));
+        return result;
+    };
+    let mut vm = match grader::prepare(image, symbols, case) {
+        Ok(vm) => vm,
+        Err(reasons) => {
+            result.reasons = reasons;
+            return result;
+        }
+    };
+
+    let before = vm.registers.snapshot();
+    let called = call(&mut vm, target, case.limit);
+    // the shim's JSRR is not the subroutine's
+    result.steps = vm.steps.saturating_sub(1);
+    if let Err(reason) = called {
+        result.reasons.push(reason);
+        return result;
+    }
+    result.reasons = grader::check(&vm, case);
+    for &r in &test.preserved {
+        let after = vm.registers.get_val(r);
+        let is_result = case.expect_registers.iter().any(|(e, _)| *e == r);
+        if after != before[r as usize] && !is_result {
+            result.reasons.push(format!(
+                "{} not preserved: x{:04X} before the call, x{:04X} after",
+                grader::register_name(r),
+                before[r as usize],
+                after
+            ));
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+
+    // SUM: R0 = R0 + R1 using R2 as scratch it saves and restores,
+    // BAD: the same without saving R2
+    const SOURCE: &str = ".ORIG x3000
+        HALT
+SUM     ST R2, SAVE2
+        ADD R2, R1, #0
+        ADD R0, R0, R2
+        LD R2, SAVE2
+        RET
+BAD     ADD R2, R1, #0
+        ADD R0, R0, R2
+        RET
+LOOP    ADD R3, R3, #1
+        BR LOOP
+SAVE2   .FILL 0
+        .END";
+
+    const TESTS: &str = r#"{
+        "limit": 50,
+        "tests": [
+            { "name": "sum", "call": "SUM", "registers": { "R0": 2, "R1": 3, "R2": 9 },
+              "expect": { "registers": { "R0": 5 } } },
+            { "name": "bad", "call": "BAD", "registers": { "R0": 2, "R1": 3, "R2": 9 },
+              "expect": { "registers": { "R0": 5 } } },
+            { "name": "loop", "call": "LOOP" },
+            { "name": "halts", "call": "x3000" }
+        ]
+    }"#;
+
+    #[test]
+    fn test_subroutines() {
+        let assembly = assemble(SOURCE).unwrap();
+        let tests = parse_tests(TESTS).unwrap();
+        assert_eq!(tests[0].preserved, DEFAULT_PRESERVED);
+        let results: Vec<TestResult> = tests
+            .iter()
+            .map(|test| run_test(&assembly.image, &assembly.symbols, test))
+            .collect();
+
+        assert_eq!(results[0].reasons, Vec::<String>::new());
+        assert_eq!(results[0].steps, 5);
+        assert_eq!(
+            results[1].reasons,
+            vec!["R2 not preserved: x0009 before the call, x0003 after"]
+        );
+        assert_eq!(
+            results[2].reasons,
+            vec!["timeout: no return within 50 instructions"]
+        );
+        assert_eq!(results[3].reasons, vec!["halted instead of returning"]);
+    }
+
+    #[test]
+    fn test_call() {
+        let assembly = assemble(SOURCE).unwrap();
+        let mut vm = VM::new();
+        vm.load_image(&assembly.image);
+        vm.registers.update_register(1, 4);
+        call(&mut vm, 0x3001, 10).unwrap();
+        assert_eq!(vm.registers.get_val(0), 4);
+        assert_eq!(vm.registers.get_val(PC_REG), SENTINEL);
+        // the shim and the sentinel breakpoint are cleaned up
+        assert_eq!(vm.memory[SHIM as usize], 0);
+        assert!(vm.breakpoints.is_empty());
+
+        let tests = parse_tests(
+            r#"{ "preserved": ["R4", "r5"], "tests": [{ "name": "t", "call": "SUB" }] }"#,
+        )
+        .unwrap();
+        assert_eq!(tests[0].preserved, vec![4, 5]);
+        assert!(parse_tests(r#"{ "preserved": ["R9"], "tests": [] }"#).is_err());
+    }
+}